@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+//Per-segment read counts and the transcripts per million they amount to
+pub struct Abundance {
+	pub counts : Vec<f64>,
+	pub effective_lengths : Vec<f64>,
+	pub tpm : Vec<f64>,
+}
+
+impl Abundance {
+	pub fn new(counts : &HashMap<i32, i32>, effective_lengths : &[f64]) -> Abundance {
+		let counts : Vec<f64> = (0..effective_lengths.len())
+			.map(|i| *counts.get(&(i as i32)).unwrap_or(&0) as f64)
+			.collect();
+
+		let tpm : Vec<f64> = tpm(&counts, effective_lengths);
+		Abundance {
+			counts,
+			effective_lengths : effective_lengths.to_vec(),
+			tpm,
+		}
+	}
+}
+
+//The effective length of a segment is the number of positions a read of the mean length can start at
+pub fn effective_length(length : usize, mean_read_length : f64) -> f64 {
+	(length as f64 - mean_read_length + 1.0).max(1.0)
+}
+
+//Transcripts per million: counts normalised by effective length, scaled to sum to a million
+pub fn tpm(counts : &[f64], effective_lengths : &[f64]) -> Vec<f64> {
+	let rates : Vec<f64> = counts.iter().zip(effective_lengths.iter())
+		.map(|(count, effective_length)| count / effective_length)
+		.collect();
+	let total : f64 = rates.iter().sum();
+	rates.iter()
+		.map(|rate| if total > 0.0 { rate / total * 1_000_000.0 } else { 0.0 })
+		.collect()
+}
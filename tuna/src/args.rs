@@ -1,6 +1,11 @@
 
 extern crate structopt;
 
+use count_output::OutputFormat;
+use library_type::LibraryType;
+use library_type::LibraryTypeOption;
+use kmer_count::KmerFormat;
+
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "tuna", about = "RNA sequence aligner")]
@@ -18,7 +23,252 @@ pub struct Opt {
     #[structopt(short = "p", long = "n_partition", default_value = "4")]
     pub n_partition: usize,
 
-    /// The name of the reads inputs
+    /// Pseudoalign reads through the compacted de Bruijn graph, skipping lookups along unitigs
+    #[structopt(short = "u", long = "unitigs")]
+    pub unitigs: bool,
+
+    /// Optional file to write the unitigs and the segments they occur in to
+    #[structopt(long = "unitig_output")]
+    pub unitig_output_filename: Option<String>,
+
+    /// Optional file to write seed-and-extend alignments of the reads to, in SAM format
+    #[structopt(long = "sam")]
+    pub sam_output_filename: Option<String>,
+
+    /// Optional file to write seed-and-extend alignments of the reads to, in BAM format
+    #[structopt(long = "bam")]
+    pub bam_output_filename: Option<String>,
+
+    /// Half-width of the band around a seed diagonal explored when extending alignments
+    #[structopt(long = "band", default_value = "8")]
+    pub band: usize,
+
+    /// Fraction of the perfect score an alignment needs to be reported
+    #[structopt(long = "min_score_fraction", default_value = "0.65")]
+    pub min_score_fraction: f64,
+
+    /// Optional GTF or GFF3 annotation of the sequence inputs, used to align reads across exon junctions
+    #[structopt(long = "gtf")]
+    pub gtf_filename: Option<String>,
+
+    /// Optional file to write the reads counted per annotated gene to
+    #[structopt(long = "gene_counts")]
+    pub gene_counts_filename: Option<String>,
+
+    /// Optional file to write the reads spliced across each annotated junction to
+    #[structopt(long = "junction_counts")]
+    pub junction_counts_filename: Option<String>,
+
+    /// Optional tab-separated file mapping transcript IDs to gene IDs
+    #[structopt(long = "tx2gene")]
+    pub tx2gene_filename: Option<String>,
+
+    /// Optional file to write counts and TPM summed over the segments of each gene to
+    #[structopt(long = "gene_output")]
+    pub gene_output_filename: Option<String>,
+
+    /// Shorten pipe-delimited sequence IDs, such as GENCODE headers, to the transcript ID they start with
+    #[structopt(long = "gencode")]
+    pub gencode: bool,
+
+    /// Comma-separated header fields to add as columns of the output, e.g. gene_id,gene_name,transcript_biotype
+    #[structopt(long = "extra_columns")]
+    pub extra_columns: Option<String>,
+
+    /// Index every segment, even those whose sequence is identical to another's
+    #[structopt(long = "keep_duplicates")]
+    pub keep_duplicates: bool,
+
+    /// Also collapse segments whose sequence is contained in a longer segment
+    #[structopt(long = "collapse_contained")]
+    pub collapse_contained: bool,
+
+    /// Optional file to write the collapsed duplicate segments to
+    #[structopt(long = "duplicates_output")]
+    pub duplicates_output_filename: Option<String>,
+
+    /// Optional FASTA of decoy sequences, such as the genome, indexed alongside the segments. Reads best
+    /// explained by a decoy are discarded.
+    #[structopt(long = "decoys")]
+    pub decoys_filename: Option<String>,
+
+    /// Number of bootstrap replicates of the read assignments to draw
+    #[structopt(long = "num_bootstraps", raw(conflicts_with = "\"num_gibbs_samples\""))]
+    pub num_bootstraps: Option<usize>,
+
+    /// Number of Gibbs samples of the read assignments to draw, as an alternative to bootstrapping
+    #[structopt(long = "num_gibbs_samples")]
+    pub num_gibbs_samples: Option<usize>,
+
+    /// Rounds of the Gibbs sampler run from uniform abundances before each sample is taken. Every sample
+    /// comes from its own chain, so this is also the number of rounds per sample.
+    #[structopt(long = "gibbs_burn_in", default_value = "20")]
+    pub gibbs_burn_in: usize,
+
+    /// Seed for the random draws of bootstrap replicates and Gibbs samples
+    #[structopt(long = "seed", default_value = "0")]
+    pub seed: u64,
+
+    /// Optional file to write the mean and variance of each segment's count across replicates to
+    #[structopt(long = "bootstrap_output")]
+    pub bootstrap_output_filename: Option<String>,
+
+    /// Optional file to write the count of each segment in every replicate to
+    #[structopt(long = "bootstrap_matrix")]
+    pub bootstrap_matrix_filename: Option<String>,
+
+    /// Correct effective lengths for the sequence context around read starts
+    #[structopt(long = "seq_bias")]
+    pub seq_bias: bool,
+
+    /// Correct effective lengths for the GC content of fragments. Fragment lengths are not estimated, even
+    /// with --mates, so the GC content is taken over a window of the mean read length from each read
+    /// start. This matches single-end fragments but understates the span of paired-end ones.
+    #[structopt(long = "gc_bias")]
+    pub gc_bias: bool,
+
+    /// Library type in Salmon's notation, e.g. SF, SR, U, ISR or IU, or A to detect it from the reads.
+    /// Reads are taken to be unstranded unless told otherwise.
+    #[structopt(short = "l", long = "libtype", default_value = "U")]
+    pub libtype: LibraryTypeOption,
+
+    /// Number of reads sampled to detect the library type
+    #[structopt(long = "libtype_sample", default_value = "10000")]
+    pub libtype_sample: usize,
+
+    /// Optional file of the second reads of pairs, in the same order as the reads, used to detect the
+    /// orientation of paired-end libraries
+    #[structopt(long = "mates")]
+    pub mates_filename: Option<String>,
+
+    /// Optional file to write the detected library type and the read orientations supporting it to, as JSON
+    #[structopt(long = "lib_format_counts")]
+    pub lib_format_counts_filename: Option<String>,
+
+    /// Optional file to write a JSON summary of the run to: version, command line, index checksum, read
+    /// statistics and timings
+    #[structopt(long = "meta_info")]
+    pub meta_info_filename: Option<String>,
+
+    /// Format of the counts output: counts, tsv, csv, jsonl or quant.sf
+    #[structopt(long = "format", default_value = "counts")]
+    pub format: OutputFormat,
+
+    /// Treat the reads input as a sample sheet of sample names and read files, and write the counts of
+    /// all samples as one matrix. Paired-end samples give the file of second reads after the first. Outputs
+    /// of a single run, such as alignments, bootstraps and the run summary, cannot be combined with it.
+    #[structopt(long = "sample_sheet", raw(conflicts_with_all = r#"&["format", "extra_columns", "sam_output_filename",
+        "bam_output_filename", "gene_counts_filename", "junction_counts_filename", "meta_info_filename",
+        "lib_format_counts_filename", "num_bootstraps", "num_gibbs_samples", "bootstrap_output_filename",
+        "bootstrap_matrix_filename", "seq_bias", "gc_bias", "mates_filename"]"#))]
+    pub sample_sheet: bool,
+
+    /// Optional file to write the read statistics of each sample of a sample sheet to
+    #[structopt(long = "sample_summary")]
+    pub sample_summary_filename: Option<String>,
+
+    /// Optional 3' adapter sequence to trim from the reads
+    #[structopt(long = "adapter")]
+    pub adapter: Option<String>,
+
+    /// Detect the 3' adapter from known adapters or overrepresented sequences and trim it from the reads
+    #[structopt(long = "detect_adapter", raw(conflicts_with = "\"adapter\""))]
+    pub detect_adapter: bool,
+
+    /// Trim poly-A (or poly-T) tails from the 3' end of the reads
+    #[structopt(long = "trim_poly_a")]
+    pub trim_poly_a: bool,
+
+    /// Trim the 3' end of fq reads from the first window of four bases with a mean Phred quality below this
+    #[structopt(long = "quality_cutoff")]
+    pub quality_cutoff: Option<u8>,
+
+    /// Drop reads shorter than this after trimming
+    #[structopt(long = "min_read_length", default_value = "0")]
+    pub min_read_length: usize,
+
+    /// Optional fasta file of contaminant sequences, such as rRNA, mitochondrial, PhiX or adapter dimers, to
+    /// screen the reads against before quantification. May be given once for each set of contaminants. Sample
+    /// sheets and single-cell runs are not screened, so it cannot be combined with them.
+    #[structopt(long = "contaminants", raw(number_of_values = "1", conflicts_with_all = r#"&["sample_sheet", "single_cell_filename"]"#))]
+    pub contaminant_filenames: Vec<String>,
+
+    /// Fraction of the k-mers of a read that must be found in one contaminant sequence to remove the read
+    #[structopt(long = "contaminant_min_fraction", default_value = "0.5")]
+    pub contaminant_min_fraction: f64,
+
+    /// Optional file to write the reads left after contaminant screening to
+    #[structopt(long = "filtered_reads")]
+    pub filtered_reads_filename: Option<String>,
+
+    /// Optional file to write the reads removed as contaminants to
+    #[structopt(long = "contaminant_reads")]
+    pub contaminant_reads_filename: Option<String>,
+
+    /// Optional file to write the reads removed for each contaminant set and sequence to
+    #[structopt(long = "contaminant_summary")]
+    pub contaminant_summary_filename: Option<String>,
+
+    /// Optional file to write the reads left unassigned to, as fq (or fasta) with no_hits or decoy after the name
+    #[structopt(long = "unmapped_reads", raw(conflicts_with_all = r#"&["sample_sheet", "single_cell_filename"]"#))]
+    pub unmapped_reads_filename: Option<String>,
+
+    /// Optional file to write the reads assigned to more than one target to, with their candidate targets
+    #[structopt(long = "multimapping_reads", raw(conflicts_with_all = r#"&["sample_sheet", "single_cell_filename"]"#))]
+    pub multimapping_reads_filename: Option<String>,
+
+    /// Optional file to write the status, equivalence class, targets and k-mer hits of every read to. The
+    /// classes are numbered as in --eq_classes.
+    #[structopt(long = "read_assignments", raw(conflicts_with_all = r#"&["sample_sheet", "single_cell_filename"]"#))]
+    pub read_assignments_filename: Option<String>,
+
+    /// Optional file to write every equivalence class to, with its ID, number of reads and targets
+    #[structopt(long = "eq_classes", raw(conflicts_with_all = r#"&["sample_sheet", "single_cell_filename"]"#))]
+    pub eq_classes_filename: Option<String>,
+
+    /// Take the UMI of each read from the field of its name after the last of the given delimiter, such as _
+    /// for UMI-tools or : for bcl2fastq, and count molecules rather than reads. Names without a UMI of bases
+    /// after the delimiter are an error.
+    #[structopt(long = "umi_from_name", raw(conflicts_with = "\"single_cell_filename\""))]
+    pub umi_from_name: Option<char>,
+
+    /// Take the UMI of each read from its start, laid out by a pattern such as NNNNNNXX in which N marks a
+    /// UMI base and any other character a base to discard, and count molecules rather than reads
+    #[structopt(long = "umi_pattern", raw(conflicts_with_all = r#"&["umi_from_name", "single_cell_filename"]"#))]
+    pub umi_pattern: Option<String>,
+
+    /// Optional R1 file of cell barcodes and UMIs for single-cell quantification. The reads input is then the
+    /// matching R2 file of cDNA reads, and the output a directory of matrix.mtx, barcodes.tsv, features.tsv and
+    /// a summary.tsv of the read pairs. Outputs of bulk runs, such as alignments, bootstraps and the run
+    /// summary, cannot be combined with it.
+    #[structopt(long = "single_cell", raw(conflicts_with_all = r#"&["sample_sheet", "format", "extra_columns", "sam_output_filename",
+        "bam_output_filename", "gene_counts_filename", "junction_counts_filename", "meta_info_filename",
+        "lib_format_counts_filename", "num_bootstraps", "num_gibbs_samples", "bootstrap_output_filename",
+        "bootstrap_matrix_filename", "seq_bias", "gc_bias", "mates_filename", "gene_output_filename"]"#))]
+    pub single_cell_filename: Option<String>,
+
+    /// Optional file of the cell barcodes allowed in a single-cell run, one per line. Without one, the
+    /// barcodes before the knee of the barcode rank plot are taken to be cells.
+    #[structopt(long = "whitelist")]
+    pub whitelist_filename: Option<String>,
+
+    /// Optional file to write the whitelist inferred from the barcode rank plot to
+    #[structopt(long = "whitelist_output")]
+    pub whitelist_output_filename: Option<String>,
+
+    /// Optional file to write the rank, read count and cell call of every barcode to
+    #[structopt(long = "barcode_ranks")]
+    pub barcode_ranks_filename: Option<String>,
+
+    /// Length of the cell barcode at the start of R1
+    #[structopt(long = "barcode_length", default_value = "16")]
+    pub barcode_length: usize,
+
+    /// Length of the UMI following the cell barcode in R1
+    #[structopt(long = "umi_length", default_value = "12")]
+    pub umi_length: usize,
+
+    /// The name of the reads inputs, or of the sample sheet with --sample_sheet
     #[structopt(parse(from_str))]
     pub read_input_filename : String,
 
@@ -26,7 +276,234 @@ pub struct Opt {
     #[structopt(parse(from_str))]
     pub seq_input_filename : String,
 
-    /// The name of the output file containing the sequence IDs mapped to their counts
+    /// The name of the output file containing the sequence IDs mapped to their counts. Each read is counted
+    /// once for every segment hit by the most of its k-mers (see CHANGELOG.md for how this differs from 0.1.0).
     #[structopt(parse(from_str))]
     pub seqcount_output_filename : String,
 }
+
+//The subcommands that can be run in place of quantification
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    #[structopt(name = "qc", about = "Quality control report of the reads of a fq or fasta file")]
+    Qc(QcOpt),
+    #[structopt(name = "kmer-count", about = "Count the k-mers of reads or reference sequences")]
+    KmerCount(KmerCountOpt),
+    #[structopt(name = "simulate", about = "Simulate reads from reference sequences with known abundances")]
+    Simulate(SimulateOpt),
+    #[structopt(name = "eval", about = "Compare a quantification to the true abundances of simulated reads")]
+    Eval(EvalOpt),
+    #[structopt(name = "classify", about = "Classify reads against reference sequences labelled with taxa")]
+    Classify(ClassifyOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct QcOpt {
+
+    /// Whether or not we want to debug
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// File to write the report to as JSON
+    #[structopt(long = "json", default_value = "qc.json")]
+    pub json_output_filename: String,
+
+    /// File to write the report to as a self-contained HTML page
+    #[structopt(long = "html", default_value = "qc.html")]
+    pub html_output_filename: String,
+
+    /// The name of the reads input
+    #[structopt(parse(from_str))]
+    pub read_input_filename : String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct KmerCountOpt {
+
+    /// Whether or not we want to debug
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// The k size for the k-mers
+    #[structopt(short = "k", long = "kk")]
+    pub k: usize,
+
+    /// Count each k-mer together with its reverse complement
+    #[structopt(short = "C", long = "canonical")]
+    pub canonical: bool,
+
+    /// Read the input as reference sequences, as for the sequence inputs of a quantification, rather than reads
+    #[structopt(long = "references")]
+    pub references: bool,
+
+    /// Write only the k-mers seen at least this many times
+    #[structopt(long = "min_count", default_value = "1")]
+    pub min_count: usize,
+
+    /// Format of the k-mer counts: text or binary
+    #[structopt(long = "format", default_value = "text")]
+    pub format: KmerFormat,
+
+    /// Optional file to write the k-mer multiplicity histogram to
+    #[structopt(long = "histogram")]
+    pub histogram_output_filename: Option<String>,
+
+    /// The name of the reads or reference input
+    #[structopt(parse(from_str))]
+    pub input_filename : String,
+
+    /// The name of the output file of k-mer counts
+    #[structopt(parse(from_str))]
+    pub kmer_output_filename : String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SimulateOpt {
+
+    /// Whether or not we want to debug
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// Number of reads (or read pairs) to simulate
+    #[structopt(short = "n", long = "num_reads", default_value = "100000")]
+    pub n_reads: usize,
+
+    /// Length of the simulated reads
+    #[structopt(long = "read_length", default_value = "100")]
+    pub read_length: usize,
+
+    /// Mean length of the fragments reads are drawn from
+    #[structopt(long = "fragment_mean", default_value = "250")]
+    pub fragment_mean: f64,
+
+    /// Standard deviation of the length of the fragments reads are drawn from
+    #[structopt(long = "fragment_sd", default_value = "25")]
+    pub fragment_sd: f64,
+
+    /// Rate at which bases are substituted by another base
+    #[structopt(long = "substitution_rate", default_value = "0.001")]
+    pub substitution_rate: f64,
+
+    /// Rate at which a random base is inserted before a base
+    #[structopt(long = "insertion_rate", default_value = "0.0001")]
+    pub insertion_rate: f64,
+
+    /// Rate at which bases are deleted
+    #[structopt(long = "deletion_rate", default_value = "0.0001")]
+    pub deletion_rate: f64,
+
+    /// Library type to simulate, as in Salmon: U, SF or SR, prefixed by I or M for paired-end reads
+    #[structopt(short = "l", long = "libtype", default_value = "U")]
+    pub libtype: LibraryType,
+
+    /// File to write the second reads of pairs to, required for paired-end library types
+    #[structopt(long = "mates")]
+    pub mates_output_filename: Option<String>,
+
+    /// Optional file to write the simulated abundance and read count of every target to
+    #[structopt(long = "truth_counts")]
+    pub truth_counts_filename: Option<String>,
+
+    /// Seed for the random draws of fragments and errors
+    #[structopt(long = "seed", default_value = "0")]
+    pub seed: u64,
+
+    /// Shorten pipe-delimited sequence IDs, such as GENCODE headers, to the transcript ID they start with
+    #[structopt(long = "gencode")]
+    pub gencode: bool,
+
+    /// The name of the sequence inputs
+    #[structopt(parse(from_str))]
+    pub seq_input_filename : String,
+
+    /// The name of the tab-separated file of target IDs and abundances, as TPM
+    #[structopt(parse(from_str))]
+    pub abundance_filename : String,
+
+    /// The name of the fq file to write the simulated reads to
+    #[structopt(parse(from_str))]
+    pub read_output_filename : String,
+
+    /// The name of the file to write the true origin of every read to
+    #[structopt(parse(from_str))]
+    pub truth_output_filename : String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EvalOpt {
+
+    /// Whether or not we want to debug
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// Column of the quantification and truth to compare, such as NumReads or TPM. Headerless files hold
+    /// only read counts, which are compared as NumReads.
+    #[structopt(long = "column", default_value = "NumReads")]
+    pub column: String,
+
+    /// Optional SAM file of the read alignments, to measure the precision and recall of read assignments
+    #[structopt(long = "sam", raw(requires = "\"read_truth_filename\"", conflicts_with = "\"assignments_filename\""))]
+    pub sam_filename: Option<String>,
+
+    /// Optional read assignments written with --read_assignments, as an alternative to a SAM file
+    #[structopt(long = "assignments", raw(requires = "\"read_truth_filename\""))]
+    pub assignments_filename: Option<String>,
+
+    /// Optional truth table of the origin of every read, as written by tuna simulate
+    #[structopt(long = "read_truth")]
+    pub read_truth_filename: Option<String>,
+
+    /// Optional file to write the evaluation to as JSON
+    #[structopt(long = "json")]
+    pub json_output_filename: Option<String>,
+
+    /// The name of the quantification output, in any of the output formats
+    #[structopt(parse(from_str))]
+    pub quant_filename : String,
+
+    /// The name of the true abundances, such as the truth counts of tuna simulate
+    #[structopt(parse(from_str))]
+    pub truth_filename : String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ClassifyOpt {
+
+    /// Whether or not we want to debug
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// The k size for the k-mers
+    #[structopt(short = "k", long = "kk")]
+    pub k: usize,
+
+    /// Taxonomy of taxon IDs and parent taxon IDs, such as an NCBI nodes.dmp. Tab-separated files may name
+    /// each taxon in a third column.
+    #[structopt(long = "taxonomy")]
+    pub taxonomy_filename: String,
+
+    /// Optional NCBI names.dmp to take the scientific name of every taxon from
+    #[structopt(long = "taxonomy_names")]
+    pub taxonomy_names_filename: Option<String>,
+
+    /// Optional tab-separated file of sequence IDs and their taxon IDs. Without one, taxa are taken from
+    /// taxid attributes of the sequence headers.
+    #[structopt(long = "seqid2taxid")]
+    pub seqid2taxid_filename: Option<String>,
+
+    /// Optional file to write the taxon of every read to
+    #[structopt(long = "classifications")]
+    pub classifications_filename: Option<String>,
+
+    /// The name of the reads inputs
+    #[structopt(parse(from_str))]
+    pub read_input_filename : String,
+
+    /// The name of the sequence inputs
+    #[structopt(parse(from_str))]
+    pub seq_input_filename : String,
+
+    /// The name of the output file of reads per taxon
+    #[structopt(parse(from_str))]
+    pub report_output_filename : String,
+}
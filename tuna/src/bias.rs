@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use log::*;
+
+use dna_hash_table::DNAHashTable;
+use abundance;
+
+//Bases before and after a read start that make up its sequence context
+const CONTEXT_BEFORE : usize = 2;
+const CONTEXT_AFTER : usize = 4;
+const CONTEXT_LENGTH : usize = CONTEXT_BEFORE + CONTEXT_AFTER;
+//Number of equal-width bins the GC content of a fragment is sorted into
+const GC_BINS : usize = 25;
+//Bounds on the correction any single context or GC bin can apply
+const MIN_WEIGHT : f64 = 0.01;
+const MAX_WEIGHT : f64 = 100.0;
+
+//How much more or less often reads start at a position than expected, by the bases around the start
+//and by the GC content of the fragment it begins
+pub struct BiasModel {
+	seq_weights : Option<Vec<[f64; 4]>>,
+	gc_weights : Option<Vec<f64>>,
+	fragment_length : usize,
+}
+
+impl BiasModel {
+	//Compare the read starts found by looking up the first k-mer of each read against every position a
+	//read could start at, weighted by the current abundance of its segment. Only reads whose first k-mer
+	//occurs once among the targets are used. Fragment lengths are not known, so the GC content of a fragment
+	//is approximated by that of the mean read length from its start, as for single-end reads.
+	pub fn learn(reads : &[String], kmer_hash_table : &DNAHashTable, n_targets : usize, counts : &HashMap<i32, i32>,
+			mean_read_length : f64, seq_bias : bool, gc_bias : bool) -> BiasModel {
+		let seg_strings : &Vec<String> = kmer_hash_table.segments();
+		let k : usize = kmer_hash_table.k();
+		let fragment_length : usize = (mean_read_length.round() as usize).max(1);
+
+		let mut observed_seq : Vec<[f64; 4]> = vec![[0.0; 4]; CONTEXT_LENGTH];
+		let mut observed_gc : Vec<f64> = vec![0.0; GC_BINS];
+		let mut n_starts : usize = 0;
+
+		for read in reads.iter().filter(|read| read.len() >= k) {
+			let start : Option<(usize, usize)> = kmer_hash_table.get_kmer(&read[..k]).and_then(|(kmers, kmer_indexes)| {
+				let hits : Vec<(usize, usize)> = kmer_indexes.iter()
+					.map(|&index| (kmers[index].segment_index, kmers[index].position))
+					.filter(|&(segment_index, _)| segment_index < n_targets)
+					.collect();
+				if hits.len() == 1 { Some(hits[0]) } else { None }
+			});
+			let (segment_index, position) = match start {
+				Some(start) if start.1 + fragment_length <= seg_strings[start.0].len() => start,
+				_ => continue,
+			};
+
+			let seg_bytes : &[u8] = seg_strings[segment_index].as_bytes();
+			add_context(&mut observed_seq, seg_bytes, position, 1.0);
+			observed_gc[gc_bin(&gc_prefix(seg_bytes), position, fragment_length)] += 1.0;
+			n_starts += 1;
+		}
+
+		if n_starts == 0 {
+			warn!("No reads could be placed to learn bias from; effective lengths are left uncorrected");
+			return BiasModel { seq_weights : None, gc_weights : None, fragment_length };
+		}
+
+		let mut expected_seq : Vec<[f64; 4]> = vec![[0.0; 4]; CONTEXT_LENGTH];
+		let mut expected_gc : Vec<f64> = vec![0.0; GC_BINS];
+		for (i, seg_string) in seg_strings.iter().take(n_targets).enumerate() {
+			let count : i32 = *counts.get(&(i as i32)).unwrap_or(&0);
+			if count == 0 || seg_string.len() < fragment_length {
+				continue;
+			}
+			let weight : f64 = count as f64 / abundance::effective_length(seg_string.len(), mean_read_length);
+			let seg_bytes : &[u8] = seg_string.as_bytes();
+			let prefix : Vec<usize> = gc_prefix(seg_bytes);
+			for position in 0..(seg_bytes.len() - fragment_length + 1) {
+				add_context(&mut expected_seq, seg_bytes, position, weight);
+				expected_gc[gc_bin(&prefix, position, fragment_length)] += weight;
+			}
+		}
+
+		info!("Learned bias from {} read starts", n_starts);
+		BiasModel {
+			seq_weights : if seq_bias {
+				Some(observed_seq.iter().zip(expected_seq.iter())
+					.map(|(observed, expected)| {
+						let ratios : Vec<f64> = ratios(observed, expected);
+						[ratios[0], ratios[1], ratios[2], ratios[3]]
+					})
+					.collect())
+			} else {
+				None
+			},
+			gc_weights : if gc_bias { Some(ratios(&observed_gc, &expected_gc)) } else { None },
+			fragment_length,
+		}
+	}
+
+	//The effective length of a segment scaled by the mean bias weight of the positions a read can start at
+	pub fn effective_lengths(&self, seg_strings : &[String], mean_read_length : f64) -> Vec<f64> {
+		seg_strings.iter()
+			.map(|seg_string| {
+				let length : f64 = abundance::effective_length(seg_string.len(), mean_read_length);
+				if seg_string.len() < self.fragment_length {
+					return length;
+				}
+				let seg_bytes : &[u8] = seg_string.as_bytes();
+				let prefix : Vec<usize> = gc_prefix(seg_bytes);
+				let n_positions : usize = seg_bytes.len() - self.fragment_length + 1;
+				let total_weight : f64 = (0..n_positions).map(|position| self.weight(seg_bytes, &prefix, position)).sum();
+				(length * total_weight / n_positions as f64).max(1.0)
+			})
+			.collect()
+	}
+
+	fn weight(&self, seg_bytes : &[u8], prefix : &[usize], position : usize) -> f64 {
+		let mut weight : f64 = 1.0;
+		if let Some(ref seq_weights) = self.seq_weights {
+			for (offset, base_weights) in seq_weights.iter().enumerate() {
+				if let Some(base) = context_base(seg_bytes, position, offset) {
+					weight *= base_weights[base];
+				}
+			}
+		}
+		if let Some(ref gc_weights) = self.gc_weights {
+			weight *= gc_weights[gc_bin(prefix, position, self.fragment_length)];
+		}
+		weight
+	}
+}
+
+//Tally the bases around a read start, skipping context that runs off either end of the segment
+fn add_context(tallies : &mut [[f64; 4]], seg_bytes : &[u8], position : usize, weight : f64) {
+	for (offset, tally) in tallies.iter_mut().enumerate() {
+		if let Some(base) = context_base(seg_bytes, position, offset) {
+			tally[base] += weight;
+		}
+	}
+}
+
+fn context_base(seg_bytes : &[u8], position : usize, offset : usize) -> Option<usize> {
+	let index : usize = (position + offset).checked_sub(CONTEXT_BEFORE)?;
+	match seg_bytes.get(index)? {
+		b'A' | b'a' => Some(0),
+		b'C' | b'c' => Some(1),
+		b'G' | b'g' => Some(2),
+		b'T' | b't' => Some(3),
+		_ => None,
+	}
+}
+
+//Running count of G and C bases, so that the GC content of any window is a subtraction
+fn gc_prefix(seg_bytes : &[u8]) -> Vec<usize> {
+	let mut prefix : Vec<usize> = Vec::with_capacity(seg_bytes.len() + 1);
+	prefix.push(0);
+	for &base in seg_bytes {
+		let is_gc : bool = matches!(base, b'G' | b'g' | b'C' | b'c');
+		let last : usize = prefix[prefix.len() - 1];
+		prefix.push(last + is_gc as usize);
+	}
+	prefix
+}
+
+fn gc_bin(prefix : &[usize], position : usize, fragment_length : usize) -> usize {
+	let gc : usize = prefix[position + fragment_length] - prefix[position];
+	(gc * GC_BINS / fragment_length).min(GC_BINS - 1)
+}
+
+//Observed over expected frequencies, each normalised to sum to one. Categories never expected are left
+//uncorrected.
+fn ratios(observed : &[f64], expected : &[f64]) -> Vec<f64> {
+	let observed_total : f64 = observed.iter().sum();
+	let expected_total : f64 = expected.iter().sum();
+	observed.iter().zip(expected.iter())
+		.map(|(&observed, &expected)| {
+			if expected <= 0.0 || observed_total <= 0.0 {
+				1.0
+			} else {
+				((observed / observed_total) / (expected / expected_total)).clamp(MIN_WEIGHT, MAX_WEIGHT)
+			}
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn gc_bins_use_the_window_from_the_start() {
+		let prefix : Vec<usize> = gc_prefix(b"GGCCAATT");
+		assert_eq!(prefix, vec![0, 1, 2, 3, 4, 4, 4, 4, 4]);
+		assert_eq!(gc_bin(&prefix, 0, 4), GC_BINS - 1);
+		assert_eq!(gc_bin(&prefix, 2, 4), GC_BINS / 2);
+		assert_eq!(gc_bin(&prefix, 4, 4), 0);
+	}
+
+	#[test]
+	fn context_skips_bases_off_the_segment() {
+		let seg_bytes : &[u8] = b"ACGTN";
+		assert_eq!(context_base(seg_bytes, 0, 0), None);
+		assert_eq!(context_base(seg_bytes, 0, CONTEXT_BEFORE), Some(0));
+		assert_eq!(context_base(seg_bytes, 1, CONTEXT_BEFORE + 2), Some(3));
+		assert_eq!(context_base(seg_bytes, 2, CONTEXT_BEFORE + 2), None);
+		assert_eq!(context_base(seg_bytes, 4, CONTEXT_BEFORE + 2), None);
+	}
+
+	#[test]
+	fn ratios_are_normalised_and_bounded() {
+		let weights : Vec<f64> = ratios(&[3.0, 1.0, 0.0, 5.0], &[1.0, 1.0, 2.0, 0.0]);
+		assert_eq!(weights[0], (3.0 / 9.0) / (1.0 / 4.0));
+		assert_eq!(weights[2], MIN_WEIGHT);
+		assert_eq!(weights[3], 1.0);
+		assert_eq!(ratios(&[0.0, 0.0], &[1.0, 1.0]), vec![1.0, 1.0]);
+	}
+
+	#[test]
+	fn unbiased_model_keeps_effective_lengths() {
+		let bias_model = BiasModel { seq_weights : None, gc_weights : Some(vec![1.0; GC_BINS]), fragment_length : 4 };
+		let seg_strings : Vec<String> = vec!["ACGTACGTAC".to_string(), "AC".to_string()];
+		let effective_lengths : Vec<f64> = bias_model.effective_lengths(&seg_strings, 4.0);
+		assert_eq!(effective_lengths[0], abundance::effective_length(10, 4.0));
+		assert_eq!(effective_lengths[1], abundance::effective_length(2, 4.0));
+	}
+}
@@ -0,0 +1,174 @@
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand_distr::{Binomial, Distribution, Gamma};
+use rayon::prelude::*;
+
+use std::collections::HashMap;
+
+use log::*;
+
+//Pseudo-count added to every target when drawing abundances in the Gibbs sampler
+const GIBBS_PRIOR : f64 = 0.01;
+
+//Equivalence classes in a fixed order, so that replicates drawn from the same seed agree
+pub struct ClassTable {
+	classes : Vec<(Vec<usize>, usize)>,
+	n_targets : usize,
+	n_reads : usize,
+}
+
+impl ClassTable {
+	pub fn new(equivalence_classes : &HashMap<Vec<usize>, usize>, n_targets : usize) -> ClassTable {
+		let mut classes : Vec<(Vec<usize>, usize)> = equivalence_classes.iter()
+			.map(|(targets, &count)| (targets.clone(), count))
+			.collect();
+		classes.sort();
+		let n_reads : usize = classes.iter().map(|&(_, count)| count).sum();
+		ClassTable { classes, n_targets, n_reads }
+	}
+
+	//Resample the reads with replacement n times. Every replicate credits each target of a resampled class
+	//once per read, just as the reads themselves are counted.
+	pub fn bootstrap(&self, n_replicates : usize, seed : u64) -> Vec<Vec<f64>> {
+		info!("Drawing {} bootstrap replicates of {} reads", n_replicates, self.n_reads);
+		(0..n_replicates).into_par_iter()
+			.map(|replicate| {
+				let mut rng = StdRng::seed_from_u64(seed.wrapping_add(replicate as u64));
+				let class_counts : Vec<u64> = self.resample(&mut rng);
+
+				let mut counts : Vec<f64> = vec![0.0; self.n_targets];
+				for ((targets, _), &class_count) in self.classes.iter().zip(class_counts.iter()) {
+					for &target in targets {
+						counts[target] += class_count as f64;
+					}
+				}
+				counts
+			})
+			.collect()
+	}
+
+	//Draw n samples of the estimated counts with a collapsed Gibbs sampler over the classes: the reads of
+	//every class are shared among its targets in proportion to their abundance, and abundances are then
+	//redrawn from the shared counts. Each sample is the last of burn_in rounds of its own chain.
+	pub fn gibbs(&self, n_samples : usize, burn_in : usize, effective_lengths : &[f64], seed : u64) -> Vec<Vec<f64>> {
+		info!("Drawing {} Gibbs samples of {} reads", n_samples, self.n_reads);
+		(0..n_samples).into_par_iter()
+			.map(|sample| {
+				let mut rng = StdRng::seed_from_u64(seed.wrapping_add(sample as u64));
+				let mut abundances : Vec<f64> = vec![1.0; self.n_targets];
+				let mut counts : Vec<f64> = vec![0.0; self.n_targets];
+
+				for _ in 0..burn_in.max(1) {
+					counts = self.allocate(&abundances, effective_lengths, &mut rng);
+					abundances = counts.iter()
+						.map(|&count| Gamma::new(count + GIBBS_PRIOR, 1.0).expect("invalid gamma shape").sample(&mut rng))
+						.collect();
+				}
+				counts
+			})
+			.collect()
+	}
+
+	//Multinomial resampling of the reads over the classes, one binomial draw per class
+	fn resample(&self, rng : &mut StdRng) -> Vec<u64> {
+		let mut remaining_reads : u64 = self.n_reads as u64;
+		let mut remaining_weight : usize = self.n_reads;
+		let mut class_counts : Vec<u64> = Vec::with_capacity(self.classes.len());
+
+		for &(_, count) in &self.classes {
+			let class_count : u64 = if remaining_weight == 0 || remaining_reads == 0 {
+				0
+			} else {
+				let p : f64 = (count as f64 / remaining_weight as f64).min(1.0);
+				Binomial::new(remaining_reads, p).expect("invalid binomial").sample(rng)
+			};
+			class_counts.push(class_count);
+			remaining_reads -= class_count;
+			remaining_weight -= count;
+		}
+		class_counts
+	}
+
+	//Share the reads of every class among its targets in proportion to their abundance per base
+	fn allocate(&self, abundances : &[f64], effective_lengths : &[f64], rng : &mut StdRng) -> Vec<f64> {
+		let mut counts : Vec<f64> = vec![0.0; self.n_targets];
+		for &(ref targets, count) in &self.classes {
+			let weights : Vec<f64> = targets.iter().map(|&target| abundances[target] / effective_lengths[target]).collect();
+			let mut remaining_weight : f64 = weights.iter().sum();
+			let mut remaining_reads : u64 = count as u64;
+
+			for (&target, &weight) in targets.iter().zip(weights.iter()) {
+				if remaining_reads == 0 {
+					break;
+				}
+				let p : f64 = if remaining_weight > 0.0 { (weight / remaining_weight).min(1.0) } else { 1.0 };
+				let share : u64 = Binomial::new(remaining_reads, p).expect("invalid binomial").sample(rng);
+				counts[target] += share as f64;
+				remaining_reads -= share;
+				remaining_weight -= weight;
+			}
+		}
+		counts
+	}
+}
+
+//Mean and variance of every target across the replicates
+pub fn summarise(replicates : &[Vec<f64>], n_targets : usize) -> Vec<(f64, f64)> {
+	let n : f64 = replicates.len() as f64;
+	(0..n_targets)
+		.map(|target| {
+			let mean : f64 = replicates.iter().map(|replicate| replicate[target]).sum::<f64>() / n;
+			let variance : f64 = if replicates.len() > 1 {
+				replicates.iter().map(|replicate| (replicate[target] - mean).powi(2)).sum::<f64>() / (n - 1.0)
+			} else {
+				0.0
+			};
+			(mean, variance)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn class_table() -> ClassTable {
+		let mut equivalence_classes : HashMap<Vec<usize>, usize> = HashMap::new();
+		equivalence_classes.insert(vec![0], 30);
+		equivalence_classes.insert(vec![1], 10);
+		equivalence_classes.insert(vec![1, 2], 20);
+		ClassTable::new(&equivalence_classes, 3)
+	}
+
+	#[test]
+	fn bootstrap_resamples_every_read_and_is_seeded() {
+		let class_table = class_table();
+		let replicates = class_table.bootstrap(5, 7);
+		assert_eq!(replicates.len(), 5);
+		for replicate in &replicates {
+			//Target 1 shares its class with target 2, so the unique classes sum to the reads drawn
+			assert_eq!(replicate[0] + replicate[1], 60.0);
+			assert!(replicate[2] <= replicate[1]);
+		}
+		assert_eq!(replicates, class_table.bootstrap(5, 7));
+	}
+
+	#[test]
+	fn gibbs_keeps_unique_reads_and_shares_the_rest() {
+		let class_table = class_table();
+		let samples = class_table.gibbs(4, 5, &[1.0, 1.0, 1.0], 3);
+		for sample in &samples {
+			assert_eq!(sample[0], 30.0);
+			assert!(sample[1] >= 10.0);
+			assert_eq!(sample[1] + sample[2], 30.0);
+		}
+		assert_eq!(samples, class_table.gibbs(4, 5, &[1.0, 1.0, 1.0], 3));
+	}
+
+	#[test]
+	fn summarise_gives_mean_and_sample_variance() {
+		let replicates = vec![vec![1.0, 4.0], vec![3.0, 4.0]];
+		assert_eq!(summarise(&replicates, 2), vec![(2.0, 2.0), (4.0, 0.0)]);
+		assert_eq!(summarise(&replicates[..1], 2), vec![(1.0, 0.0), (4.0, 0.0)]);
+	}
+}
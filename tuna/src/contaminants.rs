@@ -0,0 +1,148 @@
+use rayon::prelude::*;
+
+use std::path::Path;
+
+use log::*;
+
+use dna_hash_table::Pseudoaligner;
+use library_type::reverse_complement;
+use read_inputs::FaColDB;
+use read_inputs::FqRecord;
+
+//Contaminant sequences, such as rRNA, mitochondrial, PhiX or adapter dimers, grouped into the sets they
+//were supplied in
+pub struct ContaminantDB {
+	pub set_names : Vec<String>,
+	pub seg_ids : Vec<String>,
+	pub seg_sets : Vec<usize>,
+	//Every sequence followed by its reverse complement, so that reads from either strand are caught
+	pub seg_strings : Vec<String>,
+}
+
+impl ContaminantDB {
+	//Gather the sequences of every set, named after its file, leaving out sequences too short to hold a k-mer
+	pub fn new(sets : Vec<(String, FaColDB)>, k : usize) -> ContaminantDB {
+		let mut contaminant_db = ContaminantDB { set_names : Vec::new(), seg_ids : Vec::new(), seg_sets : Vec::new(), seg_strings : Vec::new() };
+		for (set_index, (set_name, fa_col_db)) in sets.into_iter().enumerate() {
+			for (seg_id, seg_string) in fa_col_db.seg_ids.into_iter().zip(fa_col_db.seg_strings) {
+				if seg_string.len() < k {
+					warn!("Skipping contaminant {} of {}, which is shorter than k", seg_id, set_name);
+					continue;
+				}
+				contaminant_db.seg_ids.push(seg_id);
+				contaminant_db.seg_sets.push(set_index);
+				let seg_string_rc : String = reverse_complement(&seg_string);
+				contaminant_db.seg_strings.push(seg_string);
+				contaminant_db.seg_strings.push(seg_string_rc);
+			}
+			contaminant_db.set_names.push(set_name);
+		}
+		info!("Indexing {} contaminant sequences from {} sets", contaminant_db.seg_ids.len(), contaminant_db.set_names.len());
+		contaminant_db
+	}
+}
+
+//The name a contaminant set goes by: its file name without directory or extension
+pub fn set_name(filename : &str) -> String {
+	Path::new(filename).file_stem().map_or(filename.to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+//What screening did to the reads of a run
+#[derive(Clone, Debug, Default)]
+pub struct ContaminantStats {
+	pub n_reads : usize,
+	pub n_contaminant : usize,
+	//Reads taken for each contaminant sequence
+	pub sequence_hits : Vec<usize>,
+}
+
+/**
+    screen_reads:
+    Pseudoalign every read against the contaminant index, and remove those with at least
+    min_fraction of their k-mers in a single contaminant sequence, along with their mates.
+    The removed reads are returned, and each is credited to the contaminant sequence it
+    shares most k-mers with.
+*/
+pub fn screen_reads<P : Pseudoaligner + Sync>(records : &mut Vec<FqRecord>, mates : Option<&mut Vec<FqRecord>>, pseudoaligner : &P,
+		contaminant_db : &ContaminantDB, k : usize, min_fraction : f64) -> (Vec<FqRecord>, ContaminantStats) {
+	let matches : Vec<Option<usize>> = records.par_iter()
+		.map(|record| {
+			let n_kmers : usize = (record.seq.len() + 1).saturating_sub(k);
+			let read_mapping = pseudoaligner.pseudoalign(&record.seq)?;
+			let &(segment_index, hits) = read_mapping.kmer_hits.iter().max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))?;
+			if hits as f64 >= min_fraction * n_kmers as f64 { Some(segment_index / 2) } else { None }
+		})
+		.collect();
+	if let Some(mates) = mates {
+		if mates.len() != records.len() {
+			panic!("The reads have {} records but their mates {}", records.len(), mates.len());
+		}
+		let mut is_clean = matches.iter().map(|sequence_index| sequence_index.is_none());
+		mates.retain(|_| is_clean.next().unwrap_or(false));
+	}
+
+	let mut contaminant_stats = ContaminantStats {
+		n_reads : records.len(),
+		sequence_hits : vec![0; contaminant_db.seg_ids.len()],
+		..ContaminantStats::default()
+	};
+	let mut clean : Vec<FqRecord> = Vec::with_capacity(records.len());
+	let mut removed : Vec<FqRecord> = Vec::new();
+	for (record, sequence_index) in records.drain(..).zip(matches) {
+		match sequence_index {
+			Some(sequence_index) => {
+				contaminant_stats.sequence_hits[sequence_index] += 1;
+				removed.push(record);
+			},
+			None => clean.push(record),
+		}
+	}
+	contaminant_stats.n_contaminant = removed.len();
+	*records = clean;
+
+	info!("Removed {} of {} reads as contaminants", contaminant_stats.n_contaminant, contaminant_stats.n_reads);
+	(removed, contaminant_stats)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dna_hash_table::DNAHashTable;
+
+	fn record(name : &str, seq : &str) -> FqRecord {
+		FqRecord { name : name.to_string(), seq : seq.to_string(), qual : None, umi : None }
+	}
+
+	fn fa_col_db(seg_strings : &[&str]) -> FaColDB {
+		FaColDB {
+			seg_ids : (0..seg_strings.len()).map(|i| format!("c{}", i)).collect(),
+			seg_strings : seg_strings.iter().map(|seg_string| seg_string.to_string()).collect(),
+			seg_metadata : Vec::new(),
+			n_targets : seg_strings.len(),
+		}
+	}
+
+	#[test]
+	fn names_sets_after_their_files() {
+		assert_eq!(set_name("/refs/rRNA.fa"), "rRNA");
+		assert_eq!(set_name("phix"), "phix");
+	}
+
+	#[test]
+	fn removes_contaminant_reads_on_either_strand_with_their_mates() {
+		let contaminant_db = ContaminantDB::new(vec![("rRNA".to_string(), fa_col_db(&["CCGTAATGCCTTTCCCTAACAGAG", "ACG"]))], 8);
+		assert_eq!(contaminant_db.seg_ids, vec!["c0"]);
+		assert_eq!(contaminant_db.seg_strings.len(), 2);
+		let contaminant_hash_table = DNAHashTable::new(&contaminant_db.seg_strings, 8);
+
+		//The second read is the reverse complement of part of the contaminant, the third shares only one k-mer with it
+		let mut records = vec![record("r1", "GGGGGGGGGGGGGGGG"), record("r2", "CTCTGTTAGGGAAAGG"), record("r3", "CCGTAATGAAAAAAAA")];
+		let mut mates = vec![record("r1", "AAAA"), record("r2", "CCCC"), record("r3", "TTTT")];
+		let (removed, contaminant_stats) = screen_reads(&mut records, Some(&mut mates), &contaminant_hash_table, &contaminant_db, 8, 0.5);
+
+		assert_eq!(removed.iter().map(|record| record.name.as_str()).collect::<Vec<&str>>(), vec!["r2"]);
+		assert_eq!(records.iter().map(|record| record.name.as_str()).collect::<Vec<&str>>(), vec!["r1", "r3"]);
+		assert_eq!(mates.iter().map(|mate| mate.name.as_str()).collect::<Vec<&str>>(), vec!["r1", "r3"]);
+		assert_eq!((contaminant_stats.n_reads, contaminant_stats.n_contaminant, contaminant_stats.sequence_hits.clone()), (3, 1, vec![1]));
+	}
+}
@@ -0,0 +1,201 @@
+use std::fs::File;
+use std::io::Write;
+use std::io::BufWriter;
+use std::str::FromStr;
+
+//Columns of the headered formats, in the order Salmon writes them to quant.sf
+const COLUMNS : [&str; 5] = ["Name", "Length", "EffectiveLength", "TPM", "NumReads"];
+
+//The file formats segment counts can be written in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+	Counts,
+	Tsv,
+	Csv,
+	JsonLines,
+	QuantSf,
+}
+
+impl FromStr for OutputFormat {
+	type Err = String;
+
+	fn from_str(format : &str) -> Result<OutputFormat, String> {
+		match format {
+			"counts" => Ok(OutputFormat::Counts),
+			"tsv" => Ok(OutputFormat::Tsv),
+			"csv" => Ok(OutputFormat::Csv),
+			"jsonl" => Ok(OutputFormat::JsonLines),
+			"quant.sf" | "quant_sf" => Ok(OutputFormat::QuantSf),
+			_ => Err(format!("unknown output format {}, expected one of counts, tsv, csv, jsonl or quant.sf", format)),
+		}
+	}
+}
+
+//Everything known about one segment that an output format may include
+pub struct SegmentRow<'a> {
+	pub name : &'a str,
+	pub length : usize,
+	pub effective_length : f64,
+	pub tpm : f64,
+	pub num_reads : f64,
+	pub extra_values : Vec<&'a str>,
+}
+
+//Anything that can write segment rows to a file
+pub trait CountWriter {
+	fn write_row(&mut self, row : &SegmentRow);
+}
+
+/**
+    new_count_writer:
+    Create a writer for the given format, which writes any header straight away. The extra
+    columns are left out of quant.sf, which has a fixed set of columns.
+*/
+pub fn new_count_writer(filename : &str, format : OutputFormat, extra_columns : &[String]) -> Box<dyn CountWriter> {
+	let writer = BufWriter::new(File::create(filename).expect("Unable to create file"));
+	match format {
+		OutputFormat::Counts => Box::new(CountsWriter { writer }),
+		OutputFormat::Tsv => Box::new(DelimitedWriter::new(writer, '\t', extra_columns)),
+		OutputFormat::Csv => Box::new(DelimitedWriter::new(writer, ',', extra_columns)),
+		OutputFormat::JsonLines => Box::new(JsonLinesWriter { writer, extra_columns : extra_columns.to_vec() }),
+		OutputFormat::QuantSf => Box::new(DelimitedWriter::new(writer, '\t', &[])),
+	}
+}
+
+//The original headerless output of IDs, read counts and any extra columns
+struct CountsWriter {
+	writer : BufWriter<File>,
+}
+
+impl CountWriter for CountsWriter {
+	fn write_row(&mut self, row : &SegmentRow) {
+		let mut fields : Vec<String> = vec![row.name.to_string(), row.num_reads.to_string()];
+		fields.extend(row.extra_values.iter().map(|value| value.to_string()));
+		self.writer.write_fmt(format_args!("{}\n", fields.join("\t"))).expect("Write failed");
+	}
+}
+
+//Headered columns separated by tabs or commas. Comma-separated fields are quoted where needed.
+struct DelimitedWriter {
+	writer : BufWriter<File>,
+	separator : char,
+	n_extra_columns : usize,
+}
+
+impl DelimitedWriter {
+	fn new(writer : BufWriter<File>, separator : char, extra_columns : &[String]) -> DelimitedWriter {
+		let mut delimited_writer = DelimitedWriter { writer, separator, n_extra_columns : extra_columns.len() };
+		let header : Vec<&str> = COLUMNS.iter().cloned().chain(extra_columns.iter().map(|column| column.as_str())).collect();
+		delimited_writer.write_fields(&header);
+		delimited_writer
+	}
+
+	fn write_fields(&mut self, fields : &[&str]) {
+		let separator : char = self.separator;
+		let fields : Vec<String> = fields.iter()
+			.map(|field| {
+				if separator == ',' && field.contains([',', '"', '\n']) {
+					format!("\"{}\"", field.replace('"', "\"\""))
+				} else {
+					field.to_string()
+				}
+			})
+			.collect();
+		self.writer.write_fmt(format_args!("{}\n", fields.join(&separator.to_string()))).expect("Write failed");
+	}
+}
+
+impl CountWriter for DelimitedWriter {
+	fn write_row(&mut self, row : &SegmentRow) {
+		let values : Vec<String> = vec![row.length.to_string(), format!("{:.3}", row.effective_length),
+			format!("{:.6}", row.tpm), format!("{:.3}", row.num_reads)];
+		let mut fields : Vec<&str> = vec![row.name];
+		fields.extend(values.iter().map(|value| value.as_str()));
+		fields.extend(row.extra_values.iter().take(self.n_extra_columns).cloned());
+		self.write_fields(&fields);
+	}
+}
+
+//One JSON object per segment and line
+struct JsonLinesWriter {
+	writer : BufWriter<File>,
+	extra_columns : Vec<String>,
+}
+
+impl CountWriter for JsonLinesWriter {
+	fn write_row(&mut self, row : &SegmentRow) {
+		let mut fields : Vec<String> = vec![
+			format!("{}: {}", json_string(COLUMNS[0]), json_string(row.name)),
+			format!("{}: {}", json_string(COLUMNS[1]), row.length),
+			format!("{}: {:.3}", json_string(COLUMNS[2]), row.effective_length),
+			format!("{}: {:.6}", json_string(COLUMNS[3]), row.tpm),
+			format!("{}: {:.3}", json_string(COLUMNS[4]), row.num_reads),
+		];
+		for (column, value) in self.extra_columns.iter().zip(row.extra_values.iter()) {
+			fields.push(format!("{}: {}", json_string(column), json_string(value)));
+		}
+		self.writer.write_fmt(format_args!("{{{}}}\n", fields.join(", "))).expect("Write failed");
+	}
+}
+
+//A string as a quoted JSON value
+pub fn json_string(value : &str) -> String {
+	let mut quoted : String = String::with_capacity(value.len() + 2);
+	quoted.push('"');
+	for c in value.chars() {
+		match c {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			'\t' => quoted.push_str("\\t"),
+			c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+			c => quoted.push(c),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write_rows(name : &str, format : OutputFormat) -> String {
+		let path = std::env::temp_dir().join(name);
+		let filename : &str = path.to_str().unwrap();
+		{
+			let mut count_writer = new_count_writer(filename, format, &["gene_name".to_string()]);
+			count_writer.write_row(&SegmentRow {
+				name : "t1,\"a\"", length : 100, effective_length : 50.5, tpm : 1.25, num_reads : 3.0, extra_values : vec!["G\t1"],
+			});
+		}
+		std::fs::read_to_string(filename).unwrap()
+	}
+
+	#[test]
+	fn parses_formats() {
+		assert_eq!("quant.sf".parse::<OutputFormat>(), Ok(OutputFormat::QuantSf));
+		assert_eq!("jsonl".parse::<OutputFormat>(), Ok(OutputFormat::JsonLines));
+		assert!("xml".parse::<OutputFormat>().is_err());
+	}
+
+	#[test]
+	fn writes_headerless_counts() {
+		assert_eq!(write_rows("tuna_test_counts.txt", OutputFormat::Counts), "t1,\"a\"\t3\tG\t1\n");
+	}
+
+	#[test]
+	fn quotes_csv_fields_and_drops_extra_columns_from_quant_sf() {
+		assert_eq!(write_rows("tuna_test_counts.csv", OutputFormat::Csv),
+			"Name,Length,EffectiveLength,TPM,NumReads,gene_name\n\"t1,\"\"a\"\"\",100,50.500,1.250000,3.000,G\t1\n");
+		assert_eq!(write_rows("tuna_test_quant.sf", OutputFormat::QuantSf),
+			"Name\tLength\tEffectiveLength\tTPM\tNumReads\nt1,\"a\"\t100\t50.500\t1.250000\t3.000\n");
+	}
+
+	#[test]
+	fn escapes_json_lines() {
+		assert_eq!(write_rows("tuna_test_counts.jsonl", OutputFormat::JsonLines),
+			"{\"Name\": \"t1,\\\"a\\\"\", \"Length\": 100, \"EffectiveLength\": 50.500, \"TPM\": 1.250000, \"NumReads\": 3.000, \"gene_name\": \"G\\t1\"}\n");
+		assert_eq!(json_string("\u{1}\\"), "\"\\u0001\\\\\"");
+	}
+}
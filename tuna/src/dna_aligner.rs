@@ -0,0 +1,325 @@
+use dna_hash_table::DNAHashTable;
+use library_type::reverse_complement;
+
+use std::cmp;
+use std::collections::HashMap;
+
+const MATCH_SCORE : i32 = 2;
+const MISMATCH_SCORE : i32 = -4;
+const GAP_SCORE : i32 = -6;
+const NEG_INFINITY : i32 = i32::MIN / 2;
+const MAX_MAPQ : u8 = 60;
+
+//An alignment of a whole read against a segment, starting at position (0-based) on the segment. Reverse
+//alignments are of the reverse complement of the read, and their CIGAR runs along the forward strand.
+#[derive(Clone, Debug)]
+pub struct Alignment {
+	pub segment_index : usize,
+	pub position : usize,
+	pub reverse : bool,
+	pub cigar : Vec<(usize, char)>,
+	pub edit_distance : usize,
+	pub score : i32,
+}
+
+impl Alignment {
+	pub fn cigar_string(&self) -> String {
+		self.cigar.iter().map(|&(length, op)| format!("{}{}", length, op)).collect()
+	}
+
+	//The 0-based half-open stretches of the reference covered by the alignment, split at skipped regions
+	pub fn blocks(&self) -> Vec<(usize, usize)> {
+		let mut blocks : Vec<(usize, usize)> = Vec::new();
+		let mut start : usize = self.position;
+		let mut end : usize = self.position;
+		for &(length, op) in &self.cigar {
+			match op {
+				'M' | 'D' => end += length,
+				'N' => {
+					blocks.push((start, end));
+					start = end + length;
+					end = start;
+				},
+				_ => {}
+			}
+		}
+		blocks.push((start, end));
+		blocks
+	}
+}
+
+//Anything that can align a whole read against the reference segments
+pub trait ReadAligner {
+	fn align(&self, read : &str) -> Vec<Alignment>;
+}
+
+//Uses the k-mer hits of a read in the hash table as seeds, votes for the diagonals they lie on, and
+//verifies the best supported diagonals with a banded alignment against the segment
+pub struct SeedExtendAligner<'a> {
+	kmer_hash_table : &'a DNAHashTable<'a>,
+	band : usize,
+	max_candidates : usize,
+	min_score_fraction : f64,
+}
+
+impl<'a> SeedExtendAligner<'a> {
+	pub fn new(kmer_hash_table : &'a DNAHashTable<'a>, band : usize, min_score_fraction : f64) -> SeedExtendAligner<'a> {
+		SeedExtendAligner {
+			kmer_hash_table,
+			band,
+			max_candidates : 10,
+			min_score_fraction,
+		}
+	}
+
+	fn min_score(&self, read : &str) -> i32 {
+		(self.min_score_fraction * (MATCH_SCORE as f64) * (read.len() as f64)).ceil() as i32
+	}
+
+	//Mapping quality of the best alignment given the score of the runner up, if any
+	pub fn mapping_quality(alignments : &[Alignment]) -> u8 {
+		match (alignments.first(), alignments.get(1)) {
+			(None, _) => 0,
+			(Some(_), None) => MAX_MAPQ,
+			(Some(best), Some(second)) => {
+				if best.score <= second.score || best.score <= 0 {
+					0
+				} else {
+					let fraction : f64 = (best.score - second.score) as f64 / best.score as f64;
+					cmp::max(1, (fraction * MAX_MAPQ as f64).round() as u8)
+				}
+			}
+		}
+	}
+
+	//Vote for (segment, diagonal) pairs with every seed, merging diagonals that fall within the band of
+	//a better supported one
+	fn get_candidates(&self, read : &str) -> Vec<(usize, isize)> {
+		let k : usize = self.kmer_hash_table.k();
+		let mut votes : HashMap<(usize, isize), usize> = HashMap::new();
+		if read.len() < k {
+			return Vec::new();
+		}
+
+		for i in 0..(read.len() - k + 1) {
+			if let Some((kmers, kmer_indexes)) = self.kmer_hash_table.get_kmer(&read[i..(i + k)]) {
+				for kmer_index in kmer_indexes {
+					let diagonal : isize = kmers[kmer_index].position as isize - i as isize;
+					*votes.entry((kmers[kmer_index].segment_index, diagonal)).or_insert(0) += 1;
+				}
+			}
+		}
+
+		let mut ranked : Vec<((usize, isize), usize)> = votes.into_iter().collect();
+		ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+		let mut candidates : Vec<(usize, isize)> = Vec::new();
+		for ((segment_index, diagonal), _) in ranked {
+			let covered : bool = candidates.iter().any(|&(candidate_segment, candidate_diagonal)| {
+				candidate_segment == segment_index && (candidate_diagonal - diagonal).abs() <= self.band as isize
+			});
+			if !covered {
+				candidates.push((segment_index, diagonal));
+				if candidates.len() == self.max_candidates {
+					break;
+				}
+			}
+		}
+		candidates
+	}
+
+	//Semi-global alignment of the whole read against the segment, restricted to a band around the diagonal.
+	//The reference may start and end anywhere, but every letter of the read must be aligned.
+	fn extend(&self, read : &str, segment_index : usize, diagonal : isize, reverse : bool) -> Option<Alignment> {
+		let segment : &[u8] = self.kmer_hash_table.segments()[segment_index].as_bytes();
+		let read_bytes : &[u8] = read.as_bytes();
+		let band : isize = self.band as isize;
+		let m : usize = read_bytes.len();
+
+		let window_start : usize = cmp::max(0, diagonal - band) as usize;
+		let window_end : usize = cmp::min(segment.len() as isize, diagonal + m as isize + band) as usize;
+		if window_start >= window_end {
+			return None;
+		}
+		let window : &[u8] = &segment[window_start..window_end];
+		let n : isize = window.len() as isize;
+		let center : isize = diagonal - window_start as isize;
+		let width : usize = 2 * self.band + 1;
+
+		//Cell (i, j) of the band is stored at i * width + t, where j = i + center - band + t
+		let column = |i : usize, t : usize| -> isize { i as isize + center - band + t as isize };
+		let mut scores : Vec<i32> = vec![NEG_INFINITY; (m + 1) * width];
+		let mut moves : Vec<u8> = vec![0; (m + 1) * width];
+
+		for (t, score) in scores.iter_mut().enumerate().take(width) {
+			let j : isize = column(0, t);
+			if j >= 0 && j <= n {
+				*score = 0;
+			}
+		}
+
+		for i in 1..(m + 1) {
+			for t in 0..width {
+				let j : isize = column(i, t);
+				if j < 0 || j > n {
+					continue;
+				}
+				let cell : usize = i * width + t;
+				let mut best : (i32, u8) = (NEG_INFINITY, 0);
+
+				if j >= 1 && scores[(i - 1) * width + t] > NEG_INFINITY {
+					let letter_score : i32 = if read_bytes[i - 1] == window[(j - 1) as usize] { MATCH_SCORE } else { MISMATCH_SCORE };
+					best = (scores[(i - 1) * width + t] + letter_score, 0);
+				}
+				if t + 1 < width && scores[(i - 1) * width + t + 1] > NEG_INFINITY && scores[(i - 1) * width + t + 1] + GAP_SCORE > best.0 {
+					best = (scores[(i - 1) * width + t + 1] + GAP_SCORE, 1);
+				}
+				if t >= 1 && j >= 1 && scores[cell - 1] > NEG_INFINITY && scores[cell - 1] + GAP_SCORE > best.0 {
+					best = (scores[cell - 1] + GAP_SCORE, 2);
+				}
+				scores[cell] = best.0;
+				moves[cell] = best.1;
+			}
+		}
+
+		let (best_t, best_score) = (0..width)
+			.map(|t| (t, scores[m * width + t]))
+			.filter(|&(_, score)| score > NEG_INFINITY)
+			.max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))?;
+
+		//Trace back from the best cell of the last row to the first row
+		let mut ops : Vec<char> = Vec::new();
+		let mut edit_distance : usize = 0;
+		let (mut i, mut t) = (m, best_t);
+		while i > 0 {
+			match moves[i * width + t] {
+				0 => {
+					let j : usize = column(i, t) as usize;
+					if read_bytes[i - 1] != window[j - 1] {
+						edit_distance += 1;
+					}
+					ops.push('M');
+					i -= 1;
+				},
+				1 => {
+					ops.push('I');
+					edit_distance += 1;
+					i -= 1;
+					t += 1;
+				},
+				_ => {
+					ops.push('D');
+					edit_distance += 1;
+					t -= 1;
+				}
+			}
+		}
+		ops.reverse();
+
+		let mut cigar : Vec<(usize, char)> = Vec::new();
+		for op in ops {
+			match cigar.last_mut() {
+				Some(last) if last.1 == op => last.0 += 1,
+				_ => cigar.push((1, op)),
+			}
+		}
+
+		Some(Alignment {
+			segment_index,
+			position : window_start + column(0, t) as usize,
+			reverse,
+			cigar,
+			edit_distance,
+			score : best_score,
+		})
+	}
+}
+
+impl<'a> ReadAligner for SeedExtendAligner<'a> {
+	//All alignments of the read or its reverse complement scoring above the minimum, best first
+	fn align(&self, read : &str) -> Vec<Alignment> {
+		let min_score : i32 = self.min_score(read);
+		let read_rc : String = reverse_complement(read);
+		let mut alignments : Vec<Alignment> = Vec::new();
+		for &(strand_read, reverse) in &[(read, false), (read_rc.as_str(), true)] {
+			alignments.extend(self.get_candidates(strand_read).into_iter()
+				.filter_map(|(segment_index, diagonal)| self.extend(strand_read, segment_index, diagonal, reverse))
+				.filter(|alignment| alignment.score >= min_score));
+		}
+
+		sort_alignments(&mut alignments);
+		alignments
+	}
+}
+
+//Order alignments best first, dropping repeated alignments of the read to the same place
+pub fn sort_alignments(alignments : &mut Vec<Alignment>) {
+	alignments.sort_by(|a, b| b.score.cmp(&a.score).then(a.segment_index.cmp(&b.segment_index)).then(a.position.cmp(&b.position))
+		.then(a.reverse.cmp(&b.reverse)));
+	let mut seen : Vec<(usize, usize, bool)> = Vec::new();
+	alignments.retain(|alignment| {
+		let key : (usize, usize, bool) = (alignment.segment_index, alignment.position, alignment.reverse);
+		if seen.contains(&key) {
+			false
+		} else {
+			seen.push(key);
+			true
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SEGMENT : &str = "GCTAAAGACAATTACATAACATACACGTCAGCACGAAACTTGTTGGCCCAGTGTGAATCGCTTAAGGGTTAAGTAAGTGTGATGCATACGCCTTTACTTG";
+
+	fn best_alignment(read : &str) -> Alignment {
+		let segments : Vec<String> = vec![SEGMENT.to_string()];
+		let kmer_hash_table = DNAHashTable::new(&segments, 8);
+		let aligner = SeedExtendAligner::new(&kmer_hash_table, 8, 0.65);
+		aligner.align(read).into_iter().next().expect("read did not align")
+	}
+
+	#[test]
+	fn aligns_an_exact_substring() {
+		let alignment = best_alignment(&SEGMENT[10..40]);
+		assert_eq!((alignment.position, alignment.reverse), (10, false));
+		assert_eq!(alignment.cigar_string(), "30M");
+		assert_eq!((alignment.edit_distance, alignment.score), (0, 60));
+	}
+
+	#[test]
+	fn counts_mismatches_in_the_edit_distance() {
+		let read : String = format!("{}T{}", &SEGMENT[10..24], &SEGMENT[25..40]);
+		let alignment = best_alignment(&read);
+		assert_eq!(alignment.cigar_string(), "30M");
+		assert_eq!((alignment.edit_distance, alignment.score), (1, 29 * MATCH_SCORE + MISMATCH_SCORE));
+	}
+
+	#[test]
+	fn places_insertions_and_deletions_in_the_cigar() {
+		let inserted = best_alignment(&format!("{}T{}", &SEGMENT[10..25], &SEGMENT[25..45]));
+		assert_eq!((inserted.position, inserted.cigar_string(), inserted.edit_distance), (10, "15M1I20M".to_string(), 1));
+
+		let deleted = best_alignment(&format!("{}{}", &SEGMENT[10..25], &SEGMENT[26..45]));
+		assert_eq!((deleted.position, deleted.cigar_string(), deleted.edit_distance), (10, "15M1D19M".to_string(), 1));
+		assert_eq!(deleted.blocks(), vec![(10, 45)]);
+	}
+
+	#[test]
+	fn aligns_reads_from_the_reverse_strand() {
+		let alignment = best_alignment(&reverse_complement(&SEGMENT[50..90]));
+		assert_eq!((alignment.position, alignment.reverse), (50, true));
+		assert_eq!(alignment.cigar_string(), "40M");
+	}
+
+	#[test]
+	fn mapping_quality_falls_with_the_runner_up_score() {
+		let alignment = |score : i32| Alignment { segment_index : 0, position : 0, reverse : false, cigar : Vec::new(), edit_distance : 0, score };
+		assert_eq!(SeedExtendAligner::mapping_quality(&[]), 0);
+		assert_eq!(SeedExtendAligner::mapping_quality(&[alignment(60)]), MAX_MAPQ);
+		assert_eq!(SeedExtendAligner::mapping_quality(&[alignment(60), alignment(30)]), 30);
+		assert_eq!(SeedExtendAligner::mapping_quality(&[alignment(60), alignment(60)]), 0);
+	}
+}
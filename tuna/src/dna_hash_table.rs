@@ -76,6 +76,19 @@ impl<'a> DNAHashTable<'a> {
 		}
 	}
 
+	pub fn k(&self) -> usize {
+		self.k
+	}
+
+	pub fn segments(&self) -> &'a Vec<String> {
+		self.segments
+	}
+
+	//The sequence of a k-mer entry stored in the table
+	pub fn kmer_string(&self, kmer : &Kmer) -> &'a str {
+		&self.segments[kmer.segment_index][kmer.position..(kmer.position + self.k)]
+	}
+
 	//Get the table size
 	fn get_table_size(segments : &Vec<String>, k : usize) -> usize {
 		let mut size : usize = 0;
@@ -90,7 +103,7 @@ impl<'a> DNAHashTable<'a> {
 	}
 
 	fn get_max_j(k : usize) -> usize {
-		let max_value : usize = usize::max_value();
+		let max_value : usize = usize::MAX;
 		let j : usize = DNAHashTable::integer_log_base_4(max_value);
 		cmp::min(j, k)
 
@@ -128,28 +141,188 @@ impl<'a> DNAHashTable<'a> {
 }
 
 
-pub fn get_segments(kmer_hash_table : &DNAHashTable, reads : &Vec<String>) -> HashMap<i32, i32> {
-    let mut segment_index_counts : HashMap<i32, i32> = HashMap::new();
+//The k-mer hits of a single read: every segment sharing at least one k-mer with the read, paired with
+//the number of the read's k-mers found in it, sorted by segment index
+#[derive(Clone, Debug)]
+pub struct ReadMapping {
+	pub kmer_hits : Vec<(usize, usize)>,
+}
 
-	for r in reads {
-		match kmer_hash_table.get_kmer(&r) {
-	    	Some((kmers, kmer_indexes)) => {
-	    		for kmer_index in kmer_indexes {
-	    			debug!("{:?}", &kmers[kmer_index]);
-	                let count_instance = &kmers[kmer_index];
+impl ReadMapping {
+	pub fn from_hit_counts(hit_counts : HashMap<usize, usize>) -> Option<ReadMapping> {
+		if hit_counts.is_empty() {
+			return None;
+		}
+		let mut kmer_hits : Vec<(usize, usize)> = hit_counts.into_iter().collect();
+		kmer_hits.sort();
+		Some(ReadMapping { kmer_hits })
+	}
+
+	//Number of the read's k-mers found in a segment
+	pub fn hits(&self, segment_index : usize) -> usize {
+		self.kmer_hits.binary_search_by_key(&segment_index, |&(index, _)| index).map_or(0, |i| self.kmer_hits[i].1)
+	}
+
+	//The targets best explaining the read, i.e. those hit by the most k-mers. Segments from n_targets on are
+	//decoys, and if a decoy explains the read strictly better than every target the read is left unassigned.
+	pub fn best_targets(&self, n_targets : usize) -> Option<Vec<usize>> {
+		let max_hits = |decoy : bool| -> usize {
+			self.kmer_hits.iter()
+				.filter(|&&(segment_index, _)| (segment_index >= n_targets) == decoy)
+				.map(|&(_, hits)| hits)
+				.max()
+				.unwrap_or(0)
+		};
+		let max_target_hits : usize = max_hits(false);
+		if max_hits(true) > max_target_hits {
+			return None;
+		}
+
+		Some(self.kmer_hits.iter()
+			.filter(|&&(segment_index, hits)| segment_index < n_targets && hits == max_target_hits)
+			.map(|&(segment_index, _)| segment_index)
+			.collect())
+	}
+}
+
+//What became of the reads of a partition
+#[derive(Clone, Debug, Default)]
+pub struct MappingStats {
+	pub n_reads : usize,
+	pub n_assigned : usize,
+	pub n_no_hits : usize,
+	pub n_decoy : usize,
+}
+
+impl MappingStats {
+	pub fn add(&mut self, other : &MappingStats) {
+		self.n_reads += other.n_reads;
+		self.n_assigned += other.n_assigned;
+		self.n_no_hits += other.n_no_hits;
+		self.n_decoy += other.n_decoy;
+	}
+}
+
+//Anything that can pseudoalign a read against the reference segments
+pub trait Pseudoaligner {
+	fn pseudoalign(&self, read : &str) -> Option<ReadMapping>;
+}
+
+impl<'a> Pseudoaligner for DNAHashTable<'a> {
+	//Look up every k-mer of the read and count the distinct segments each one occurs in
+	fn pseudoalign(&self, read : &str) -> Option<ReadMapping> {
+		let mut hit_counts : HashMap<usize, usize> = HashMap::new();
+		if read.len() < self.k {
+			return None;
+		}
+
+		for i in 0..(read.len() - self.k + 1) {
+			if let Some((kmers, kmer_indexes)) = self.get_kmer(&read[i..(i + self.k)]) {
+				let mut kmer_segments : Vec<usize> = kmer_indexes.iter().map(|&index| kmers[index].segment_index).collect();
+				kmer_segments.dedup();
+				for segment_index in kmer_segments {
+					*hit_counts.entry(segment_index).or_insert(0) += 1;
+				}
+			}
+		}
+		ReadMapping::from_hit_counts(hit_counts)
+	}
+}
+
+//The reads assigned to each target, along with the number of reads sharing each set of best targets
+#[derive(Clone, Debug, Default)]
+pub struct SegmentCounts {
+	pub counts : HashMap<i32, i32>,
+	pub equivalence_classes : HashMap<Vec<usize>, usize>,
+	pub mapping_stats : MappingStats,
+	//What became of every read, in the order of the reads, when kept for per-read outputs
+	pub outcomes : Vec<ReadOutcome>,
+}
 
-	                let count = segment_index_counts.entry(count_instance.segment_index as i32).or_insert(0);
-	                *count += 1;
-	    		}
-	    	},
-	    	None => {
-	            debug!("No match");
-	        }
-    };
+impl SegmentCounts {
+	//Add the counts of the reads following these ones
+	pub fn add(&mut self, other : SegmentCounts) {
+		for (segment_index, count) in other.counts.iter() {
+			*self.counts.entry(*segment_index).or_insert(0) += *count;
+		}
+		for (targets, count) in other.equivalence_classes.iter() {
+			*self.equivalence_classes.entry(targets.clone()).or_insert(0) += *count;
+		}
+		self.mapping_stats.add(&other.mapping_stats);
+		self.outcomes.extend(other.outcomes);
+	}
 
+	//The equivalence classes in order of their targets, numbered from 0 as in every output that names them
+	pub fn numbered_classes(&self) -> Vec<(&Vec<usize>, usize)> {
+		let mut classes : Vec<(&Vec<usize>, usize)> = self.equivalence_classes.iter().map(|(targets, &count)| (targets, count)).collect();
+		classes.sort();
+		classes
+	}
+}
+
+//What became of a single read: no k-mer hits, best explained by a decoy, or assigned to the targets that
+//best explain it
+#[derive(Clone, Debug)]
+pub enum ReadOutcome {
+	NoHits,
+	Decoy(ReadMapping),
+	Assigned(ReadMapping, Vec<usize>),
+}
+
+impl ReadOutcome {
+	pub fn new<P : Pseudoaligner>(pseudoaligner : &P, read : &str, n_targets : usize) -> ReadOutcome {
+		match pseudoaligner.pseudoalign(read) {
+			Some(read_mapping) => match read_mapping.best_targets(n_targets) {
+				Some(targets) => ReadOutcome::Assigned(read_mapping, targets),
+				None => ReadOutcome::Decoy(read_mapping),
+			},
+			None => ReadOutcome::NoHits,
+		}
+	}
+}
+
+//Count the reads assigned to each target, crediting every target that best explains a read once, and keep
+//the outcome of every read if asked to
+pub fn get_segments<P : Pseudoaligner>(pseudoaligner : &P, reads : &[String], n_targets : usize, keep_outcomes : bool) -> SegmentCounts {
+	let mut segment_index_counts : HashMap<i32, i32> = HashMap::new();
+	let mut equivalence_classes : HashMap<Vec<usize>, usize> = HashMap::new();
+	let mut mapping_stats = MappingStats::default();
+	let mut outcomes : Vec<ReadOutcome> = Vec::new();
+
+	for r in reads {
+		mapping_stats.n_reads += 1;
+		let outcome : ReadOutcome = ReadOutcome::new(pseudoaligner, r, n_targets);
+		match outcome {
+			ReadOutcome::Assigned(ref read_mapping, ref targets) => {
+				debug!("{:?}", read_mapping);
+				mapping_stats.n_assigned += 1;
+				for segment_index in targets.iter() {
+					let count = segment_index_counts.entry(*segment_index as i32).or_insert(0);
+					*count += 1;
+				}
+				*equivalence_classes.entry(targets.clone()).or_insert(0) += 1;
+			},
+			ReadOutcome::Decoy(ref read_mapping) => {
+				debug!("{:?}", read_mapping);
+				debug!("Best explained by a decoy");
+				mapping_stats.n_decoy += 1;
+			},
+			ReadOutcome::NoHits => {
+				debug!("No match");
+				mapping_stats.n_no_hits += 1;
+			}
+		};
+		if keep_outcomes {
+			outcomes.push(outcome);
+		}
 	}
 
-	return segment_index_counts;
+	SegmentCounts {
+		counts : segment_index_counts,
+		equivalence_classes,
+		mapping_stats,
+		outcomes,
+	}
 }
 
 // pub fn get_segments2(kmer_hash_table : &DNAHashTable, reads : &Vec<String>) -> PartialSegmentMap {
@@ -201,4 +374,70 @@ pub struct Kmer {
 
 // 		}
 // 	}
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn segments() -> Vec<String> {
+		vec!["CCGTAATGCCTTTCCCTAACAGAGTTTT".to_string(), "TCCCTAACAGAGTTTTTCGAACTCGTGT".to_string()]
+	}
+
+	#[test]
+	fn pseudoalign_counts_the_kmers_of_the_read_in_each_segment() {
+		let segments = segments();
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let read_mapping = kmer_hash_table.pseudoalign("GCCTTTCCCTAACA").expect("read has hits");
+		//Eight k-mers, the last three of which also occur in the second segment
+		assert_eq!(read_mapping.kmer_hits, vec![(0, 8), (1, 3)]);
+		assert_eq!(read_mapping.hits(1), 3);
+		assert_eq!(read_mapping.best_targets(2), Some(vec![0]));
+	}
+
+	#[test]
+	fn get_segments_credits_each_best_target_once_per_read() {
+		let segments = segments();
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let reads : Vec<String> = vec![
+			"GCCTTTCCCTAACA".to_string(),
+			"TCCCTAACAGAGTTTT".to_string(),
+			"TCCCTAACAGAGTTTT".to_string(),
+			"AAAAAAAAAAAAAA".to_string(),
+			"TCCC".to_string(),
+		];
+		let segment_counts = get_segments(&kmer_hash_table, &reads, 2, false);
+		assert_eq!(segment_counts.counts.get(&0), Some(&3));
+		assert_eq!(segment_counts.counts.get(&1), Some(&2));
+		assert_eq!(segment_counts.equivalence_classes.get(&vec![0, 1]), Some(&2));
+		assert_eq!(segment_counts.mapping_stats.n_reads, 5);
+		assert_eq!(segment_counts.mapping_stats.n_assigned, 3);
+		assert_eq!(segment_counts.mapping_stats.n_no_hits, 2);
+	}
+
+	#[test]
+	fn decoys_that_explain_a_read_better_leave_it_unassigned() {
+		let read_mapping = ReadMapping { kmer_hits : vec![(0, 5), (1, 5), (2, 6)] };
+		assert_eq!(read_mapping.best_targets(2), None);
+		assert_eq!(read_mapping.best_targets(3), Some(vec![2]));
+
+		let read_mapping = ReadMapping { kmer_hits : vec![(0, 5), (1, 5), (2, 5)] };
+		assert_eq!(read_mapping.best_targets(2), Some(vec![0, 1]));
+	}
+
+	#[test]
+	fn get_segments_counts_reads_lost_to_decoys() {
+		let segments = segments();
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let reads : Vec<String> = vec!["TTTCGAACTCGTGT".to_string(), "CCGTAATGCC".to_string()];
+		let segment_counts = get_segments(&kmer_hash_table, &reads, 1, true);
+		assert_eq!(segment_counts.counts.get(&1), None);
+		assert_eq!(segment_counts.counts.get(&0), Some(&1));
+		assert_eq!(segment_counts.mapping_stats.n_decoy, 1);
+		assert_eq!(segment_counts.mapping_stats.n_assigned, 1);
+		//The outcomes are kept in the order of the reads
+		match segment_counts.outcomes[..] {
+			[ReadOutcome::Decoy(_), ReadOutcome::Assigned(_, ref targets)] => assert_eq!(targets, &vec![0]),
+			ref outcomes => panic!("unexpected outcomes {:?}", outcomes),
+		}
+	}
+}
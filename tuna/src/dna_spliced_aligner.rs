@@ -0,0 +1,151 @@
+use dna_aligner::Alignment;
+use dna_aligner::ReadAligner;
+use dna_aligner::SeedExtendAligner;
+use dna_aligner::sort_alignments;
+use gene_annotation::GeneAnnotation;
+
+use std::collections::HashMap;
+
+use log::*;
+
+//The fewest letters a spliced alignment must place on either side of its junction
+const MIN_ANCHOR : usize = 3;
+
+//The sequence on either side of every annotated junction, joined together so that reads crossing the
+//junction can be aligned to it as if the intron were not there
+pub struct JunctionSet {
+	pub sequences : Vec<String>,
+	//For every sequence, its chromosome and where its left flank starts and ends
+	contexts : Vec<JunctionContext>,
+}
+
+struct JunctionContext {
+	segment_index : usize,
+	left_start : usize,
+	left_length : usize,
+	intron_length : usize,
+}
+
+impl JunctionSet {
+	//Flanks extend up to flank letters into the exons on either side of each junction
+	pub fn new(annotation : &GeneAnnotation, seg_ids : &[String], seg_strings : &[String], flank : usize, k : usize) -> JunctionSet {
+		let segment_indexes : HashMap<&str, usize> = seg_ids.iter().enumerate().map(|(i, id)| (id.as_str(), i)).collect();
+		let mut sequences : Vec<String> = Vec::new();
+		let mut contexts : Vec<JunctionContext> = Vec::new();
+		let mut seen : Vec<bool> = vec![false; annotation.junctions.len()];
+		let mut missing : usize = 0;
+
+		for transcript in &annotation.transcripts {
+			let segment_index : usize = match segment_indexes.get(transcript.chrom.as_str()) {
+				Some(&segment_index) => segment_index,
+				None => {
+					debug!("Transcript {} is on {}, which is not in the sequence inputs", transcript.id, transcript.chrom);
+					missing += 1;
+					continue;
+				}
+			};
+			let chrom : &str = &seg_strings[segment_index];
+
+			for pair in transcript.exons.windows(2) {
+				let junction_index : usize = match annotation.get_junction(&transcript.chrom, pair[0].1, pair[1].0) {
+					Some(junction_index) if !seen[junction_index] => junction_index,
+					_ => continue,
+				};
+				seen[junction_index] = true;
+				if pair[1].1 > chrom.len() {
+					continue;
+				}
+
+				let left_start : usize = pair[0].0.max(pair[0].1.saturating_sub(flank));
+				let right_end : usize = pair[1].1.min(pair[1].0 + flank);
+				let sequence : String = format!("{}{}", &chrom[left_start..pair[0].1], &chrom[pair[1].0..right_end]);
+				if sequence.len() < k {
+					continue;
+				}
+
+				sequences.push(sequence);
+				contexts.push(JunctionContext {
+					segment_index,
+					left_start,
+					left_length : pair[0].1 - left_start,
+					intron_length : pair[1].0 - pair[0].1,
+				});
+			}
+		}
+
+		if missing > 0 {
+			warn!("{} transcripts are on sequences not in the sequence inputs", missing);
+		}
+		info!("Built flanking sequences for {} junctions", sequences.len());
+		JunctionSet { sequences, contexts }
+	}
+
+	//Translate an alignment against a junction sequence into one against its chromosome, with the intron
+	//skipped in the CIGAR. Alignments not anchored on both sides of the junction are dropped.
+	fn to_genome(&self, alignment : &Alignment) -> Option<Alignment> {
+		let context : &JunctionContext = &self.contexts[alignment.segment_index];
+		let reference_length : usize = alignment.blocks().iter().map(|&(start, end)| end - start).sum();
+		if alignment.position + MIN_ANCHOR > context.left_length
+			|| alignment.position + reference_length < context.left_length + MIN_ANCHOR {
+			return None;
+		}
+
+		let mut cigar : Vec<(usize, char)> = Vec::new();
+		let mut reference_offset : usize = alignment.position;
+		let mut spliced : bool = false;
+		for &(length, op) in &alignment.cigar {
+			if op == 'I' {
+				cigar.push((length, op));
+				continue;
+			}
+			if !spliced && reference_offset + length > context.left_length {
+				let before : usize = context.left_length - reference_offset;
+				if before > 0 {
+					cigar.push((before, op));
+				}
+				cigar.push((context.intron_length, 'N'));
+				cigar.push((length - before, op));
+				spliced = true;
+			} else {
+				cigar.push((length, op));
+			}
+			reference_offset += length;
+		}
+
+		Some(Alignment {
+			segment_index : context.segment_index,
+			position : context.left_start + alignment.position,
+			reverse : alignment.reverse,
+			cigar,
+			edit_distance : alignment.edit_distance,
+			score : alignment.score,
+		})
+	}
+}
+
+//Aligns reads against the genome and the junction sequences, reporting alignments across junctions
+//in genome coordinates
+pub struct SplicedAligner<'a> {
+	genome_aligner : SeedExtendAligner<'a>,
+	junction_aligner : SeedExtendAligner<'a>,
+	junction_set : &'a JunctionSet,
+}
+
+impl<'a> SplicedAligner<'a> {
+	pub fn new(genome_aligner : SeedExtendAligner<'a>, junction_aligner : SeedExtendAligner<'a>, junction_set : &'a JunctionSet) -> SplicedAligner<'a> {
+		SplicedAligner {
+			genome_aligner,
+			junction_aligner,
+			junction_set,
+		}
+	}
+}
+
+impl<'a> ReadAligner for SplicedAligner<'a> {
+	fn align(&self, read : &str) -> Vec<Alignment> {
+		let mut alignments : Vec<Alignment> = self.genome_aligner.align(read);
+		alignments.extend(self.junction_aligner.align(read).iter().filter_map(|alignment| self.junction_set.to_genome(alignment)));
+		sort_alignments(&mut alignments);
+		alignments
+	}
+}
@@ -0,0 +1,245 @@
+use dna_hash_table::DNAHashTable;
+use dna_hash_table::Kmer;
+use dna_hash_table::Pseudoaligner;
+use dna_hash_table::ReadMapping;
+
+use std::collections::HashMap;
+
+use log::*;
+
+const NUCLEOTIDES : [char; 4] = ['A', 'C', 'G', 'T'];
+
+//A compacted de Bruijn graph over the k-mers of the reference segments. Every distinct k-mer is a node,
+//and maximal non-branching paths of nodes sharing the same colors (the segments they occur in) are
+//merged into unitigs.
+pub struct DNAUnitigGraph<'a> {
+	pub unitigs : Vec<Unitig>,
+	kmer_hash_table : &'a DNAHashTable<'a>,
+	//The node of every k-mer entry in the hash table, indexed by creation time
+	node_of_kmer : Vec<usize>,
+	//The unitig each node was merged into and its offset along the unitig sequence
+	node_positions : Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Unitig {
+	pub sequence : String,
+	pub colors : Vec<usize>,
+}
+
+//A distinct k-mer of the reference, represented by its first occurrence
+struct Node {
+	kmer : Kmer,
+	colors : Vec<usize>,
+}
+
+impl<'a> DNAUnitigGraph<'a> {
+	pub fn new(kmer_hash_table : &'a DNAHashTable<'a>) -> DNAUnitigGraph<'a> {
+		let (nodes, node_of_kmer) = DNAUnitigGraph::get_nodes(kmer_hash_table);
+		let mut graph = DNAUnitigGraph {
+			unitigs : Vec::new(),
+			kmer_hash_table,
+			node_of_kmer,
+			node_positions : vec![(0, 0); nodes.len()],
+		};
+
+		let successors : Vec<Vec<usize>> = nodes.iter().map(|node| graph.get_neighbours(node, true)).collect();
+		let predecessors : Vec<Vec<usize>> = nodes.iter().map(|node| graph.get_neighbours(node, false)).collect();
+
+		//A node continues into its successor when neither branches and both occur in the same segments
+		let continues_to = |node_index : usize| -> Option<usize> {
+			if successors[node_index].len() != 1 {
+				return None;
+			}
+			let next : usize = successors[node_index][0];
+			if next != node_index && predecessors[next].len() == 1 && nodes[node_index].colors == nodes[next].colors {
+				Some(next)
+			} else {
+				None
+			}
+		};
+
+		let mut visited : Vec<bool> = vec![false; nodes.len()];
+		let is_start = |node_index : usize| -> bool {
+			predecessors[node_index].len() != 1 || continues_to(predecessors[node_index][0]) != Some(node_index)
+		};
+		let starts : Vec<usize> = (0..nodes.len()).filter(|&node_index| is_start(node_index)).collect();
+
+		//Nodes that are never a start belong to isolated cycles, which are broken at an arbitrary node
+		for start in starts.into_iter().chain(0..nodes.len()) {
+			if visited[start] {
+				continue;
+			}
+			let unitig_index : usize = graph.unitigs.len();
+			let mut sequence : String = kmer_hash_table.kmer_string(&nodes[start].kmer).to_string();
+			let mut current : usize = start;
+			visited[current] = true;
+			graph.node_positions[current] = (unitig_index, 0);
+
+			while let Some(next) = continues_to(current) {
+				if visited[next] {
+					break;
+				}
+				let next_kmer : &str = kmer_hash_table.kmer_string(&nodes[next].kmer);
+				graph.node_positions[next] = (unitig_index, sequence.len() - kmer_hash_table.k() + 1);
+				sequence.push_str(&next_kmer[(next_kmer.len() - 1)..]);
+				visited[next] = true;
+				current = next;
+			}
+
+			graph.unitigs.push(Unitig {
+				sequence,
+				colors : nodes[start].colors.clone(),
+			});
+		}
+
+		info!("Compacted {} distinct k-mers into {} unitigs", nodes.len(), graph.unitigs.len());
+		graph
+	}
+
+	//Group the entries of every hash table bucket by their k-mer string, giving one node per distinct k-mer
+	fn get_nodes(kmer_hash_table : &DNAHashTable) -> (Vec<Node>, Vec<usize>) {
+		let n_kmers : usize = kmer_hash_table.hash_table.iter().map(|bucket| bucket.len()).sum();
+		let mut node_of_kmer : Vec<usize> = vec![0; n_kmers];
+		let mut nodes : Vec<Node> = Vec::new();
+
+		for bucket in &kmer_hash_table.hash_table {
+			let mut bucket_nodes : Vec<usize> = Vec::new();
+			for kmer in bucket {
+				let kmer_string : &str = kmer_hash_table.kmer_string(kmer);
+				let existing : Option<usize> = bucket_nodes.iter()
+					.cloned()
+					.find(|&node_index| kmer_hash_table.kmer_string(&nodes[node_index].kmer) == kmer_string);
+
+				let node_index : usize = match existing {
+					Some(node_index) => node_index,
+					None => {
+						nodes.push(Node {
+							kmer : kmer.clone(),
+							colors : Vec::new(),
+						});
+						bucket_nodes.push(nodes.len() - 1);
+						nodes.len() - 1
+					}
+				};
+
+				if nodes[node_index].colors.last() != Some(&kmer.segment_index) {
+					nodes[node_index].colors.push(kmer.segment_index);
+				}
+				node_of_kmer[kmer.creation_time] = node_index;
+			}
+		}
+
+		(nodes, node_of_kmer)
+	}
+
+	//The nodes overlapping this one by k - 1 letters, either after it or before it
+	fn get_neighbours(&self, node : &Node, forward : bool) -> Vec<usize> {
+		let kmer_string : &str = self.kmer_hash_table.kmer_string(&node.kmer);
+		let mut neighbours : Vec<usize> = Vec::new();
+		for nucleotide in NUCLEOTIDES.iter() {
+			let neighbour : String = if forward {
+				format!("{}{}", &kmer_string[1..], nucleotide)
+			} else {
+				format!("{}{}", nucleotide, &kmer_string[..(kmer_string.len() - 1)])
+			};
+			if let Some(node_index) = self.get_node(&neighbour) {
+				neighbours.push(node_index);
+			}
+		}
+		neighbours
+	}
+
+	fn get_node(&self, kmer_string : &str) -> Option<usize> {
+		self.kmer_hash_table.get_kmer(kmer_string)
+			.map(|(kmers, kmer_indexes)| self.node_of_kmer[kmers[kmer_indexes[0]].creation_time])
+	}
+}
+
+impl<'a> Pseudoaligner for DNAUnitigGraph<'a> {
+	//Walk the k-mers of the read, and whenever one is found follow the read along its unitig for as long as
+	//the letters agree. All k-mers on a unitig share its colors, so they need not be looked up individually.
+	fn pseudoalign(&self, read : &str) -> Option<ReadMapping> {
+		let k : usize = self.kmer_hash_table.k();
+		let read_bytes : &[u8] = read.as_bytes();
+		let mut hit_counts : HashMap<usize, usize> = HashMap::new();
+
+		let mut i : usize = 0;
+		while i + k <= read.len() {
+			match self.get_node(&read[i..(i + k)]) {
+				Some(node_index) => {
+					let (unitig_index, offset) = self.node_positions[node_index];
+					let unitig : &Unitig = &self.unitigs[unitig_index];
+					let skipped : usize = read_bytes[(i + k)..].iter()
+						.zip(unitig.sequence.as_bytes()[(offset + k)..].iter())
+						.take_while(|&(read_letter, unitig_letter)| read_letter == unitig_letter)
+						.count();
+
+					for &segment_index in &unitig.colors {
+						*hit_counts.entry(segment_index).or_insert(0) += skipped + 1;
+					}
+					i += skipped + 1;
+				},
+				None => {
+					i += 1;
+				}
+			}
+		}
+		ReadMapping::from_hit_counts(hit_counts)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const X : &str = "CCGTAATGCCTT";
+	const Y : &str = "TCCCTAACAGAGTTTT";
+	const Z : &str = "TCGAACTCGTGT";
+
+	#[test]
+	fn compacts_a_segment_without_branches_into_one_unitig() {
+		let segments : Vec<String> = vec![format!("{}{}{}", X, Y, Z)];
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let graph = DNAUnitigGraph::new(&kmer_hash_table);
+		assert_eq!(graph.unitigs.len(), 1);
+		assert_eq!(graph.unitigs[0].sequence, segments[0]);
+		assert_eq!(graph.unitigs[0].colors, vec![0]);
+	}
+
+	#[test]
+	fn splits_unitigs_where_the_colors_change() {
+		let segments : Vec<String> = vec![format!("{}{}", X, Y), format!("{}{}", Y, Z)];
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let graph = DNAUnitigGraph::new(&kmer_hash_table);
+		let mut unitigs : Vec<(String, Vec<usize>)> = graph.unitigs.iter()
+			.map(|unitig| (unitig.sequence.clone(), unitig.colors.clone()))
+			.collect();
+		unitigs.sort();
+		assert_eq!(unitigs, vec![
+			(format!("{}{}", &Y[10..], Z), vec![1]),
+			(format!("{}{}", X, &Y[..6]), vec![0]),
+			(Y.to_string(), vec![0, 1]),
+		]);
+	}
+
+	#[test]
+	fn walking_unitigs_finds_the_same_hits_as_looking_up_every_kmer() {
+		let segments : Vec<String> = vec![format!("{}{}", X, Y), format!("{}{}", Y, Z)];
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let graph = DNAUnitigGraph::new(&kmer_hash_table);
+		let reads : Vec<String> = vec![
+			format!("{}{}", &X[4..], &Y[..8]),
+			Y.to_string(),
+			format!("{}{}{}", &Y[4..], Z, "ACGTTT"),
+			//a mismatch in the middle of a unitig
+			format!("{}A{}", &Y[..7], &Y[8..]),
+		];
+		for read in &reads {
+			let walked = graph.pseudoalign(read).expect("no hits walking unitigs").kmer_hits;
+			let looked_up = kmer_hash_table.pseudoalign(read).expect("no hits looking up k-mers").kmer_hits;
+			assert_eq!(walked, looked_up, "read {}", read);
+		}
+		assert!(graph.pseudoalign("AAAAAAAAAA").is_none());
+	}
+}
@@ -0,0 +1,149 @@
+use dna_hash_table::DNAHashTable;
+
+use std::collections::HashMap;
+
+use log::*;
+
+//A segment left out of the index because its sequence is identical to, or contained in, a retained one
+#[derive(Clone, Debug)]
+pub struct DuplicateSegment {
+	pub retained_index : usize,
+	pub removed_index : usize,
+	pub contained : bool,
+}
+
+//The segments to keep in the index, and what became of the others
+pub struct SegmentCollapse {
+	pub retained : Vec<usize>,
+	pub duplicates : Vec<DuplicateSegment>,
+}
+
+//Find segments with identical sequences, and optionally segments whose sequence occurs inside a longer
+//one. The first of a set of identical segments is retained.
+pub fn find_duplicates(seg_strings : &[String], include_contained : bool, k : usize) -> SegmentCollapse {
+	let mut first_indexes : HashMap<&str, usize> = HashMap::new();
+	let mut duplicates : Vec<DuplicateSegment> = Vec::new();
+	let mut unique : Vec<usize> = Vec::new();
+
+	for (i, seg_string) in seg_strings.iter().enumerate() {
+		match first_indexes.get(seg_string.as_str()) {
+			Some(&retained_index) => duplicates.push(DuplicateSegment {
+				retained_index,
+				removed_index : i,
+				contained : false,
+			}),
+			None => {
+				first_indexes.insert(seg_string, i);
+				unique.push(i);
+			}
+		}
+	}
+
+	let mut retained : Vec<usize> = if include_contained {
+		find_contained(seg_strings, &unique, k, &mut duplicates)
+	} else {
+		unique
+	};
+	retained.sort();
+
+	//Identical segments whose first copy turned out to be contained belong to its container instead
+	let containers : HashMap<usize, usize> = duplicates.iter()
+		.filter(|duplicate| duplicate.contained)
+		.map(|duplicate| (duplicate.removed_index, duplicate.retained_index))
+		.collect();
+	for duplicate in duplicates.iter_mut() {
+		if let Some(&container_index) = containers.get(&duplicate.retained_index) {
+			duplicate.retained_index = container_index;
+		}
+	}
+
+	if !duplicates.is_empty() {
+		info!("Collapsed {} duplicate segments into {} retained segments", duplicates.len(), retained.len());
+	}
+	SegmentCollapse { retained, duplicates }
+}
+
+//Look up the first k-mer of every unique segment, longest first, and check whether the segment occurs
+//in full at any of its hits in an already retained segment
+fn find_contained(seg_strings : &[String], unique : &[usize], k : usize, duplicates : &mut Vec<DuplicateSegment>) -> Vec<usize> {
+	let unique_strings : Vec<String> = unique.iter()
+		.map(|&i| seg_strings[i].clone())
+		.filter(|seg_string| seg_string.len() >= k)
+		.collect();
+	let unique_indexes : Vec<usize> = unique.iter().cloned().filter(|&i| seg_strings[i].len() >= k).collect();
+	let kmer_hash_table = DNAHashTable::new(&unique_strings, k);
+
+	let mut by_length : Vec<usize> = (0..unique_strings.len()).collect();
+	by_length.sort_by(|&a, &b| unique_strings[b].len().cmp(&unique_strings[a].len()).then(a.cmp(&b)));
+
+	let mut is_retained : Vec<bool> = vec![false; unique_strings.len()];
+	for table_index in by_length {
+		let seg_string : &str = &unique_strings[table_index];
+		let container : Option<usize> = kmer_hash_table.get_kmer(&seg_string[..k]).and_then(|(kmers, kmer_indexes)| {
+			kmer_indexes.into_iter()
+				.map(|kmer_index| &kmers[kmer_index])
+				.find(|kmer| {
+					let candidate : &str = &unique_strings[kmer.segment_index];
+					kmer.segment_index != table_index && is_retained[kmer.segment_index]
+						&& candidate.len() >= kmer.position + seg_string.len()
+						&& &candidate[kmer.position..(kmer.position + seg_string.len())] == seg_string
+				})
+				.map(|kmer| kmer.segment_index)
+		});
+
+		match container {
+			Some(container_index) => duplicates.push(DuplicateSegment {
+				retained_index : unique_indexes[container_index],
+				removed_index : unique_indexes[table_index],
+				contained : true,
+			}),
+			None => is_retained[table_index] = true,
+		}
+	}
+
+	//Segments too short to look up are always retained
+	unique.iter().cloned()
+		.filter(|&i| seg_strings[i].len() < k)
+		.chain(unique_indexes.iter().cloned().enumerate().filter(|&(table_index, _)| is_retained[table_index]).map(|(_, i)| i))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn segments(seg_strings : &[&str]) -> Vec<String> {
+		seg_strings.iter().map(|seg_string| seg_string.to_string()).collect()
+	}
+
+	fn pairs(segment_collapse : &SegmentCollapse) -> Vec<(usize, usize, bool)> {
+		let mut pairs : Vec<(usize, usize, bool)> = segment_collapse.duplicates.iter()
+			.map(|duplicate| (duplicate.removed_index, duplicate.retained_index, duplicate.contained))
+			.collect();
+		pairs.sort();
+		pairs
+	}
+
+	#[test]
+	fn keeps_first_of_identical_segments() {
+		let seg_strings = segments(&["ACGTACGTAA", "CCCCGGGGTT", "ACGTACGTAA", "ACGTACGTAA"]);
+		let segment_collapse = find_duplicates(&seg_strings, false, 4);
+		assert_eq!(segment_collapse.retained, vec![0, 1]);
+		assert_eq!(pairs(&segment_collapse), vec![(2, 0, false), (3, 0, false)]);
+	}
+
+	#[test]
+	fn collapses_contained_segments_into_longest() {
+		let seg_strings = segments(&["CGTACG", "GGACGTACGTTT", "CGTACG", "CCCCGGGG", "AC"]);
+		let segment_collapse = find_duplicates(&seg_strings, true, 4);
+		assert_eq!(segment_collapse.retained, vec![1, 3, 4]);
+		//The identical copy follows its first copy into the container
+		assert_eq!(pairs(&segment_collapse), vec![(0, 1, true), (2, 1, false)]);
+	}
+
+	#[test]
+	fn contained_collapse_is_opt_in() {
+		let seg_strings = segments(&["TACGTA", "GGACGTACGTTT"]);
+		assert_eq!(find_duplicates(&seg_strings, false, 4).retained, vec![0, 1]);
+	}
+}
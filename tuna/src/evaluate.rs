@@ -0,0 +1,170 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+//How closely estimated abundances follow the true ones, over every target in either
+#[derive(Clone, Debug)]
+pub struct AbundanceComparison {
+	pub n_targets : usize,
+	pub pearson : f64,
+	pub spearman : f64,
+	//Mean over targets of |estimate - truth| / (estimate + truth), taken as 0 where both are 0
+	pub mard : f64,
+}
+
+/**
+    compare_abundances:
+    Compare estimated abundances to the true ones, counting targets missing from either
+    side as 0
+*/
+pub fn compare_abundances(estimated : &HashMap<String, f64>, truth : &HashMap<String, f64>) -> AbundanceComparison {
+	let ids : BTreeSet<&String> = estimated.keys().chain(truth.keys()).collect();
+	let xs : Vec<f64> = ids.iter().map(|&id| estimated.get(id).cloned().unwrap_or(0.0)).collect();
+	let ys : Vec<f64> = ids.iter().map(|&id| truth.get(id).cloned().unwrap_or(0.0)).collect();
+
+	let relative_differences : Vec<f64> = xs.iter().zip(ys.iter())
+		.map(|(&x, &y)| if x + y > 0.0 { (x - y).abs() / (x + y) } else { 0.0 })
+		.collect();
+	AbundanceComparison {
+		n_targets : ids.len(),
+		pearson : pearson(&xs, &ys),
+		spearman : pearson(&ranks(&xs), &ranks(&ys)),
+		mard : mean(&relative_differences),
+	}
+}
+
+fn mean(values : &[f64]) -> f64 {
+	if values.is_empty() { 0.0 } else { values.iter().sum::<f64>() / values.len() as f64 }
+}
+
+//Pearson correlation, or 0 where either side has no variance
+fn pearson(xs : &[f64], ys : &[f64]) -> f64 {
+	let (x_mean, y_mean) = (mean(xs), mean(ys));
+	let mut covariance : f64 = 0.0;
+	let mut x_variance : f64 = 0.0;
+	let mut y_variance : f64 = 0.0;
+	for (&x, &y) in xs.iter().zip(ys.iter()) {
+		covariance += (x - x_mean) * (y - y_mean);
+		x_variance += (x - x_mean) * (x - x_mean);
+		y_variance += (y - y_mean) * (y - y_mean);
+	}
+	if x_variance > 0.0 && y_variance > 0.0 { covariance / (x_variance * y_variance).sqrt() } else { 0.0 }
+}
+
+//Ranks of the values from 1, with tied values sharing the mean of their ranks
+fn ranks(values : &[f64]) -> Vec<f64> {
+	let mut order : Vec<usize> = (0..values.len()).collect();
+	order.sort_by(|&a, &b| values[a].partial_cmp(&values[b]).expect("abundance is not a number"));
+	let mut ranks : Vec<f64> = vec![0.0; values.len()];
+	let mut start : usize = 0;
+	while start < order.len() {
+		let mut end : usize = start + 1;
+		while end < order.len() && values[order[end]] == values[order[start]] {
+			end += 1;
+		}
+		let rank : f64 = (start + end + 1) as f64 / 2.0;
+		for &i in &order[start..end] {
+			ranks[i] = rank;
+		}
+		start = end;
+	}
+	ranks
+}
+
+//How well reads were assigned to the targets they truly came from. A read assigned to several targets,
+//one of them right, counts as a fraction of a correct read.
+#[derive(Clone, Debug, Default)]
+pub struct AssignmentAccuracy {
+	pub n_reads : usize,
+	pub n_assigned : usize,
+	pub n_correct : f64,
+}
+
+impl AssignmentAccuracy {
+	//Fraction of the assigned reads that went to their true target
+	pub fn precision(&self) -> f64 {
+		if self.n_assigned > 0 { self.n_correct / self.n_assigned as f64 } else { 0.0 }
+	}
+
+	//Fraction of all reads of the truth that went to their true target
+	pub fn recall(&self) -> f64 {
+		if self.n_reads > 0 { self.n_correct / self.n_reads as f64 } else { 0.0 }
+	}
+}
+
+/**
+    assignment_accuracy:
+    Compare the targets each read was assigned to with the target it truly came from.
+    Only reads in the truth are counted.
+*/
+pub fn assignment_accuracy(assignments : &HashMap<String, Vec<String>>, truth : &HashMap<String, String>) -> AssignmentAccuracy {
+	let mut accuracy = AssignmentAccuracy { n_reads : truth.len(), ..AssignmentAccuracy::default() };
+	for (read_name, true_target) in truth {
+		if let Some(targets) = assignments.get(read_name).filter(|targets| !targets.is_empty()) {
+			accuracy.n_assigned += 1;
+			if targets.contains(true_target) {
+				accuracy.n_correct += 1.0 / targets.len() as f64;
+			}
+		}
+	}
+	accuracy
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn abundances(values : &[(&str, f64)]) -> HashMap<String, f64> {
+		values.iter().map(|&(id, value)| (id.to_string(), value)).collect()
+	}
+
+	#[test]
+	fn tied_values_share_the_mean_of_their_ranks() {
+		assert_eq!(ranks(&[3.0, 1.0, 2.0, 1.0]), vec![4.0, 1.5, 3.0, 1.5]);
+		assert_eq!(ranks(&[5.0, 5.0, 5.0]), vec![2.0, 2.0, 2.0]);
+		assert!(ranks(&[]).is_empty());
+	}
+
+	#[test]
+	fn spearman_correlates_tied_ranks() {
+		let estimated = abundances(&[("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)]);
+		let truth = abundances(&[("a", 10.0), ("b", 20.0), ("c", 30.0), ("d", 40.0)]);
+		let comparison = compare_abundances(&estimated, &truth);
+		assert!((comparison.spearman - 4.5 / 22.5f64.sqrt()).abs() < 1e-12);
+
+		//A monotonic but non-linear relation is a perfect rank correlation even with ties on both sides
+		let estimated = abundances(&[("a", 1.0), ("b", 1.0), ("c", 4.0), ("d", 9.0)]);
+		let truth = abundances(&[("a", 2.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+		let comparison = compare_abundances(&estimated, &truth);
+		assert!((comparison.spearman - 1.0).abs() < 1e-12);
+		assert!(comparison.pearson < 1.0);
+	}
+
+	#[test]
+	fn counts_missing_targets_as_zero() {
+		let estimated = abundances(&[("a", 3.0), ("b", 1.0)]);
+		let truth = abundances(&[("a", 1.0), ("c", 2.0)]);
+		let comparison = compare_abundances(&estimated, &truth);
+		assert_eq!(comparison.n_targets, 3);
+		//|3 - 1| / 4, then b and c each missing from one side
+		assert!((comparison.mard - (0.5 + 1.0 + 1.0) / 3.0).abs() < 1e-12);
+		//Without variance there is no correlation
+		let flat = compare_abundances(&abundances(&[("a", 1.0), ("b", 1.0)]), &abundances(&[("a", 1.0), ("b", 2.0)]));
+		assert_eq!((flat.pearson, flat.spearman), (0.0, 0.0));
+	}
+
+	#[test]
+	fn credits_multi_mapping_reads_in_part() {
+		let mut assignments : HashMap<String, Vec<String>> = HashMap::new();
+		assignments.insert("r1".to_string(), vec!["t1".to_string()]);
+		assignments.insert("r2".to_string(), vec!["t1".to_string(), "t2".to_string()]);
+		assignments.insert("r3".to_string(), vec!["t1".to_string()]);
+		assignments.insert("r5".to_string(), vec!["t1".to_string()]);
+		let truth : HashMap<String, String> = [("r1", "t1"), ("r2", "t2"), ("r3", "t2"), ("r4", "t1")].iter()
+			.map(|&(read, target)| (read.to_string(), target.to_string()))
+			.collect();
+		let accuracy = assignment_accuracy(&assignments, &truth);
+		assert_eq!((accuracy.n_reads, accuracy.n_assigned), (4, 3));
+		assert!((accuracy.precision() - 0.5).abs() < 1e-12);
+		assert!((accuracy.recall() - 0.375).abs() < 1e-12);
+	}
+}
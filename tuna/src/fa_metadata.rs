@@ -0,0 +1,121 @@
+//Fields of a GENCODE header, which are written as the pipe-delimited ID of the sequence
+const GENCODE_FIELDS : [&str; 8] = ["transcript_id", "gene_id", "havana_gene_id", "havana_transcript_id",
+	"transcript_name", "gene_name", "length", "transcript_biotype"];
+
+//Ensembl header keys, and the names they are stored under so that they agree with GENCODE
+const ENSEMBL_KEYS : [(&str, &str); 4] = [("gene", "gene_id"), ("gene_symbol", "gene_name"),
+	("transcript", "transcript_id"), ("description", "gene_description")];
+
+//Metadata parsed from the header of a segment: the full description following the ID, and any
+//attributes recognised in the ID or description
+#[derive(Clone, Debug, Default)]
+pub struct SegMetadata {
+	pub description : String,
+	attributes : Vec<(String, String)>,
+}
+
+impl SegMetadata {
+	//Recognises GENCODE pipe-delimited IDs, Ensembl key:value descriptions and generic key=value pairs.
+	//Without any of those, the ID itself is taken as the transcript ID.
+	pub fn parse(seg_id : &str, description : &str) -> SegMetadata {
+		let mut metadata = SegMetadata {
+			description : description.to_string(),
+			attributes : Vec::new(),
+		};
+
+		let id_fields : Vec<&str> = seg_id.split('|').collect();
+		if id_fields.len() > 1 {
+			for (&key, &value) in GENCODE_FIELDS.iter().zip(id_fields.iter()) {
+				metadata.insert(key, value);
+			}
+		}
+
+		let mut words = description.split_whitespace();
+		while let Some(word) = words.next() {
+			let separator : Option<usize> = word.find([':', '=']);
+			let (key, value) = match separator {
+				Some(index) if index > 0 => (&word[..index], &word[(index + 1)..]),
+				_ => continue,
+			};
+			let key : &str = ENSEMBL_KEYS.iter().find(|&&(ensembl_key, _)| ensembl_key == key).map_or(key, |&(_, name)| name);
+
+			if key == "gene_description" {
+				//Ensembl gene descriptions run to the end of the header
+				let rest : Vec<&str> = words.by_ref().collect();
+				metadata.insert(key, format!("{} {}", value, rest.join(" ")).trim());
+			} else {
+				metadata.insert(key, value);
+			}
+		}
+
+		if metadata.get("transcript_id").is_none() {
+			metadata.insert("transcript_id", seg_id);
+		}
+		metadata
+	}
+
+	//The value of an attribute, or the full description for "description"
+	pub fn get(&self, key : &str) -> Option<&str> {
+		if key == "description" {
+			return Some(self.description.as_str());
+		}
+		self.attributes.iter()
+			.find(|(attribute_key, _)| attribute_key == key)
+			.map(|(_, value)| value.as_str())
+	}
+
+	pub fn gene_id(&self) -> Option<&str> {
+		self.get("gene_id")
+	}
+
+	//The taxon the segment belongs to, from a taxid attribute of its header
+	pub fn taxon_id(&self) -> Option<&str> {
+		self.get("taxid")
+	}
+
+	//Set an attribute, replacing any value it was parsed with
+	pub fn set(&mut self, key : &str, value : &str) {
+		self.attributes.retain(|(attribute_key, _)| attribute_key != key);
+		self.insert(key, value);
+	}
+
+	fn insert(&mut self, key : &str, value : &str) {
+		if !value.is_empty() && self.get(key).is_none() {
+			self.attributes.push((key.to_string(), value.to_string()));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_gencode_ids() {
+		let metadata = SegMetadata::parse("ENST01.1|ENSG01.2|OTTHUMG01|OTTHUMT01|ABC-201|ABC|1200|protein_coding|", "");
+		assert_eq!(metadata.get("transcript_id"), Some("ENST01.1"));
+		assert_eq!(metadata.gene_id(), Some("ENSG01.2"));
+		assert_eq!(metadata.get("gene_name"), Some("ABC"));
+		assert_eq!(metadata.get("transcript_biotype"), Some("protein_coding"));
+	}
+
+	#[test]
+	fn parses_ensembl_descriptions() {
+		let metadata = SegMetadata::parse("ENST01.1", "cdna chromosome:GRCh38:1:1:100:1 gene:ENSG01.2 gene_symbol:ABC description:A B C [Source:HGNC]");
+		assert_eq!(metadata.get("transcript_id"), Some("ENST01.1"));
+		assert_eq!(metadata.gene_id(), Some("ENSG01.2"));
+		assert_eq!(metadata.get("gene_name"), Some("ABC"));
+		assert_eq!(metadata.get("gene_description"), Some("A B C [Source:HGNC]"));
+		assert_eq!(metadata.get("chromosome"), Some("GRCh38:1:1:100:1"));
+	}
+
+	#[test]
+	fn parses_key_value_pairs_and_keeps_first_value() {
+		let mut metadata = SegMetadata::parse("seq1", "taxid=562 taxid=9606 =ignored");
+		assert_eq!(metadata.taxon_id(), Some("562"));
+		assert_eq!(metadata.get("transcript_id"), Some("seq1"));
+		assert_eq!(metadata.get("description"), Some("taxid=562 taxid=9606 =ignored"));
+		metadata.set("taxid", "9606");
+		assert_eq!(metadata.taxon_id(), Some("9606"));
+	}
+}
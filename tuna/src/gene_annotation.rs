@@ -0,0 +1,317 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::collections::HashMap;
+
+use log::*;
+
+use dna_aligner::Alignment;
+
+//A transcript model from a GTF or GFF3 file, with 0-based half-open exon coordinates sorted by start
+#[derive(Clone, Debug)]
+pub struct Transcript {
+	pub id : String,
+	pub gene_id : String,
+	pub chrom : String,
+	pub strand : char,
+	pub exons : Vec<(usize, usize)>,
+}
+
+//An annotated intron, from the end of one exon (donor) to the start of the next (acceptor)
+#[derive(Clone, Debug)]
+pub struct Junction {
+	pub chrom : String,
+	pub donor : usize,
+	pub acceptor : usize,
+	pub strand : char,
+	pub gene_ids : Vec<String>,
+}
+
+pub struct GeneAnnotation {
+	pub transcripts : Vec<Transcript>,
+	pub gene_ids : Vec<String>,
+	pub junctions : Vec<Junction>,
+	junction_indexes : HashMap<(String, usize, usize), usize>,
+	//Exons of every chromosome as (start, end, gene index), sorted by start
+	chrom_exons : HashMap<String, Vec<(usize, usize, usize)>>,
+	max_exon_length : usize,
+}
+
+impl GeneAnnotation {
+	//Given the name of a GTF or GFF3 file, read the exons of every transcript in it. The format is decided
+	//per line by whether the attributes are written as key "value" or key=value.
+	pub fn read_annotation(filename : &str) -> GeneAnnotation {
+		let f = File::open(filename).expect("file not found");
+		let mut transcripts : Vec<Transcript> = Vec::new();
+		let mut transcript_indexes : HashMap<String, usize> = HashMap::new();
+		//GFF3 exons only name their transcript, whose gene is given by the transcript's own Parent
+		let mut gff_parents : HashMap<String, String> = HashMap::new();
+
+		for line in BufReader::new(f).lines() {
+			let line_val = line.expect("line not available");
+			if line_val.starts_with('#') || line_val.trim().is_empty() {
+				continue;
+			}
+			let fields : Vec<&str> = line_val.split('\t').collect();
+			if fields.len() < 9 {
+				warn!("Skipping annotation line with {} fields", fields.len());
+				continue;
+			}
+			let attributes : HashMap<String, String> = parse_attributes(fields[8]);
+
+			if let (Some(id), Some(parent)) = (attributes.get("ID"), attributes.get("Parent")) {
+				gff_parents.insert(id.clone(), parent.clone());
+			}
+			if fields[2] != "exon" {
+				continue;
+			}
+
+			let start : usize = fields[3].parse::<usize>().expect("exon start is not a number") - 1;
+			let end : usize = fields[4].parse::<usize>().expect("exon end is not a number");
+			let transcript_ids : Vec<String> = match attributes.get("transcript_id") {
+				Some(transcript_id) => vec![transcript_id.clone()],
+				None => attributes.get("Parent").map_or(Vec::new(), |parent| parent.split(',').map(|s| s.to_string()).collect()),
+			};
+
+			for transcript_id in transcript_ids {
+				let index : usize = *transcript_indexes.entry(transcript_id.clone()).or_insert_with(|| {
+					transcripts.push(Transcript {
+						id : transcript_id.clone(),
+						gene_id : attributes.get("gene_id").cloned().unwrap_or_default(),
+						chrom : fields[0].to_string(),
+						strand : fields[6].chars().next().unwrap_or('.'),
+						exons : Vec::new(),
+					});
+					transcripts.len() - 1
+				});
+				transcripts[index].exons.push((start, end));
+			}
+		}
+
+		for transcript in transcripts.iter_mut() {
+			if transcript.gene_id.is_empty() {
+				transcript.gene_id = gff_parents.get(&transcript.id).cloned().unwrap_or_else(|| transcript.id.clone());
+			}
+			transcript.exons.sort();
+		}
+		info!("Read {} annotated transcripts", transcripts.len());
+		GeneAnnotation::from_transcripts(transcripts)
+	}
+
+	fn from_transcripts(transcripts : Vec<Transcript>) -> GeneAnnotation {
+		let mut gene_ids : Vec<String> = Vec::new();
+		let mut gene_indexes : HashMap<String, usize> = HashMap::new();
+		let mut junctions : Vec<Junction> = Vec::new();
+		let mut junction_indexes : HashMap<(String, usize, usize), usize> = HashMap::new();
+		let mut chrom_exons : HashMap<String, Vec<(usize, usize, usize)>> = HashMap::new();
+		let mut max_exon_length : usize = 0;
+
+		for transcript in &transcripts {
+			let gene_index : usize = *gene_indexes.entry(transcript.gene_id.clone()).or_insert_with(|| {
+				gene_ids.push(transcript.gene_id.clone());
+				gene_ids.len() - 1
+			});
+			for &(start, end) in &transcript.exons {
+				chrom_exons.entry(transcript.chrom.clone()).or_default().push((start, end, gene_index));
+				max_exon_length = max_exon_length.max(end - start);
+			}
+
+			for pair in transcript.exons.windows(2) {
+				let key = (transcript.chrom.clone(), pair[0].1, pair[1].0);
+				if pair[0].1 >= pair[1].0 {
+					continue;
+				}
+				let index : usize = *junction_indexes.entry(key).or_insert_with(|| {
+					junctions.push(Junction {
+						chrom : transcript.chrom.clone(),
+						donor : pair[0].1,
+						acceptor : pair[1].0,
+						strand : transcript.strand,
+						gene_ids : Vec::new(),
+					});
+					junctions.len() - 1
+				});
+				if !junctions[index].gene_ids.contains(&transcript.gene_id) {
+					junctions[index].gene_ids.push(transcript.gene_id.clone());
+				}
+			}
+		}
+
+		for exons in chrom_exons.values_mut() {
+			exons.sort();
+			exons.dedup();
+		}
+
+		GeneAnnotation {
+			transcripts,
+			gene_ids,
+			junctions,
+			junction_indexes,
+			chrom_exons,
+			max_exon_length,
+		}
+	}
+
+	pub fn get_junction(&self, chrom : &str, donor : usize, acceptor : usize) -> Option<usize> {
+		self.junction_indexes.get(&(chrom.to_string(), donor, acceptor)).cloned()
+	}
+
+	//The genes with an exon overlapping any of the given blocks of a chromosome
+	pub fn get_overlapping_genes(&self, chrom : &str, blocks : &[(usize, usize)]) -> Vec<usize> {
+		let mut genes : Vec<usize> = Vec::new();
+		let exons = match self.chrom_exons.get(chrom) {
+			Some(exons) => exons,
+			None => return genes,
+		};
+
+		for &(block_start, block_end) in blocks {
+			let first : usize = exons.partition_point(|exon| exon.0 + self.max_exon_length <= block_start);
+			for &(start, end, gene_index) in exons[first..].iter().take_while(|exon| exon.0 < block_end) {
+				if start < block_end && block_start < end && !genes.contains(&gene_index) {
+					genes.push(gene_index);
+				}
+			}
+		}
+		genes.sort();
+		genes
+	}
+}
+
+//Reads counted towards each gene and junction of an annotation, in the manner of htseq-count: a read
+//counts towards a gene only if its primary alignment overlaps the exons of that gene alone
+pub struct FeatureCounts {
+	pub gene_counts : Vec<usize>,
+	pub junction_counts : Vec<usize>,
+	pub no_feature : usize,
+	pub ambiguous : usize,
+	pub not_aligned : usize,
+}
+
+impl GeneAnnotation {
+	pub fn count_features(&self, alignments : &[Vec<Alignment>], seg_ids : &[String]) -> FeatureCounts {
+		let mut feature_counts = FeatureCounts {
+			gene_counts : vec![0; self.gene_ids.len()],
+			junction_counts : vec![0; self.junctions.len()],
+			no_feature : 0,
+			ambiguous : 0,
+			not_aligned : 0,
+		};
+
+		for read_alignments in alignments {
+			let primary : &Alignment = match read_alignments.first() {
+				Some(primary) => primary,
+				None => {
+					feature_counts.not_aligned += 1;
+					continue;
+				}
+			};
+			let chrom : &str = &seg_ids[primary.segment_index];
+			let blocks : Vec<(usize, usize)> = primary.blocks();
+
+			for pair in blocks.windows(2) {
+				if let Some(junction_index) = self.get_junction(chrom, pair[0].1, pair[1].0) {
+					feature_counts.junction_counts[junction_index] += 1;
+				}
+			}
+
+			let genes : Vec<usize> = self.get_overlapping_genes(chrom, &blocks);
+			match genes.len() {
+				0 => feature_counts.no_feature += 1,
+				1 => feature_counts.gene_counts[genes[0]] += 1,
+				_ => feature_counts.ambiguous += 1,
+			}
+		}
+		feature_counts
+	}
+}
+
+//Parse GTF (key "value"; ...) or GFF3 (key=value;...) attributes
+fn parse_attributes(column : &str) -> HashMap<String, String> {
+	let mut attributes : HashMap<String, String> = HashMap::new();
+	for attribute in column.split(';').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+		let (key, value) = match attribute.find('=') {
+			Some(index) if !attribute[..index].contains(' ') => (&attribute[..index], &attribute[(index + 1)..]),
+			_ => match attribute.find(' ') {
+				Some(index) => (&attribute[..index], &attribute[(index + 1)..]),
+				None => continue,
+			},
+		};
+		attributes.entry(key.trim().to_string()).or_insert_with(|| value.trim().trim_matches('"').to_string());
+	}
+	attributes
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write_annotation(name : &str, text : &str) -> String {
+		let path = std::env::temp_dir().join(name);
+		std::fs::write(&path, text).unwrap();
+		path.to_str().unwrap().to_string()
+	}
+
+	fn spliced(position : usize, cigar : Vec<(usize, char)>) -> Alignment {
+		Alignment { segment_index : 0, position, reverse : false, cigar, edit_distance : 0, score : 0 }
+	}
+
+	#[test]
+	fn reads_gtf_exons_and_junctions() {
+		let filename = write_annotation("tuna_test_annotation.gtf",
+			"#comment\n\
+			chr1\ttest\texon\t201\t300\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+			chr1\ttest\texon\t101\t150\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+			chr1\ttest\tCDS\t101\t150\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+			chr1\ttest\texon\t101\t150\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t2\";\n\
+			chr1\ttest\texon\t401\t500\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t2\";\n");
+		let annotation = GeneAnnotation::read_annotation(&filename);
+
+		assert_eq!(annotation.transcripts.len(), 2);
+		assert_eq!(annotation.transcripts[0].exons, vec![(100, 150), (200, 300)]);
+		assert_eq!(annotation.gene_ids, vec!["g1".to_string()]);
+		assert_eq!(annotation.junctions.len(), 2);
+		assert_eq!(annotation.get_junction("chr1", 150, 200), Some(0));
+		assert_eq!(annotation.get_junction("chr1", 150, 400), Some(1));
+		assert_eq!(annotation.get_junction("chr2", 150, 200), None);
+	}
+
+	#[test]
+	fn reads_gff3_genes_through_transcript_parents() {
+		let filename = write_annotation("tuna_test_annotation.gff3",
+			"##gff-version 3\n\
+			chr1\ttest\tgene\t101\t300\t.\t-\t.\tID=g1\n\
+			chr1\ttest\tmRNA\t101\t300\t.\t-\t.\tID=t1;Parent=g1\n\
+			chr1\ttest\texon\t101\t150\t.\t-\t.\tParent=t1\n\
+			chr1\ttest\texon\t201\t300\t.\t-\t.\tParent=t1\n");
+		let annotation = GeneAnnotation::read_annotation(&filename);
+
+		assert_eq!(annotation.transcripts.len(), 1);
+		assert_eq!(annotation.transcripts[0].gene_id, "g1");
+		assert_eq!(annotation.transcripts[0].strand, '-');
+		assert_eq!(annotation.junctions[0].gene_ids, vec!["g1".to_string()]);
+	}
+
+	#[test]
+	fn counts_genes_and_junctions_like_htseq() {
+		let transcripts = vec![
+			Transcript { id : "t1".to_string(), gene_id : "g1".to_string(), chrom : "chr1".to_string(), strand : '+', exons : vec![(100, 150), (200, 300)] },
+			Transcript { id : "t2".to_string(), gene_id : "g2".to_string(), chrom : "chr1".to_string(), strand : '+', exons : vec![(280, 400)] },
+		];
+		let annotation = GeneAnnotation::from_transcripts(transcripts);
+		let seg_ids = vec!["chr1".to_string()];
+		let alignments = vec![
+			vec![spliced(130, vec![(20, 'M'), (50, 'N'), (20, 'M')])],
+			vec![spliced(290, vec![(20, 'M')])],
+			vec![spliced(500, vec![(20, 'M')])],
+			vec![spliced(350, vec![(20, 'M')])],
+			Vec::new(),
+		];
+		let feature_counts = annotation.count_features(&alignments, &seg_ids);
+
+		assert_eq!(feature_counts.gene_counts, vec![1, 1]);
+		assert_eq!(feature_counts.junction_counts, vec![1]);
+		assert_eq!(feature_counts.ambiguous, 1);
+		assert_eq!(feature_counts.no_feature, 1);
+		assert_eq!(feature_counts.not_aligned, 1);
+	}
+}
@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::collections::HashMap;
+
+use log::*;
+
+use gene_annotation::GeneAnnotation;
+use fa_metadata::SegMetadata;
+
+//The gene every segment belongs to, so that segment-level values can be summed across isoforms
+pub struct GeneMap {
+	pub gene_ids : Vec<String>,
+	pub segment_genes : Vec<usize>,
+}
+
+impl GeneMap {
+	//Genes are looked up in the tx2gene table first, then in the annotation, then in the gene attributes
+	//of the FASTA header. Segments without a gene are treated as their own gene.
+	pub fn new(seg_ids : &[String], seg_metadata : &[SegMetadata], tx2gene : Option<&HashMap<String, String>>, annotation : Option<&GeneAnnotation>) -> GeneMap {
+		let annotated : HashMap<String, String> = annotation.map_or(HashMap::new(), |annotation| {
+			annotation.transcripts.iter().map(|transcript| (transcript.id.clone(), transcript.gene_id.clone())).collect()
+		});
+
+		let mut gene_ids : Vec<String> = Vec::new();
+		let mut gene_indexes : HashMap<String, usize> = HashMap::new();
+		let mut segment_genes : Vec<usize> = Vec::new();
+		let mut unmapped : usize = 0;
+
+		for (seg_id, metadata) in seg_ids.iter().zip(seg_metadata.iter()) {
+			let gene_id : String = tx2gene.and_then(|tx2gene| lookup(tx2gene, seg_id))
+				.or_else(|| lookup(&annotated, seg_id))
+				.or_else(|| metadata.gene_id().map(|gene_id| gene_id.to_string()))
+				.unwrap_or_else(|| {
+					unmapped += 1;
+					seg_id.clone()
+				});
+
+			let gene_index : usize = *gene_indexes.entry(gene_id.clone()).or_insert_with(|| {
+				gene_ids.push(gene_id);
+				gene_ids.len() - 1
+			});
+			segment_genes.push(gene_index);
+		}
+
+		if unmapped > 0 {
+			warn!("{} segments have no gene and are reported as their own gene", unmapped);
+		}
+		info!("Mapped {} segments to {} genes", seg_ids.len(), gene_ids.len());
+		GeneMap { gene_ids, segment_genes }
+	}
+
+	//Sum a per-segment value over the segments of each gene
+	pub fn aggregate(&self, values : &[f64]) -> Vec<f64> {
+		let mut gene_values : Vec<f64> = vec![0.0; self.gene_ids.len()];
+		for (&gene_index, value) in self.segment_genes.iter().zip(values.iter()) {
+			gene_values[gene_index] += value;
+		}
+		gene_values
+	}
+
+	//Count the reads of each gene from the equivalence classes, crediting a read once to every distinct gene
+	//among its targets, however many isoforms of that gene it is compatible with
+	pub fn class_counts(&self, equivalence_classes : &HashMap<Vec<usize>, usize>) -> Vec<f64> {
+		let mut gene_counts : Vec<f64> = vec![0.0; self.gene_ids.len()];
+		for (targets, &count) in equivalence_classes.iter() {
+			let mut genes : Vec<usize> = targets.iter().map(|&target| self.segment_genes[target]).collect();
+			genes.sort();
+			genes.dedup();
+			for gene_index in genes {
+				gene_counts[gene_index] += count as f64;
+			}
+		}
+		gene_counts
+	}
+
+	//The effective length of each gene, the mean over its segments weighted by their abundances. Genes
+	//without abundance take the plain mean.
+	pub fn effective_lengths(&self, effective_lengths : &[f64], abundances : &[f64]) -> Vec<f64> {
+		let weighted : Vec<f64> = effective_lengths.iter().zip(abundances.iter()).map(|(length, abundance)| length * abundance).collect();
+		let weights : Vec<f64> = self.aggregate(abundances);
+		let sums : Vec<f64> = self.aggregate(effective_lengths);
+		let n_segments : Vec<f64> = self.aggregate(&vec![1.0; effective_lengths.len()]);
+		self.aggregate(&weighted).iter().enumerate()
+			.map(|(gene_index, weighted_sum)| if weights[gene_index] > 0.0 { weighted_sum / weights[gene_index] } else { sums[gene_index] / n_segments[gene_index] })
+			.collect()
+	}
+}
+
+/**
+    read_tx2gene:
+    Given the name of a tab-separated file of transcript IDs and gene IDs, read it into
+    a map from transcript to gene
+*/
+pub fn read_tx2gene(filename : &str) -> HashMap<String, String> {
+	let f = File::open(filename).expect("file not found");
+	let mut tx2gene : HashMap<String, String> = HashMap::new();
+
+	for line in BufReader::new(f).lines() {
+		let line_val = line.expect("line not available");
+		let fields : Vec<&str> = line_val.split(['\t', ',']).map(|field| field.trim()).collect();
+		if fields.len() < 2 || fields[0].is_empty() || line_val.starts_with('#') {
+			continue;
+		}
+		tx2gene.insert(fields[0].to_string(), fields[1].to_string());
+		tx2gene.entry(strip_version(fields[0]).to_string()).or_insert_with(|| fields[1].to_string());
+	}
+	tx2gene
+}
+
+//Look up a transcript ID with and without its version suffix
+fn lookup(map : &HashMap<String, String>, transcript_id : &str) -> Option<String> {
+	map.get(transcript_id)
+		.or_else(|| map.get(strip_version(transcript_id)))
+		.cloned()
+}
+
+fn strip_version(transcript_id : &str) -> &str {
+	match transcript_id.rfind('.') {
+		Some(index) if transcript_id[(index + 1)..].chars().all(|c| c.is_ascii_digit()) => &transcript_id[..index],
+		_ => transcript_id,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn strips_only_numeric_versions() {
+		assert_eq!(strip_version("ENST0001.12"), "ENST0001");
+		assert_eq!(strip_version("tx.a"), "tx.a");
+		assert_eq!(strip_version("tx"), "tx");
+	}
+
+	#[test]
+	fn maps_segments_by_tx2gene_then_header_then_own_id() {
+		let seg_ids : Vec<String> = vec!["t1.2".to_string(), "t2".to_string(), "t3".to_string(), "t4".to_string()];
+		let seg_metadata : Vec<SegMetadata> = vec![
+			SegMetadata::default(),
+			SegMetadata::parse("t2", "gene:g1"),
+			SegMetadata::parse("t3", "gene:g2"),
+			SegMetadata::default(),
+		];
+		let mut tx2gene : HashMap<String, String> = HashMap::new();
+		tx2gene.insert("t1".to_string(), "g1".to_string());
+		tx2gene.insert("t3".to_string(), "g3".to_string());
+		let gene_map = GeneMap::new(&seg_ids, &seg_metadata, Some(&tx2gene), None);
+
+		assert_eq!(gene_map.gene_ids, vec!["g1", "g3", "t4"]);
+		assert_eq!(gene_map.segment_genes, vec![0, 0, 1, 2]);
+		assert_eq!(gene_map.aggregate(&[1.0, 2.0, 3.0, 4.0]), vec![3.0, 3.0, 4.0]);
+	}
+
+	#[test]
+	fn credits_reads_of_several_isoforms_once_to_their_gene() {
+		let gene_map = GeneMap { gene_ids : vec!["g1".to_string(), "g2".to_string()], segment_genes : vec![0, 0, 0, 1] };
+		let mut equivalence_classes : HashMap<Vec<usize>, usize> = HashMap::new();
+		//Five reads compatible with all three isoforms of g1, two of them and g2 as well, and one with g2 only
+		equivalence_classes.insert(vec![0, 1, 2], 5);
+		equivalence_classes.insert(vec![1, 2, 3], 2);
+		equivalence_classes.insert(vec![3], 1);
+		assert_eq!(gene_map.class_counts(&equivalence_classes), vec![7.0, 3.0]);
+
+		assert_eq!(gene_map.effective_lengths(&[100.0, 200.0, 400.0, 50.0], &[3.0, 1.0, 0.0, 0.0]), vec![125.0, 50.0]);
+	}
+
+	#[test]
+	fn reads_tab_and_comma_separated_tx2gene() {
+		let path = std::env::temp_dir().join("tuna_test_tx2gene.tsv");
+		std::fs::write(&path, "#transcript\tgene\nt1.1\tg1\nt2,g2\n").unwrap();
+		let tx2gene = read_tx2gene(path.to_str().unwrap());
+
+		assert_eq!(lookup(&tx2gene, "t1.3"), Some("g1".to_string()));
+		assert_eq!(lookup(&tx2gene, "t1.1"), Some("g1".to_string()));
+		assert_eq!(lookup(&tx2gene, "t2"), Some("g2".to_string()));
+		assert_eq!(tx2gene.len(), 3);
+	}
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use log::*;
+
+use library_type::reverse_complement;
+
+//The file formats k-mer counts can be dumped in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KmerFormat {
+	Text,
+	Binary,
+}
+
+impl FromStr for KmerFormat {
+	type Err = String;
+
+	fn from_str(format : &str) -> Result<KmerFormat, String> {
+		match format {
+			"text" | "tsv" => Ok(KmerFormat::Text),
+			"binary" => Ok(KmerFormat::Binary),
+			_ => Err(format!("unknown k-mer format {}, expected text or binary", format)),
+		}
+	}
+}
+
+/**
+    count_kmers:
+    Count every distinct k-mer of the sequences, merging each k-mer with its reverse
+    complement under the lesser of the two when counting canonically. The counts are
+    sorted by k-mer.
+*/
+pub fn count_kmers(sequences : &[String], k : usize, canonical : bool) -> Vec<(String, usize)> {
+	let mut kmer_counts : HashMap<&str, usize> = HashMap::new();
+	for sequence in sequences {
+		for i in 0..(sequence.len() + 1).saturating_sub(k) {
+			*kmer_counts.entry(&sequence[i..(i + k)]).or_insert(0) += 1;
+		}
+	}
+	let mut counts : Vec<(String, usize)> = if canonical {
+		let mut canonical_counts : HashMap<String, usize> = HashMap::with_capacity(kmer_counts.len());
+		for (kmer, count) in kmer_counts {
+			let rc : String = reverse_complement(kmer);
+			let canonical_kmer : String = if rc.as_str() < kmer { rc } else { kmer.to_string() };
+			*canonical_counts.entry(canonical_kmer).or_insert(0) += count;
+		}
+		canonical_counts.into_iter().collect()
+	} else {
+		kmer_counts.into_iter().map(|(kmer, count)| (kmer.to_string(), count)).collect()
+	};
+	counts.sort();
+	info!("Counted {} distinct {}k-mers", counts.len(), if canonical { "canonical " } else { "" });
+	counts
+}
+
+//Number of distinct k-mers seen each number of times, the k-mer spectrum
+pub fn kmer_histogram(counts : &[(String, usize)]) -> BTreeMap<usize, usize> {
+	let mut histogram : BTreeMap<usize, usize> = BTreeMap::new();
+	for &(_, count) in counts {
+		*histogram.entry(count).or_insert(0) += 1;
+	}
+	histogram
+}
+
+/**
+    estimate_genome_size:
+    Estimate the k-mer coverage and genome size from the spectrum: past the first valley,
+    which separates k-mers with sequencing errors from genomic ones, the highest peak is
+    the coverage, and the genomic k-mers divided by it the genome size
+*/
+pub fn estimate_genome_size(histogram : &BTreeMap<usize, usize>) -> Option<(usize, usize)> {
+	let spectrum : Vec<(usize, usize)> = histogram.iter().map(|(&multiplicity, &count)| (multiplicity, count)).collect();
+	let valley : usize = (1..spectrum.len()).find(|&i| spectrum[i].1 > spectrum[i - 1].1)? - 1;
+	let &(peak, _) = spectrum[valley..].iter().max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))?;
+	let n_genomic : usize = spectrum[valley..].iter().map(|&(multiplicity, count)| multiplicity * count).sum();
+	Some((peak, n_genomic / peak))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sequences(seqs : &[&str]) -> Vec<String> {
+		seqs.iter().map(|seq| seq.to_string()).collect()
+	}
+
+	#[test]
+	fn counts_kmers_and_merges_reverse_complements() {
+		let seqs = sequences(&["ACGTT", "AAC", "GT"]);
+		let counts = count_kmers(&seqs, 3, false);
+		assert_eq!(counts, vec![("AAC".to_string(), 1), ("ACG".to_string(), 1), ("CGT".to_string(), 1), ("GTT".to_string(), 1)]);
+		//AAC and GTT are reverse complements, as are ACG and CGT
+		let canonical = count_kmers(&seqs, 3, true);
+		assert_eq!(canonical, vec![("AAC".to_string(), 2), ("ACG".to_string(), 2)]);
+	}
+
+	#[test]
+	fn estimates_coverage_past_the_error_valley() {
+		let counts : Vec<(String, usize)> = vec![("A".to_string(), 1), ("C".to_string(), 1), ("G".to_string(), 1), ("T".to_string(), 2),
+			("AA".to_string(), 3), ("AC".to_string(), 3), ("AG".to_string(), 4)];
+		let histogram = kmer_histogram(&counts);
+		assert_eq!(histogram.iter().map(|(&multiplicity, &count)| (multiplicity, count)).collect::<Vec<(usize, usize)>>(),
+			vec![(1, 3), (2, 1), (3, 2), (4, 1)]);
+		//Past the valley at 2, the peak at 3 is the coverage of the 2 + 6 + 4 genomic k-mers
+		assert_eq!(estimate_genome_size(&histogram), Some((3, 4)));
+		assert_eq!(estimate_genome_size(&kmer_histogram(&counts[..3])), None);
+	}
+
+	#[test]
+	fn parses_formats() {
+		assert_eq!("tsv".parse::<KmerFormat>(), Ok(KmerFormat::Text));
+		assert_eq!("binary".parse::<KmerFormat>(), Ok(KmerFormat::Binary));
+		assert!("json".parse::<KmerFormat>().is_err());
+	}
+}
@@ -0,0 +1,268 @@
+use rayon::prelude::*;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use log::*;
+
+use dna_hash_table::Pseudoaligner;
+
+//Fraction of oriented reads that must agree on a strand for the library to be called stranded
+const STRANDED_FRACTION : f64 = 0.8;
+
+//Which strand of the segments the first read of a fragment comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strandedness {
+	Forward,
+	Reverse,
+	Unstranded,
+}
+
+//How the mates of a paired-end fragment face each other: towards one another, or on the same strand
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MateOrientation {
+	Inward,
+	Matching,
+}
+
+//A library type written as in Salmon: an optional mate orientation (I or M), then SF, SR or U
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LibraryType {
+	pub orientation : Option<MateOrientation>,
+	pub strandedness : Strandedness,
+}
+
+impl FromStr for LibraryType {
+	type Err = String;
+
+	fn from_str(code : &str) -> Result<LibraryType, String> {
+		let (orientation, strand_code) = match code.chars().next() {
+			Some('I') => (Some(MateOrientation::Inward), &code[1..]),
+			Some('M') => (Some(MateOrientation::Matching), &code[1..]),
+			_ => (None, code),
+		};
+		let strandedness : Strandedness = match strand_code {
+			"SF" => Strandedness::Forward,
+			"SR" => Strandedness::Reverse,
+			"U" => Strandedness::Unstranded,
+			_ => return Err(format!("unknown library type {}, expected U, SF or SR, optionally prefixed by I or M", code)),
+		};
+		Ok(LibraryType { orientation, strandedness })
+	}
+}
+
+impl LibraryType {
+	pub fn code(&self) -> String {
+		let orientation : &str = match self.orientation {
+			Some(MateOrientation::Inward) => "I",
+			Some(MateOrientation::Matching) => "M",
+			None => "",
+		};
+		let strandedness : &str = match self.strandedness {
+			Strandedness::Forward => "SF",
+			Strandedness::Reverse => "SR",
+			Strandedness::Unstranded => "U",
+		};
+		format!("{}{}", orientation, strandedness)
+	}
+}
+
+//The library type to quantify with: either given, or detected from a sample of the reads (A)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LibraryTypeOption {
+	Detect,
+	Known(LibraryType),
+}
+
+impl FromStr for LibraryTypeOption {
+	type Err = String;
+
+	fn from_str(code : &str) -> Result<LibraryTypeOption, String> {
+		match code {
+			"A" => Ok(LibraryTypeOption::Detect),
+			_ => code.parse::<LibraryType>().map(LibraryTypeOption::Known),
+		}
+	}
+}
+
+impl LibraryTypeOption {
+	pub fn code(&self) -> String {
+		match *self {
+			LibraryTypeOption::Detect => "A".to_string(),
+			LibraryTypeOption::Known(library_type) => library_type.code(),
+		}
+	}
+}
+
+//The orientations seen among a sample of reads, and the library type they point to
+#[derive(Clone, Debug)]
+pub struct LibraryFormatCounts {
+	pub library_type : LibraryType,
+	pub n_sampled : usize,
+	pub n_ambiguous : usize,
+	pub format_counts : BTreeMap<String, usize>,
+}
+
+impl LibraryFormatCounts {
+	//Fraction of the oriented reads whose first read lies on the forward strand
+	pub fn strand_bias(&self) -> f64 {
+		let n_forward : usize = self.format_counts.iter().filter(|&(code, _)| code.ends_with('F')).map(|(_, &count)| count).sum();
+		let n_oriented : usize = self.format_counts.values().sum();
+		if n_oriented > 0 { n_forward as f64 / n_oriented as f64 } else { 0.5 }
+	}
+}
+
+/**
+    detect_library_type:
+    Pseudoalign both strands of the first n_sample reads (and their mates, when given)
+    and infer the library type from the strands the reads agree best with
+*/
+pub fn detect_library_type<P : Pseudoaligner + Sync>(reads : &[String], mates : Option<&[String]>, pseudoaligner : &P,
+		n_targets : usize, n_sample : usize) -> LibraryFormatCounts {
+	let n_sample : usize = n_sample.min(reads.len()).min(mates.map_or(reads.len(), |mates| mates.len()));
+	let formats : Vec<Option<String>> = (0..n_sample).into_par_iter()
+		.map(|i| {
+			let read_forward : bool = read_strand(pseudoaligner, &reads[i], n_targets)?;
+			let strand : &str = if read_forward { "SF" } else { "SR" };
+			match mates {
+				Some(mates) => {
+					let mate_forward : bool = read_strand(pseudoaligner, &mates[i], n_targets)?;
+					let orientation : &str = if mate_forward != read_forward { "I" } else { "M" };
+					Some(format!("{}{}", orientation, strand))
+				},
+				None => Some(strand.to_string()),
+			}
+		})
+		.collect();
+
+	let mut format_counts : BTreeMap<String, usize> = BTreeMap::new();
+	for format in formats.iter().filter_map(|format| format.as_ref()) {
+		*format_counts.entry(format.clone()).or_insert(0) += 1;
+	}
+	let n_ambiguous : usize = formats.iter().filter(|format| format.is_none()).count();
+
+	let orientation : Option<MateOrientation> = mates.map(|_| {
+		let n_inward : usize = format_counts.iter().filter(|&(code, _)| code.starts_with('I')).map(|(_, &count)| count).sum();
+		let n_matching : usize = format_counts.iter().filter(|&(code, _)| code.starts_with('M')).map(|(_, &count)| count).sum();
+		if n_matching > n_inward { MateOrientation::Matching } else { MateOrientation::Inward }
+	});
+	let mut library_format_counts = LibraryFormatCounts {
+		library_type : LibraryType { orientation, strandedness : Strandedness::Unstranded },
+		n_sampled : n_sample,
+		n_ambiguous,
+		format_counts,
+	};
+	let strand_bias : f64 = library_format_counts.strand_bias();
+	if strand_bias >= STRANDED_FRACTION {
+		library_format_counts.library_type.strandedness = Strandedness::Forward;
+	} else if strand_bias <= 1.0 - STRANDED_FRACTION {
+		library_format_counts.library_type.strandedness = Strandedness::Reverse;
+	}
+
+	info!("Detected library type {} from {} sampled reads ({} could not be oriented)",
+		library_format_counts.library_type.code(), n_sample, n_ambiguous);
+	library_format_counts
+}
+
+/**
+    orient_reads:
+    Turn the reads onto the strand of the segments they come from: all of them for a
+    reverse-stranded library, and each read by its own best strand for an unstranded one
+*/
+pub fn orient_reads<P : Pseudoaligner + Sync>(reads : &mut Vec<String>, library_type : &LibraryType, pseudoaligner : &P, n_targets : usize) {
+	match library_type.strandedness {
+		Strandedness::Forward => (),
+		Strandedness::Reverse => reads.par_iter_mut().for_each(|read| *read = reverse_complement(read)),
+		Strandedness::Unstranded => reads.par_iter_mut().for_each(|read| {
+			if read_strand(pseudoaligner, read, n_targets) == Some(false) {
+				*read = reverse_complement(read);
+			}
+		}),
+	}
+}
+
+pub fn reverse_complement(sequence : &str) -> String {
+	sequence.chars().rev()
+		.map(|base| match base {
+			'A' => 'T',
+			'C' => 'G',
+			'G' => 'C',
+			'T' => 'A',
+			'a' => 't',
+			'c' => 'g',
+			'g' => 'c',
+			't' => 'a',
+			'U' => 'A',
+			'u' => 'a',
+			other => other,
+		})
+		.collect()
+}
+
+//Whether a read shares more k-mers with a target on the forward strand than on the reverse strand, or
+//None when both strands do equally well
+fn read_strand<P : Pseudoaligner>(pseudoaligner : &P, read : &str, n_targets : usize) -> Option<bool> {
+	let best_hits = |sequence : &str| -> usize {
+		pseudoaligner.pseudoalign(sequence).map_or(0, |read_mapping| {
+			read_mapping.kmer_hits.iter()
+				.filter(|&&(segment_index, _)| segment_index < n_targets)
+				.map(|&(_, hits)| hits)
+				.max()
+				.unwrap_or(0)
+		})
+	};
+	let forward_hits : usize = best_hits(read);
+	let reverse_hits : usize = best_hits(&reverse_complement(read));
+	if forward_hits > reverse_hits {
+		Some(true)
+	} else if reverse_hits > forward_hits {
+		Some(false)
+	} else {
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use dna_hash_table::DNAHashTable;
+
+	#[test]
+	fn parses_library_types_and_rejects_unknown_ones() {
+		let library_type : LibraryType = "ISR".parse().unwrap();
+		assert_eq!(library_type, LibraryType { orientation : Some(MateOrientation::Inward), strandedness : Strandedness::Reverse });
+		assert_eq!(library_type.code(), "ISR");
+		assert_eq!("U".parse::<LibraryType>().unwrap().code(), "U");
+		assert!("A".parse::<LibraryType>().is_err());
+		assert!("IX".parse::<LibraryType>().is_err());
+		assert_eq!("A".parse::<LibraryTypeOption>(), Ok(LibraryTypeOption::Detect));
+		assert_eq!("MSF".parse::<LibraryTypeOption>().unwrap().code(), "MSF");
+	}
+
+	#[test]
+	fn reverse_complements_dna_and_rna() {
+		assert_eq!(reverse_complement("ACGTN"), "NACGT");
+		assert_eq!(reverse_complement("acgu"), "acgt");
+		assert_eq!(reverse_complement("UUAG"), "CTAA");
+	}
+
+	#[test]
+	fn detects_reverse_stranded_reads() {
+		let segments : Vec<String> = vec!["CCGTAATGCCTTTCCCTAACAGAGTTTTTCGAACTCGTGT".to_string()];
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let mut reads : Vec<String> = vec![
+			reverse_complement("CCGTAATGCCTTTCCC"),
+			reverse_complement("TAACAGAGTTTTTCGA"),
+			reverse_complement("CCCTAACAGAGTTTTT"),
+			"AAAAAAAAAAAAAAAA".to_string(),
+		];
+		let library_format_counts = detect_library_type(&reads, None, &kmer_hash_table, 1, 10);
+		assert_eq!(library_format_counts.library_type.code(), "SR");
+		assert_eq!(library_format_counts.n_sampled, 4);
+		assert_eq!(library_format_counts.n_ambiguous, 1);
+
+		orient_reads(&mut reads, &library_format_counts.library_type, &kmer_hash_table, 1);
+		assert_eq!(reads[0], "CCGTAATGCCTTTCCC");
+	}
+}
@@ -2,6 +2,9 @@ extern crate primes;
 extern crate rayon;
 extern crate log;
 extern crate env_logger;
+extern crate rand;
+extern crate rand_distr;
+extern crate flate2;
 
 #[macro_use]
 extern crate structopt;
@@ -9,21 +12,588 @@ extern crate structopt;
 
 mod dna_hash_table;
 mod dna_read_graph;
+mod dna_unitig_graph;
+mod dna_aligner;
+mod sam_output;
+mod gene_annotation;
+mod dna_spliced_aligner;
+mod abundance;
+mod gene_map;
+mod fa_metadata;
+mod duplicate_segments;
+mod bootstrap;
+mod bias;
+mod library_type;
+mod meta_info;
+mod count_output;
+mod single_cell;
+mod umi;
+mod read_qc;
+mod kmer_count;
+mod simulate;
+mod evaluate;
+mod contaminants;
+mod taxonomy;
 mod read_inputs;
 mod args;
 
 use env_logger::Env;
 use rayon::prelude::*;
 use std::collections::HashMap;
+use std::time::Instant;
 use dna_hash_table::DNAHashTable;
+use dna_hash_table::Pseudoaligner;
+use dna_hash_table::ReadOutcome;
+use dna_hash_table::SegmentCounts;
+use dna_unitig_graph::DNAUnitigGraph;
+use dna_aligner::SeedExtendAligner;
+use dna_aligner::Alignment;
+use dna_aligner::ReadAligner;
+use dna_spliced_aligner::JunctionSet;
+use dna_spliced_aligner::SplicedAligner;
+use gene_annotation::GeneAnnotation;
+use gene_map::GeneMap;
+use abundance::Abundance;
+use bootstrap::ClassTable;
+use bias::BiasModel;
+use contaminants::ContaminantDB;
+use library_type::LibraryType;
+use library_type::LibraryTypeOption;
+use library_type::LibraryFormatCounts;
+use meta_info::MetaInfo;
+use meta_info::QuantRun;
+use sam_output::BamWriter;
+use sam_output::SamWriter;
+use simulate::ErrorModel;
+use simulate::FragmentModel;
+use read_inputs::FqRecord;
+use read_inputs::FaColDB;
+use read_inputs::Sample;
+use read_inputs::SampleSummary;
+use read_inputs::TrimOptions;
+use read_inputs::TrimStats;
+use single_cell::BarcodeLayout;
+use single_cell::BarcodeRanks;
+use single_cell::CellMatrix;
+use single_cell::Whitelist;
+use taxonomy::Taxonomy;
+use umi::UmiSource;
 use structopt::StructOpt;
+use structopt::clap::AppSettings;
 use log::*;
 
 fn main() {
 
-    let opt = args::Opt::from_args();
+    let start_time = Instant::now();
+    //Subcommands are named as the first argument, without which the reads are quantified
+    let matches = args::Command::augment_clap(args::Opt::clap())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .get_matches();
+    if let Some(command) = args::Command::from_subcommand(matches.subcommand()) {
+        run_command(command);
+        return;
+    }
+
+    let opt = args::Opt::from_clap(&matches);
+    init_logger(opt.verbose);
+
+    let mut fa_col_db = read_inputs::read_fa_file_to_cols(opt.seq_input_filename.as_str());
+    if opt.gencode {
+        fa_col_db.use_transcript_ids();
+    }
+
+    let segment_collapse = duplicate_segments::find_duplicates(&fa_col_db.seg_strings, opt.collapse_contained, opt.k);
+    if let Some(ref filename) = opt.duplicates_output_filename {
+        read_inputs::write_duplicates(filename, &segment_collapse, &fa_col_db.seg_ids);
+    }
+    if !opt.keep_duplicates {
+        fa_col_db.retain_segments(&segment_collapse.retained);
+    }
+    if let Some(ref filename) = opt.decoys_filename {
+        let decoys = read_inputs::read_fa_file_to_cols(filename);
+        info!("Indexing {} decoy sequences", decoys.seg_ids.len());
+        fa_col_db.append_decoys(decoys);
+    }
+
+    for s in &fa_col_db.seg_ids {
+        debug!("Read in {}", s);
+    }
+    let kmer_hash_table = DNAHashTable::new(&fa_col_db.seg_strings, opt.k);
+    let unitig_graph = if opt.unitigs || opt.unitig_output_filename.is_some() {
+        Some(DNAUnitigGraph::new(&kmer_hash_table))
+    } else {
+        None
+    };
+    if let (Some(filename), Some(graph)) = (&opt.unitig_output_filename, &unitig_graph) {
+        read_inputs::write_unitigs(filename, graph, &fa_col_db.seg_ids);
+    }
+
+    if let Some(ref r1_filename) = opt.single_cell_filename {
+        quantify_cells(&opt, r1_filename, &fa_col_db, &kmer_hash_table, unitig_graph.as_ref());
+        return;
+    }
+    if opt.sample_sheet {
+        quantify_samples(&opt, &fa_col_db, &kmer_hash_table, unitig_graph.as_ref());
+        return;
+    }
+
+    let mut records : Vec<FqRecord> = read_inputs::read_fq_fasta_records(opt.read_input_filename.as_str());
+    let mut mate_records : Option<Vec<FqRecord>> = opt.mates_filename.as_ref().map(|filename| read_inputs::read_fq_fasta_records(filename));
+    let umi_source : Option<UmiSource> = umi_source(&opt);
+    if let Some(ref umi_source) = umi_source {
+        umi::extract_umis(&mut records, umi_source);
+    }
+    let trim_options : TrimOptions = trim_options(&opt);
+    let trim_stats : Option<TrimStats> = if trim_options.is_enabled() {
+        Some(read_inputs::trim_records(&mut records, mate_records.as_mut(), &trim_options))
+    } else {
+        None
+    };
+    if !opt.contaminant_filenames.is_empty() {
+        screen_contaminants(&opt, &mut records, mate_records.as_mut());
+    }
+    let umis : Option<Vec<String>> = umi_source.map(|_| records.iter().map(|record| record.umi.clone().unwrap_or_default()).collect());
+    let mut reads2 : Vec<String> = records.iter().map(|record| record.seq.clone()).collect();
+    let mates : Option<Vec<String>> = mate_records.map(|mate_records| mate_records.into_iter().map(|record| record.seq).collect());
+
+    let (library_format_counts, segment_counts, partition_seconds) = pseudoalign_reads(&opt, &mut reads2, mates.as_ref(), umis.as_ref(),
+        &kmer_hash_table, unitig_graph.as_ref(), fa_col_db.n_targets);
+    if let (Some(filename), Some(library_format_counts)) = (&opt.lib_format_counts_filename, &library_format_counts) {
+        read_inputs::write_lib_format_counts(filename, library_format_counts);
+    }
+    if opt.unmapped_reads_filename.is_some() || opt.multimapping_reads_filename.is_some() || opt.read_assignments_filename.is_some() {
+        let outcomes : &[ReadOutcome] = &segment_counts.outcomes;
+        if let Some(ref filename) = opt.unmapped_reads_filename {
+            write_unmapped_reads(filename, &records, outcomes);
+        }
+        if let Some(ref filename) = opt.multimapping_reads_filename {
+            read_inputs::write_multimapping_reads(filename, &records, outcomes, &fa_col_db.seg_ids);
+        }
+        if let Some(ref filename) = opt.read_assignments_filename {
+            read_inputs::write_read_assignments(filename, &records, outcomes, &segment_counts.numbered_classes(), &fa_col_db.seg_ids);
+        }
+    }
+    if let Some(ref filename) = opt.eq_classes_filename {
+        read_inputs::write_equivalence_classes(filename, &segment_counts.numbered_classes(), &fa_col_db.seg_ids);
+    }
+    let mapping_stats = &segment_counts.mapping_stats;
+    info!("Processed {} reads: {} assigned, {} with no k-mer hits, {} discarded as best explained by decoys",
+        mapping_stats.n_reads, mapping_stats.n_assigned, mapping_stats.n_no_hits, mapping_stats.n_decoy);
+    let global_map : HashMap<i32, i32> = segment_counts.counts.clone();
+    let mean_read_length : f64 = records.iter().map(|record| record.seq.len()).sum::<usize>() as f64 / records.len().max(1) as f64;
+    let effective_lengths : Vec<f64> = if opt.seq_bias || opt.gc_bias {
+        let bias_model = BiasModel::learn(&reads2, &kmer_hash_table, fa_col_db.n_targets, &global_map, mean_read_length,
+            opt.seq_bias, opt.gc_bias);
+        bias_model.effective_lengths(fa_col_db.target_strings(), mean_read_length)
+    } else {
+        fa_col_db.target_strings().iter()
+            .map(|seg_string| abundance::effective_length(seg_string.len(), mean_read_length))
+            .collect()
+    };
 
-    if opt.verbose {
+    let class_table = ClassTable::new(&segment_counts.equivalence_classes, fa_col_db.n_targets);
+    let wants_replicates : bool = opt.bootstrap_output_filename.is_some() || opt.bootstrap_matrix_filename.is_some();
+    if !wants_replicates && (opt.num_bootstraps.is_some() || opt.num_gibbs_samples.is_some()) {
+        warn!("Neither --bootstrap_output nor --bootstrap_matrix was given, so no replicates are drawn");
+    }
+    let replicates : Option<Vec<Vec<f64>>> = match (opt.num_bootstraps, opt.num_gibbs_samples) {
+        _ if !wants_replicates => None,
+        (Some(num_bootstraps), _) => Some(class_table.bootstrap(num_bootstraps, opt.seed)),
+        (None, Some(num_gibbs_samples)) => Some(class_table.gibbs(num_gibbs_samples, opt.gibbs_burn_in, &effective_lengths, opt.seed)),
+        (None, None) => None,
+    };
+    if let Some(ref replicates) = replicates {
+        if let Some(ref filename) = opt.bootstrap_output_filename {
+            let summary = bootstrap::summarise(replicates, fa_col_db.n_targets);
+            read_inputs::write_bootstrap_summary(filename, &summary, fa_col_db.target_ids());
+        }
+        if let Some(ref filename) = opt.bootstrap_matrix_filename {
+            read_inputs::write_bootstrap_matrix(filename, replicates, fa_col_db.target_ids());
+        }
+    }
+
+    let annotation : Option<GeneAnnotation> = opt.gtf_filename.as_ref()
+        .map(|filename| GeneAnnotation::read_annotation(filename));
+    let junction_set : Option<JunctionSet> = annotation.as_ref().map(|annotation| {
+        let flank : usize = records.iter().map(|record| record.seq.len()).max().unwrap_or(1).saturating_sub(1);
+        JunctionSet::new(annotation, &fa_col_db.seg_ids, &fa_col_db.seg_strings, flank, opt.k)
+    });
+    let junction_hash_table : Option<DNAHashTable> = junction_set.as_ref()
+        .map(|junction_set| DNAHashTable::new(&junction_set.sequences, opt.k));
+
+    if opt.sam_output_filename.is_some() || opt.bam_output_filename.is_some() || opt.gene_counts_filename.is_some()
+        || opt.junction_counts_filename.is_some() {
+        let aligner = SeedExtendAligner::new(&kmer_hash_table, opt.band, opt.min_score_fraction);
+        let alignments : Vec<Vec<Alignment>> = match (&junction_set, &junction_hash_table) {
+            (Some(junction_set), Some(junction_hash_table)) => {
+                let junction_aligner = SeedExtendAligner::new(junction_hash_table, opt.band, opt.min_score_fraction);
+                align_reads(&records, &SplicedAligner::new(aligner, junction_aligner, junction_set))
+            },
+            _ => align_reads(&records, &aligner),
+        };
+        info!("Aligned {} of {} reads", alignments.iter().filter(|a| !a.is_empty()).count(), records.len());
+
+        if let Some(ref filename) = opt.sam_output_filename {
+            let mut sam_writer = SamWriter::new(filename, &fa_col_db.seg_ids, &fa_col_db.seg_strings);
+            for (record, read_alignments) in records.iter().zip(alignments.iter()) {
+                sam_writer.write_read(record, read_alignments);
+            }
+        }
+        if let Some(ref filename) = opt.bam_output_filename {
+            let mut bam_writer = BamWriter::new(filename, &fa_col_db.seg_ids, &fa_col_db.seg_strings);
+            for (record, read_alignments) in records.iter().zip(alignments.iter()) {
+                bam_writer.write_read(record, read_alignments);
+            }
+        }
+
+        if let Some(ref annotation) = annotation {
+            let feature_counts = annotation.count_features(&alignments, &fa_col_db.seg_ids);
+            if let Some(ref filename) = opt.gene_counts_filename {
+                read_inputs::write_gene_counts(filename, annotation, &feature_counts);
+            }
+            if let Some(ref filename) = opt.junction_counts_filename {
+                read_inputs::write_junction_counts(filename, annotation, &feature_counts);
+            }
+        }
+    }
+
+    let abundance = Abundance::new(&global_map, &effective_lengths);
+    if let Some(ref filename) = opt.gene_output_filename {
+        let tx2gene = opt.tx2gene_filename.as_ref().map(|filename| gene_map::read_tx2gene(filename));
+        let gene_map = GeneMap::new(fa_col_db.target_ids(), &fa_col_db.seg_metadata, tx2gene.as_ref(), annotation.as_ref());
+        //Gene counts come from the equivalence classes, as a read counted for every isoform it fits would be
+        //counted several times over in the sum of its isoforms
+        let gene_counts : Vec<f64> = gene_map.class_counts(&segment_counts.equivalence_classes);
+        let gene_effective_lengths : Vec<f64> = gene_map.effective_lengths(&abundance.effective_lengths, &abundance.tpm);
+        let gene_tpm : Vec<f64> = abundance::tpm(&gene_counts, &gene_effective_lengths);
+        read_inputs::write_gene_output(filename, &gene_map, &gene_counts, &gene_tpm);
+    }
+
+    let extra_columns : Vec<String> = opt.extra_columns.as_ref()
+        .map_or(Vec::new(), |columns| columns.split(',').map(|column| column.trim().to_string()).collect());
+    read_inputs::write_output5(opt.seqcount_output_filename.as_str(), opt.format, fa_col_db.target_ids(), fa_col_db.target_strings(),
+        &abundance, &fa_col_db.seg_metadata, &extra_columns);
+
+    if let Some(ref filename) = opt.meta_info_filename {
+        let meta_info = MetaInfo::new(QuantRun {
+            k : opt.k,
+            seg_ids : &fa_col_db.seg_ids,
+            seg_strings : &fa_col_db.seg_strings,
+            segment_counts : &segment_counts,
+            partition_seconds,
+            library_format_counts,
+            trim_stats,
+            start_time,
+        });
+        read_inputs::write_meta_info(filename, &meta_info);
+    }
+}
+
+//Detect or take the library type, orient the reads by it and count the reads of every segment
+fn pseudoalign_reads(opt : &args::Opt, reads : &mut Vec<String>, mates : Option<&Vec<String>>, umis : Option<&Vec<String>>, kmer_hash_table : &DNAHashTable,
+        unitig_graph : Option<&DNAUnitigGraph>, n_targets : usize) -> (Option<LibraryFormatCounts>, SegmentCounts, Vec<f64>) {
+    let (library_format_counts, library_type) : (Option<LibraryFormatCounts>, LibraryType) = match opt.libtype {
+        LibraryTypeOption::Detect => {
+            let library_format_counts = library_type::detect_library_type(reads, mates.map(|mates| mates.as_slice()), kmer_hash_table,
+                n_targets, opt.libtype_sample);
+            let library_type : LibraryType = library_format_counts.library_type;
+            (Some(library_format_counts), library_type)
+        },
+        LibraryTypeOption::Known(library_type) => (None, library_type),
+    };
+    library_type::orient_reads(reads, &library_type, kmer_hash_table, n_targets);
+
+    //The outcome of every read is only kept for the outputs listing reads
+    let keep_outcomes : bool = opt.unmapped_reads_filename.is_some() || opt.multimapping_reads_filename.is_some()
+        || opt.read_assignments_filename.is_some();
+    let (segment_counts, partition_seconds) : (SegmentCounts, Vec<f64>) = match (unitig_graph, umis) {
+        (Some(graph), Some(umis)) if opt.unitigs => time(|| umi::count_molecules(graph, kmer_hash_table, reads, umis, n_targets, keep_outcomes)),
+        (_, Some(umis)) => time(|| umi::count_molecules(kmer_hash_table, kmer_hash_table, reads, umis, n_targets, keep_outcomes)),
+        (Some(graph), None) if opt.unitigs => perform_map_reduce(opt.n_partition, reads, graph, n_targets, keep_outcomes),
+        _ => perform_map_reduce(opt.n_partition, reads, kmer_hash_table, n_targets, keep_outcomes),
+    };
+    (library_format_counts, segment_counts, partition_seconds)
+}
+
+//Write the reads without k-mer hits or best explained by a decoy, noting which after their names
+fn write_unmapped_reads(filename : &str, records : &[FqRecord], outcomes : &[ReadOutcome]) {
+    let unmapped : Vec<FqRecord> = records.iter().zip(outcomes.iter())
+        .filter_map(|(record, outcome)| {
+            let reason : &str = match *outcome {
+                ReadOutcome::NoHits => "no_hits",
+                ReadOutcome::Decoy(_) => "decoy",
+                ReadOutcome::Assigned(..) => return None,
+            };
+            Some(FqRecord { name : format!("{} {}", record.name, reason), ..record.clone() })
+        })
+        .collect();
+    info!("Writing {} unmapped reads", unmapped.len());
+    read_inputs::write_fq_records(filename, unmapped.iter());
+}
+
+//Remove the reads matching any of the contaminant sets, and write what was removed
+fn screen_contaminants(opt : &args::Opt, records : &mut Vec<FqRecord>, mate_records : Option<&mut Vec<FqRecord>>) {
+    let sets : Vec<(String, FaColDB)> = opt.contaminant_filenames.iter()
+        .map(|filename| (contaminants::set_name(filename), read_inputs::read_fa_file_to_cols(filename)))
+        .collect();
+    let contaminant_db = ContaminantDB::new(sets, opt.k);
+    let contaminant_hash_table = DNAHashTable::new(&contaminant_db.seg_strings, opt.k);
+    let (removed, contaminant_stats) = contaminants::screen_reads(records, mate_records, &contaminant_hash_table, &contaminant_db, opt.k,
+        opt.contaminant_min_fraction);
+
+    if let Some(ref filename) = opt.filtered_reads_filename {
+        read_inputs::write_fq_records(filename, records.iter());
+    }
+    if let Some(ref filename) = opt.contaminant_reads_filename {
+        read_inputs::write_fq_records(filename, removed.iter());
+    }
+    if let Some(ref filename) = opt.contaminant_summary_filename {
+        read_inputs::write_contaminant_summary(filename, &contaminant_db, &contaminant_stats);
+    }
+}
+
+//Quantify every sample of the sample sheet against the same index, and write the counts of all samples
+//as one matrix with a summary of each sample
+fn quantify_samples(opt : &args::Opt, fa_col_db : &FaColDB, kmer_hash_table : &DNAHashTable, unitig_graph : Option<&DNAUnitigGraph>) {
+    let samples : Vec<Sample> = read_inputs::read_sample_sheet(opt.read_input_filename.as_str());
+    let trim_options : TrimOptions = trim_options(opt);
+    let umi_source : Option<UmiSource> = umi_source(opt);
+    let gene_map : Option<GeneMap> = opt.gene_output_filename.as_ref().map(|_| {
+        let annotation : Option<GeneAnnotation> = opt.gtf_filename.as_ref()
+            .map(|filename| GeneAnnotation::read_annotation(filename));
+        let tx2gene = opt.tx2gene_filename.as_ref().map(|filename| gene_map::read_tx2gene(filename));
+        GeneMap::new(fa_col_db.target_ids(), &fa_col_db.seg_metadata, tx2gene.as_ref(), annotation.as_ref())
+    });
+    let mut sample_counts : Vec<Vec<f64>> = Vec::new();
+    let mut gene_counts : Vec<Vec<f64>> = Vec::new();
+    let mut sample_summaries : Vec<SampleSummary> = Vec::new();
+
+    for sample in &samples {
+        let mut records : Vec<FqRecord> = read_inputs::read_fq_fasta_records(&sample.read_filename);
+        let mut mate_records : Option<Vec<FqRecord>> = sample.mate_filename.as_ref().map(|filename| read_inputs::read_fq_fasta_records(filename));
+        if let Some(ref umi_source) = umi_source {
+            umi::extract_umis(&mut records, umi_source);
+        }
+        if trim_options.is_enabled() {
+            read_inputs::trim_records(&mut records, mate_records.as_mut(), &trim_options);
+        }
+        //As for a single run, the second reads of pairs only inform the library type
+        let mates : Option<Vec<String>> = mate_records.map(|mate_records| mate_records.into_iter().map(|record| record.seq).collect());
+        let umis : Option<Vec<String>> = umi_source.as_ref().map(|_| records.iter().map(|record| record.umi.clone().unwrap_or_default()).collect());
+        let mut reads : Vec<String> = records.into_iter().map(|record| record.seq).collect();
+        let (library_format_counts, segment_counts, _) = pseudoalign_reads(opt, &mut reads, mates.as_ref(), umis.as_ref(), kmer_hash_table,
+            unitig_graph, fa_col_db.n_targets);
+        let mapping_stats = &segment_counts.mapping_stats;
+        info!("Sample {}: processed {} reads, {} assigned", sample.name, mapping_stats.n_reads, mapping_stats.n_assigned);
+
+        sample_counts.push((0..fa_col_db.n_targets)
+            .map(|i| *segment_counts.counts.get(&(i as i32)).unwrap_or(&0) as f64)
+            .collect());
+        if let Some(ref gene_map) = gene_map {
+            gene_counts.push(gene_map.class_counts(&segment_counts.equivalence_classes));
+        }
+        sample_summaries.push(SampleSummary {
+            name : sample.name.clone(),
+            mapping_stats : mapping_stats.clone(),
+            library_type : library_format_counts.map_or(opt.libtype.code(), |library_format_counts| library_format_counts.library_type.code()),
+        });
+    }
+
+    read_inputs::write_count_matrix(opt.seqcount_output_filename.as_str(), &samples, fa_col_db.target_ids(), &sample_counts);
+    if let (Some(filename), Some(gene_map)) = (&opt.gene_output_filename, &gene_map) {
+        read_inputs::write_count_matrix(filename, &samples, &gene_map.gene_ids, &gene_counts);
+    }
+    if let Some(ref filename) = opt.sample_summary_filename {
+        read_inputs::write_sample_summary(filename, &sample_summaries);
+    }
+}
+
+//Count the molecules of every gene in every cell of a single-cell run
+fn quantify_cells(opt : &args::Opt, r1_filename : &str, fa_col_db : &FaColDB, kmer_hash_table : &DNAHashTable, unitig_graph : Option<&DNAUnitigGraph>) {
+    let mut r1_records : Vec<FqRecord> = read_inputs::read_fq_fasta_records(r1_filename);
+    let mut r2_records : Vec<FqRecord> = read_inputs::read_fq_fasta_records(opt.read_input_filename.as_str());
+    let trim_options : TrimOptions = trim_options(opt);
+    if trim_options.is_enabled() {
+        read_inputs::trim_cdna_reads(&mut r2_records, &mut r1_records, &trim_options);
+    }
+    let r1s : Vec<String> = r1_records.into_iter().map(|record| record.seq).collect();
+    let r2s : Vec<String> = r2_records.into_iter().map(|record| record.seq).collect();
+    let layout = BarcodeLayout { barcode_length : opt.barcode_length, umi_length : opt.umi_length };
+    let whitelist : Whitelist = match opt.whitelist_filename {
+        Some(ref filename) => Whitelist::new(read_inputs::read_whitelist(filename)),
+        None => {
+            let barcode_ranks = BarcodeRanks::new(&r1s, layout);
+            if let Some(ref filename) = opt.whitelist_output_filename {
+                read_inputs::write_whitelist(filename, &barcode_ranks);
+            }
+            if let Some(ref filename) = opt.barcode_ranks_filename {
+                read_inputs::write_barcode_ranks(filename, &barcode_ranks);
+            }
+            barcode_ranks.whitelist()
+        },
+    };
+
+    let annotation : Option<GeneAnnotation> = opt.gtf_filename.as_ref()
+        .map(|filename| GeneAnnotation::read_annotation(filename));
+    let tx2gene = opt.tx2gene_filename.as_ref().map(|filename| gene_map::read_tx2gene(filename));
+    let gene_map = GeneMap::new(fa_col_db.target_ids(), &fa_col_db.seg_metadata, tx2gene.as_ref(), annotation.as_ref());
+
+    let (cell_reads, cell_stats) = match unitig_graph {
+        Some(graph) if opt.unitigs => single_cell::assign_cell_reads(&r1s, &r2s, layout, &whitelist, graph,
+            fa_col_db.n_targets, &gene_map),
+        _ => single_cell::assign_cell_reads(&r1s, &r2s, layout, &whitelist, kmer_hash_table,
+            fa_col_db.n_targets, &gene_map),
+    };
+    let cell_matrix = CellMatrix::new(&cell_reads);
+    read_inputs::write_cell_matrix(opt.seqcount_output_filename.as_str(), &cell_matrix, &gene_map, &fa_col_db.seg_metadata);
+    read_inputs::write_cell_summary(opt.seqcount_output_filename.as_str(), &cell_stats, cell_matrix.barcodes.len());
+}
+
+//Run the subcommand given on the command line in place of a quantification
+fn run_command(command : args::Command) {
+    match command {
+        args::Command::Qc(qc_opt) => {
+            init_logger(qc_opt.verbose);
+            let read_qc = read_qc::qc_reads(qc_opt.read_input_filename.as_str());
+            read_inputs::write_qc_json(qc_opt.json_output_filename.as_str(), qc_opt.read_input_filename.as_str(), &read_qc);
+            read_inputs::write_qc_html(qc_opt.html_output_filename.as_str(), qc_opt.read_input_filename.as_str(), &read_qc);
+        },
+        args::Command::KmerCount(kmer_count_opt) => {
+            init_logger(kmer_count_opt.verbose);
+            count_kmers(&kmer_count_opt);
+        },
+        args::Command::Simulate(simulate_opt) => {
+            init_logger(simulate_opt.verbose);
+            simulate_reads(&simulate_opt);
+        },
+        args::Command::Eval(eval_opt) => {
+            init_logger(eval_opt.verbose);
+            evaluate(&eval_opt);
+        },
+        args::Command::Classify(classify_opt) => {
+            init_logger(classify_opt.verbose);
+            classify_reads(&classify_opt);
+        },
+    }
+}
+
+//Count the k-mers of the reads or references, write those seen often enough and the k-mer spectrum
+fn count_kmers(opt : &args::KmerCountOpt) {
+    let sequences : Vec<String> = if opt.references {
+        read_inputs::read_fa_file_to_cols(opt.input_filename.as_str()).seg_strings
+    } else {
+        read_inputs::read_fq_fasta_records(opt.input_filename.as_str()).into_iter().map(|record| record.seq).collect()
+    };
+    let counts : Vec<(String, usize)> = kmer_count::count_kmers(&sequences, opt.k, opt.canonical);
+    read_inputs::write_kmer_counts(opt.kmer_output_filename.as_str(), opt.format, opt.k, opt.canonical, &counts, opt.min_count);
+
+    let histogram = kmer_count::kmer_histogram(&counts);
+    if let Some((coverage, genome_size)) = kmer_count::estimate_genome_size(&histogram) {
+        info!("Estimated k-mer coverage {} and genome size {}", coverage, genome_size);
+    }
+    if let Some(ref filename) = opt.histogram_output_filename {
+        read_inputs::write_kmer_histogram(filename, &histogram);
+    }
+}
+
+//Simulate reads from the references at the abundances of the table, and write them with their true origins
+fn simulate_reads(opt : &args::SimulateOpt) {
+    let mut fa_col_db = read_inputs::read_fa_file_to_cols(opt.seq_input_filename.as_str());
+    if opt.gencode {
+        fa_col_db.use_transcript_ids();
+    }
+    let abundance_table : HashMap<String, f64> = read_inputs::read_abundance_table(opt.abundance_filename.as_str());
+    let n_unknown : usize = abundance_table.keys().filter(|id| !fa_col_db.seg_ids.contains(id)).count();
+    if n_unknown > 0 {
+        warn!("{} targets of the abundance table are not among the sequence inputs", n_unknown);
+    }
+    let abundances : Vec<f64> = fa_col_db.seg_ids.iter().map(|id| abundance_table.get(id).cloned().unwrap_or(0.0)).collect();
+
+    let library_type : LibraryType = opt.libtype;
+    if library_type.orientation.is_some() != opt.mates_output_filename.is_some() {
+        panic!("Paired-end library types need a --mates file, and single-end ones cannot have one");
+    }
+    let fragment_model = FragmentModel { mean_length : opt.fragment_mean, sd_length : opt.fragment_sd, read_length : opt.read_length };
+    let error_model = ErrorModel { substitution_rate : opt.substitution_rate, insertion_rate : opt.insertion_rate, deletion_rate : opt.deletion_rate };
+    let simulated_reads = simulate::simulate_reads(&fa_col_db.seg_strings, &abundances, opt.n_reads, fragment_model, error_model,
+        library_type, opt.seed);
+
+    read_inputs::write_fq_records(opt.read_output_filename.as_str(), simulated_reads.iter().map(|simulated_read| &simulated_read.record));
+    if let Some(ref filename) = opt.mates_output_filename {
+        read_inputs::write_fq_records(filename, simulated_reads.iter().filter_map(|simulated_read| simulated_read.mate.as_ref()));
+    }
+    read_inputs::write_truth_table(opt.truth_output_filename.as_str(), &simulated_reads, &fa_col_db.seg_ids);
+    if let Some(ref filename) = opt.truth_counts_filename {
+        read_inputs::write_truth_counts(filename, &simulated_reads, &abundances, &fa_col_db.seg_ids, &fa_col_db.seg_strings);
+    }
+}
+
+//Compare a quantification, and the read assignments behind it when given, to the truth
+fn evaluate(opt : &args::EvalOpt) {
+    let estimated : HashMap<String, f64> = read_inputs::read_quant_column(opt.quant_filename.as_str(), opt.column.as_str());
+    let truth : HashMap<String, f64> = read_inputs::read_quant_column(opt.truth_filename.as_str(), opt.column.as_str());
+    let comparison = evaluate::compare_abundances(&estimated, &truth);
+    info!("Compared {} targets: Pearson {:.4}, Spearman {:.4}, MARD {:.4}", comparison.n_targets, comparison.pearson,
+        comparison.spearman, comparison.mard);
+
+    let assignments : Option<HashMap<String, Vec<String>>> = match (opt.sam_filename.as_ref(), opt.assignments_filename.as_ref()) {
+        (Some(filename), _) => Some(read_inputs::read_sam_assignments(filename)),
+        (_, Some(filename)) => Some(read_inputs::read_assignment_table(filename)),
+        _ => None,
+    };
+    let accuracy = match (assignments, opt.read_truth_filename.as_ref()) {
+        (Some(assignments), Some(read_truth_filename)) => {
+            let accuracy = evaluate::assignment_accuracy(&assignments, &read_inputs::read_truth_table(read_truth_filename));
+            info!("Assigned {} of {} reads: precision {:.4}, recall {:.4}", accuracy.n_assigned, accuracy.n_reads,
+                accuracy.precision(), accuracy.recall());
+            Some(accuracy)
+        },
+        (None, Some(_)) => {
+            warn!("Read-level truth needs --sam or --assignments to compare against");
+            None
+        },
+        _ => None,
+    };
+
+    if let Some(ref filename) = opt.json_output_filename {
+        read_inputs::write_eval_report(filename, &comparison, accuracy.as_ref());
+    }
+}
+
+//Classify the reads against the taxa of the sequence inputs, and write the reads of every taxon
+fn classify_reads(opt : &args::ClassifyOpt) {
+    let mut fa_col_db = read_inputs::read_fa_file_to_cols(opt.seq_input_filename.as_str());
+    if let Some(ref filename) = opt.seqid2taxid_filename {
+        fa_col_db.set_taxon_ids(&read_inputs::read_seqid2taxid(filename));
+    }
+    let mut taxonomy = Taxonomy::new(read_inputs::read_taxonomy(opt.taxonomy_filename.as_str()));
+    if let Some(ref filename) = opt.taxonomy_names_filename {
+        taxonomy.set_names(read_inputs::read_taxon_names(filename));
+    }
+    let seg_taxa : Vec<Option<&str>> = fa_col_db.taxon_ids();
+    let n_unlabelled : usize = seg_taxa.iter().filter(|taxon_id| taxon_id.is_none()).count();
+    if n_unlabelled > 0 {
+        warn!("{} sequences have no taxon and are left out of classification", n_unlabelled);
+    }
+    let n_unknown : usize = seg_taxa.iter().filter(|taxon_id| taxon_id.is_some_and(|taxon_id| !taxonomy.contains(taxon_id))).count();
+    if n_unknown > 0 {
+        warn!("{} sequences have taxa missing from the taxonomy", n_unknown);
+    }
+
+    let kmer_hash_table = DNAHashTable::new(&fa_col_db.seg_strings, opt.k);
+    let records : Vec<FqRecord> = read_inputs::read_fq_fasta_records(opt.read_input_filename.as_str());
+    let reads : Vec<String> = records.iter().map(|record| record.seq.clone()).collect();
+    let (classifications, taxon_counts) = taxonomy::classify_reads(&reads, &kmer_hash_table, &seg_taxa, &taxonomy);
+
+    let clade_counts = taxonomy::clade_counts(&taxon_counts, &taxonomy);
+    read_inputs::write_taxon_report(opt.report_output_filename.as_str(), &taxon_counts, &clade_counts, &taxonomy);
+    if let Some(ref filename) = opt.classifications_filename {
+        read_inputs::write_read_classifications(filename, &records, &classifications);
+    }
+}
+
+fn init_logger(verbose : bool) {
+    if verbose {
         
         let env = Env::default()
            .filter_or("MY_LOG_LEVEL", "trace")
@@ -38,25 +608,34 @@ fn main() {
 
         env_logger::init_from_env(env);
     }
+}
 
-    let fa_col_db = read_inputs::read_fa_file_to_cols(opt.seq_input_filename.as_str());
-
-    for s in &fa_col_db.seg_ids {
-        debug!("Read in {}", s);
+fn umi_source(opt : &args::Opt) -> Option<UmiSource> {
+    match (&opt.umi_pattern, opt.umi_from_name) {
+        (Some(pattern), _) => Some(UmiSource::Pattern(pattern.clone())),
+        (None, Some(delimiter)) => Some(UmiSource::ReadName(delimiter)),
+        (None, None) => None,
     }
-    let kmer_hash_table = DNAHashTable::new(&fa_col_db.seg_strings, opt.k);
-    let mut reads2 : Vec<String> = read_inputs::read_fq_fasta_file(opt.read_input_filename.as_str());
-
-    let mut global_map = perform_map_reduce(opt.n_partition, &mut reads2, &kmer_hash_table);
+}
 
-    read_inputs::write_output4(opt.seqcount_output_filename.as_str(), &mut global_map, fa_col_db.seg_ids);
+fn trim_options(opt : &args::Opt) -> TrimOptions {
+    TrimOptions {
+        adapter : opt.adapter.clone(),
+        detect_adapter : opt.detect_adapter,
+        poly_a : opt.trim_poly_a,
+        quality_cutoff : opt.quality_cutoff,
+        min_length : opt.min_read_length,
+    }
 }
 
-fn perform_map_reduce(n_partition : usize, 
+fn perform_map_reduce<P : Pseudoaligner + Sync>(n_partition : usize, 
         reads : &mut Vec<String>, 
-        kmer_hash_table : &DNAHashTable) -> HashMap<i32, i32> {
+        pseudoaligner : &P,
+        n_targets : usize,
+        keep_outcomes : bool) -> (SegmentCounts, Vec<f64>) {
 
-    let ratio : usize = (reads.len() / n_partition) as usize;
+    //Screening or trimming can leave fewer reads than partitions, or none at all
+    let ratio : usize = (reads.len() / n_partition).max(1);
 
     let mut partitions :Vec<Vec<String>> = Vec::new();
     let mut i = 0;
@@ -71,25 +650,59 @@ fn perform_map_reduce(n_partition : usize,
 
     i = 0;
     let partitions_map = partitions.par_iter()
-        .map(|ref chunk| dna_hash_table::get_segments(&kmer_hash_table, &chunk)); 
-    let comp_result : Vec<HashMap<i32, i32>> = partitions_map.collect();
+        .map(|chunk| {
+            let partition_start = Instant::now();
+            let segment_counts = dna_hash_table::get_segments(pseudoaligner, chunk, n_targets, keep_outcomes);
+            (segment_counts, meta_info::seconds(partition_start))
+        });
+    let comp_result : Vec<(SegmentCounts, f64)> = partitions_map.collect();
 
-    let mut global_map : HashMap<i32, i32> = HashMap::new();
+    let mut global_counts = SegmentCounts::default();
+    let mut partition_seconds : Vec<f64> = Vec::new();
 
     debug!("Length {}", comp_result.len());
-    for p in comp_result.iter() {
-        for (k, v) in p.iter() {
+    for (p, seconds) in comp_result {
+        for (k, v) in p.counts.iter() {
             debug!("Partition {} has {} {}", i, k, v);
-
-            let cnt = global_map.entry(*k).or_insert(0);
-            *cnt += *v;
         }
+        global_counts.add(p);
+        partition_seconds.push(seconds);
         i = i + 1;
     }
 
-    return global_map
-} 
+    (global_counts, partition_seconds)
+}
+
+//Run a single pass over all reads, timed as one partition
+fn time<F : FnOnce() -> SegmentCounts>(f : F) -> (SegmentCounts, Vec<f64>) {
+    let start = Instant::now();
+    let segment_counts = f();
+    (segment_counts, vec![meta_info::seconds(start)])
+}
 
+fn align_reads<A : ReadAligner + Sync>(records : &[FqRecord], aligner : &A) -> Vec<Vec<Alignment>> {
+    records.par_iter()
+        .map(|record| aligner.align(&record.seq))
+        .collect()
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn map_reduce_counts_fewer_reads_than_partitions() {
+        let segments : Vec<String> = vec!["CCGTAATGCCTTTCCCTAACAGAG".to_string()];
+        let kmer_hash_table = DNAHashTable::new(&segments, 8);
 
+        let (segment_counts, partition_seconds) = perform_map_reduce(4, &mut Vec::new(), &kmer_hash_table, 1, false);
+        assert!(segment_counts.counts.is_empty());
+        assert!(partition_seconds.is_empty());
+
+        let mut reads : Vec<String> = vec!["CCGTAATGCCTTTCCC".to_string(), "GGGGGGGGGGGG".to_string()];
+        let (segment_counts, _) = perform_map_reduce(4, &mut reads, &kmer_hash_table, 1, true);
+        assert_eq!(segment_counts.counts.get(&0), Some(&1));
+        assert_eq!(segment_counts.mapping_stats.n_reads, 2);
+        assert_eq!(segment_counts.outcomes.len(), 2);
+    }
+}
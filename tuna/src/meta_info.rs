@@ -0,0 +1,160 @@
+use std::time::Instant;
+
+use dna_hash_table::SegmentCounts;
+use library_type::LibraryFormatCounts;
+use read_inputs::TrimStats;
+
+//FNV-1a offset basis and prime for 64-bit hashes
+const FNV_OFFSET : u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME : u64 = 0x0100_0000_01b3;
+
+//What a run was asked to do and how it went, written out as meta_info.json
+pub struct MetaInfo {
+	pub version : String,
+	pub command_line : String,
+	pub k : usize,
+	pub index_checksum : String,
+	pub n_reads : usize,
+	pub n_assigned : usize,
+	pub n_no_hits : usize,
+	pub n_decoy : usize,
+	pub n_multimapping : usize,
+	pub partition_seconds : Vec<f64>,
+	pub library_format_counts : Option<LibraryFormatCounts>,
+	pub trim_stats : Option<TrimStats>,
+	pub wall_seconds : f64,
+	pub cpu_seconds : Option<f64>,
+}
+
+//What a quantification run produced, to be summarised in its MetaInfo
+pub struct QuantRun<'a> {
+	pub k : usize,
+	pub seg_ids : &'a [String],
+	pub seg_strings : &'a [String],
+	pub segment_counts : &'a SegmentCounts,
+	pub partition_seconds : Vec<f64>,
+	pub library_format_counts : Option<LibraryFormatCounts>,
+	pub trim_stats : Option<TrimStats>,
+	pub start_time : Instant,
+}
+
+impl MetaInfo {
+	pub fn new(run : QuantRun) -> MetaInfo {
+		let mapping_stats = &run.segment_counts.mapping_stats;
+		MetaInfo {
+			version : env!("CARGO_PKG_VERSION").to_string(),
+			command_line : std::env::args().collect::<Vec<String>>().join(" "),
+			k : run.k,
+			index_checksum : format!("{:016x}", index_checksum(run.seg_ids, run.seg_strings)),
+			n_reads : mapping_stats.n_reads,
+			n_assigned : mapping_stats.n_assigned,
+			n_no_hits : mapping_stats.n_no_hits,
+			n_decoy : mapping_stats.n_decoy,
+			n_multimapping : run.segment_counts.equivalence_classes.iter()
+				.filter(|&(targets, _)| targets.len() > 1)
+				.map(|(_, &count)| count)
+				.sum(),
+			partition_seconds : run.partition_seconds,
+			library_format_counts : run.library_format_counts,
+			trim_stats : run.trim_stats,
+			wall_seconds : seconds(run.start_time),
+			cpu_seconds : cpu_seconds(),
+		}
+	}
+
+	//Fraction of the assigned reads that are shared between more than one target
+	pub fn multimapping_rate(&self) -> f64 {
+		if self.n_assigned > 0 { self.n_multimapping as f64 / self.n_assigned as f64 } else { 0.0 }
+	}
+}
+
+//FNV-1a hash of every indexed ID and sequence, so that runs against the same index can be recognised
+pub fn index_checksum(seg_ids : &[String], seg_strings : &[String]) -> u64 {
+	let mut hash : u64 = FNV_OFFSET;
+	for (seg_id, seg_string) in seg_ids.iter().zip(seg_strings.iter()) {
+		for &byte in seg_id.as_bytes().iter().chain(b"\n").chain(seg_string.as_bytes()).chain(b"\n") {
+			hash ^= byte as u64;
+			hash = hash.wrapping_mul(FNV_PRIME);
+		}
+	}
+	hash
+}
+
+pub fn seconds(start_time : Instant) -> f64 {
+	let elapsed = start_time.elapsed();
+	elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+//Clock ticks per second that /proc reports CPU times in (USER_HZ), which Linux fixes at 100 on every
+//architecture it exposes to user space
+const CLOCK_TICKS : f64 = 100.0;
+
+//User and system CPU time used by the process so far, read from /proc where the platform has it
+#[cfg(target_os = "linux")]
+fn cpu_seconds() -> Option<f64> {
+	let stat : String = std::fs::read_to_string("/proc/self/stat").ok()?;
+	parse_cpu_seconds(&stat)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_seconds() -> Option<f64> {
+	None
+}
+
+//The utime and stime fields (14 and 15) of a /proc/<pid>/stat line. The command name in field 2 is
+//in parentheses and may itself contain spaces, so fields are counted from its closing parenthesis.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_seconds(stat : &str) -> Option<f64> {
+	let fields : Vec<&str> = stat[(stat.rfind(')')? + 1)..].split_whitespace().collect();
+	let utime : u64 = fields.get(11)?.parse().ok()?;
+	let stime : u64 = fields.get(12)?.parse().ok()?;
+	Some((utime + stime) as f64 / CLOCK_TICKS)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn checksum_depends_on_ids_and_sequences() {
+		let ids : Vec<String> = vec!["a".to_string(), "b".to_string()];
+		let seqs : Vec<String> = vec!["ACGT".to_string(), "GG".to_string()];
+		let renamed : Vec<String> = vec!["a".to_string(), "c".to_string()];
+		assert_eq!(index_checksum(&ids, &seqs), index_checksum(&ids, &seqs));
+		assert_ne!(index_checksum(&ids, &seqs), index_checksum(&renamed, &seqs));
+		assert_ne!(index_checksum(&ids[..1], &seqs[..1]), index_checksum(&ids, &seqs));
+		assert_eq!(index_checksum(&[], &[]), FNV_OFFSET);
+	}
+
+	#[test]
+	fn parses_cpu_ticks_after_the_command_name() {
+		let stat : &str = "4242 (tuna (x) y) R 1 4242 4242 0 -1 4194304 100 0 0 0 250 50 0 0 20 0 8 0 1000 0 0";
+		assert_eq!(parse_cpu_seconds(stat), Some(3.0));
+		assert_eq!(parse_cpu_seconds("4242 (tuna) R 1"), None);
+		assert_eq!(parse_cpu_seconds("no parenthesis"), None);
+	}
+
+	#[test]
+	fn summarises_a_run() {
+		let mut segment_counts = SegmentCounts::default();
+		segment_counts.mapping_stats.n_reads = 10;
+		segment_counts.mapping_stats.n_assigned = 8;
+		segment_counts.equivalence_classes.insert(vec![0], 6);
+		segment_counts.equivalence_classes.insert(vec![0, 1], 2);
+		let seg_ids : Vec<String> = vec!["a".to_string(), "b".to_string()];
+		let meta_info = MetaInfo::new(QuantRun {
+			k : 21,
+			seg_ids : &seg_ids,
+			seg_strings : &seg_ids,
+			segment_counts : &segment_counts,
+			partition_seconds : vec![0.5],
+			library_format_counts : None,
+			trim_stats : None,
+			start_time : Instant::now(),
+		});
+		assert_eq!(meta_info.k, 21);
+		assert_eq!(meta_info.n_multimapping, 2);
+		assert_eq!(meta_info.multimapping_rate(), 0.25);
+		assert_eq!(meta_info.version, env!("CARGO_PKG_VERSION"));
+	}
+}
@@ -2,10 +2,61 @@
 use std::fs::File;
 use std::io::Write;
 use std::io::LineWriter;
+use std::io::BufWriter;
 use std::io::BufReader;
+use std::io::Lines;
 use std::io::prelude::*;
 use log::*;
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use dna_unitig_graph::DNAUnitigGraph;
+use gene_annotation::GeneAnnotation;
+use gene_annotation::FeatureCounts;
+use gene_map::GeneMap;
+use abundance::Abundance;
+use fa_metadata::SegMetadata;
+use duplicate_segments::SegmentCollapse;
+use library_type::LibraryFormatCounts;
+use meta_info::MetaInfo;
+use dna_hash_table::MappingStats;
+use single_cell::CellMatrix;
+use single_cell::BarcodeRanks;
+use single_cell::CellStats;
+use count_output::OutputFormat;
+use count_output::SegmentRow;
+use count_output::json_string;
+use count_output::new_count_writer;
+use read_qc::ReadQc;
+use read_qc::BASES;
+use read_qc::svg_chart;
+use kmer_count::KmerFormat;
+use simulate::SimulatedRead;
+use evaluate::AbundanceComparison;
+use evaluate::AssignmentAccuracy;
+use contaminants::ContaminantDB;
+use contaminants::ContaminantStats;
+use taxonomy::Taxonomy;
+use taxonomy::TaxonCounts;
+use dna_hash_table::ReadOutcome;
+
+//Adapters checked for first when detecting the adapter of a run: Illumina TruSeq, Nextera and small RNA
+const KNOWN_ADAPTERS : [&str; 3] = ["AGATCGGAAGAGC", "CTGTCTCTTATACACATCT", "TGGAATTCTCGGGTGCCAAGG"];
+//Fraction of sampled reads an adapter or k-mer must occur in to be taken as the adapter of the run
+const ADAPTER_MIN_FRACTION : f64 = 0.01;
+//Fraction of the reads holding an overrepresented k-mer that must hold it in their 3' half, as reads run
+//into adapters at their 3' ends while sequence of the reads themselves is spread along them
+const ADAPTER_MIN_3P_FRACTION : f64 = 0.8;
+const ADAPTER_SAMPLE : usize = 10000;
+const ADAPTER_SEED_LENGTH : usize = 12;
+const ADAPTER_MAX_LENGTH : usize = 32;
+//Shortest overlap of an adapter with the 3' end of a read that is trimmed
+const ADAPTER_MIN_OVERLAP : usize = 3;
+//Fraction of the bases of an adapter overlap allowed to mismatch
+const ADAPTER_MISMATCH_RATE : f64 = 0.1;
+//Shortest run of A or T at the 3' end of a read that is trimmed as a poly-A tail
+const POLY_A_MIN_LENGTH : usize = 8;
+const QUALITY_WINDOW : usize = 4;
+const PHRED_OFFSET : u8 = 33;
 
 // pub struct FaEntry {
 //     pub seg_id : String,
@@ -14,7 +65,83 @@ use std::collections::HashMap;
 
 pub struct FaColDB {
 	pub seg_ids : Vec<String>,
-	pub seg_strings : Vec<String>
+	pub seg_strings : Vec<String>,
+	pub seg_metadata : Vec<SegMetadata>,
+	// segments from this index on are decoys
+	pub n_targets : usize
+}
+
+impl FaColDB {
+	// keep only the segments at the given indexes, in order
+	pub fn retain_segments(&mut self, indexes : &[usize]) {
+		self.seg_ids = indexes.iter().map(|&i| self.seg_ids[i].clone()).collect();
+		self.seg_strings = indexes.iter().map(|&i| self.seg_strings[i].clone()).collect();
+		self.seg_metadata = indexes.iter().map(|&i| self.seg_metadata[i].clone()).collect();
+		self.n_targets = indexes.iter().filter(|&&i| i < self.n_targets).count();
+	}
+
+	// add the segments of another database after these ones as decoys
+	pub fn append_decoys(&mut self, decoys : FaColDB) {
+		self.seg_ids.extend(decoys.seg_ids);
+		self.seg_strings.extend(decoys.seg_strings);
+		self.seg_metadata.extend(decoys.seg_metadata);
+	}
+
+	pub fn target_ids(&self) -> &[String] {
+		&self.seg_ids[..self.n_targets]
+	}
+
+	pub fn target_strings(&self) -> &[String] {
+		&self.seg_strings[..self.n_targets]
+	}
+
+	// label segments with the taxa of a sequence ID to taxon ID map, over any taxid of their headers
+	pub fn set_taxon_ids(&mut self, taxon_ids : &HashMap<String, String>) {
+		for (seg_id, metadata) in self.seg_ids.iter().zip(self.seg_metadata.iter_mut()) {
+			if let Some(taxon_id) = taxon_ids.get(seg_id) {
+				metadata.set("taxid", taxon_id);
+			}
+		}
+	}
+
+	pub fn taxon_ids(&self) -> Vec<Option<&str>> {
+		self.seg_metadata.iter().map(|metadata| metadata.taxon_id()).collect()
+	}
+
+	// replace pipe-delimited IDs such as GENCODE headers with the transcript ID they start with
+	pub fn use_transcript_ids(&mut self) {
+		for (seg_id, metadata) in self.seg_ids.iter_mut().zip(self.seg_metadata.iter()) {
+			if let Some(transcript_id) = metadata.get("transcript_id") {
+				*seg_id = transcript_id.to_string();
+			}
+		}
+	}
+}
+
+//A single read, along with its quality string when it came from a fq file
+#[derive(Clone, Debug)]
+pub struct FqRecord {
+	pub name : String,
+	pub seq : String,
+	pub qual : Option<String>,
+	pub umi : Option<String>,
+}
+
+//A sample of a sample sheet: its name, its read file and, for paired-end samples, the file of the
+//second reads of its pairs
+#[derive(Clone, Debug)]
+pub struct Sample {
+	pub name : String,
+	pub read_filename : String,
+	pub mate_filename : Option<String>,
+}
+
+//What became of the reads of one sample of a sample sheet
+#[derive(Clone, Debug)]
+pub struct SampleSummary {
+	pub name : String,
+	pub mapping_stats : MappingStats,
+	pub library_type : String,
 }
 
 // impl FaEntry {
@@ -46,37 +173,369 @@ pub fn is_char_header(x : char) -> bool {
 }
 
 /**
-	read_fq_fasta_file:
-	Given the name of a fq or fasta file, read it into a vector of strings consisting of
-	the sequences
+    read_sample_sheet:
+    Given the name of a sample sheet, read every line of a sample name followed by its
+    tab- or comma-separated read file, and the file of second reads for paired-end
+    samples. Relative paths are taken from the directory of the sheet.
 */
-pub fn read_fq_fasta_file(filename : &str) -> Vec<String> {
+pub fn read_sample_sheet(filename : &str) -> Vec<Sample> {
 	let f = File::open(filename).expect("file not found");
-	let mut fq_strings : Vec<String> = Vec::new();
-	let mut current : String = String::new();
+	let sheet_dir = std::path::Path::new(filename).parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+	let mut samples : Vec<Sample> = Vec::new();
+
 	for line in BufReader::new(f).lines() {
 		let line_val = line.expect("line not available");
-		let first_char = line_val.chars().next().unwrap();
-		// println!("{}", line_val);
-		// println!("{}", first_char == 'a');
+		if line_val.trim().is_empty() || line_val.starts_with('#') {
+			continue;
+		}
+		let fields : Vec<&str> = line_val.split(['\t', ',']).map(|field| field.trim()).filter(|field| !field.is_empty()).collect();
+		if fields.len() < 2 {
+			warn!("Skipping sample sheet line without read files: {}", line_val);
+			continue;
+		}
+		if fields.len() > 3 {
+			panic!("Sample {} has {} read files, but a sample has one, or two for paired-end reads", fields[0], fields.len() - 1);
+		}
+		let sheet_path = |path : &str| sheet_dir.join(path).to_string_lossy().into_owned();
+		samples.push(Sample {
+			name : fields[0].to_string(),
+			read_filename : sheet_path(fields[1]),
+			mate_filename : fields.get(2).map(|path| sheet_path(path)),
+		});
+	}
+	info!("Read {} samples from the sample sheet", samples.len());
+	samples
+}
 
-		if first_char == '!' || first_char == '@' || first_char == '>' {
-			// process new string
-			if current != "" {
-				fq_strings.push(current);
-				current = String::new();
+/**
+    read_fq_fasta_records:
+    Given the name of a fq or fasta file, read it into a vector of records consisting of
+    the read names, sequences and (for fq files) qualities
+*/
+pub fn read_fq_fasta_records(filename : &str) -> Vec<FqRecord> {
+	FqRecords::open(filename).collect()
+}
+
+//Streams the records of a fq or fasta file one at a time. Every letter of a sequence is kept, upper-cased,
+//and any other character becomes N, so that the bases always line up with the qualities. Records without
+//a name are named after their position in the file.
+pub struct FqRecords<R : BufRead> {
+	lines : Lines<R>,
+	current : Option<FqRecord>,
+	in_quality : bool,
+	n_records : usize,
+}
+
+impl FqRecords<BufReader<File>> {
+	pub fn open(filename : &str) -> FqRecords<BufReader<File>> {
+		let f = File::open(filename).expect("file not found");
+		FqRecords::new(BufReader::new(f))
+	}
+}
+
+impl<R : BufRead> FqRecords<R> {
+	pub fn new(reader : R) -> FqRecords<R> {
+		FqRecords { lines : reader.lines(), current : None, in_quality : false, n_records : 0 }
+	}
+
+	fn next_record(&mut self) -> Option<FqRecord> {
+		for line in self.lines.by_ref() {
+			let line_val = line.expect("line not available");
+			let first_char = match line_val.chars().next() {
+				Some(first_char) => first_char,
+				None => continue,
+			};
+
+			if self.in_quality {
+				// quality lines may start with header characters, so only stop once the quality is complete
+				let record = self.current.as_mut().expect("quality without a record");
+				let qual = record.qual.get_or_insert_with(String::new);
+				qual.push_str(line_val.trim_end());
+				self.in_quality = qual.len() < record.seq.len();
+				if !self.in_quality {
+					return self.current.take();
+				}
+			} else if is_char_header(first_char) {
+				// process new string
+				let name : String = line_val.split_whitespace().next().expect("no word in header")
+					.chars().filter(|x| !is_char_header(*x)).collect();
+				let previous = self.current.replace(FqRecord { name, seq : String::new(), qual : None, umi : None });
+				if let Some(previous) = previous.filter(|record| !record.seq.is_empty()) {
+					return Some(previous);
+				}
+			} else if first_char == '+' && self.current.is_some() {
+				// a record without a sequence has no quality line to wait for
+				self.in_quality = self.current.as_ref().is_some_and(|record| !record.seq.is_empty());
+				if !self.in_quality {
+					self.current = None;
+				}
+			} else {
+				let mapped : String = line_val.chars()
+					.filter(|x| !x.is_whitespace())
+					.map(|x| if x.is_ascii_alphabetic() { x.to_ascii_uppercase() } else { 'N' })
+					.collect();
+				match self.current.as_mut() {
+					Some(record) => record.seq.push_str(mapped.as_str()),
+					None => self.current = Some(FqRecord { name : String::new(), seq : mapped, qual : None, umi : None }),
+				}
 			}
-			continue; // skip
+		}
+
+		self.current.take().filter(|record| !record.seq.is_empty())
+	}
+}
+
+impl<R : BufRead> Iterator for FqRecords<R> {
+	type Item = FqRecord;
+
+	fn next(&mut self) -> Option<FqRecord> {
+		let mut record : FqRecord = self.next_record()?;
+		self.n_records += 1;
+		if record.name.is_empty() {
+			record.name = format!("read{}", self.n_records);
+		}
+		Some(record)
+	}
+}
+
+//Which trimming steps to apply to the reads before they are pseudoaligned
+#[derive(Clone, Debug, Default)]
+pub struct TrimOptions {
+	pub adapter : Option<String>,
+	pub detect_adapter : bool,
+	pub poly_a : bool,
+	pub quality_cutoff : Option<u8>,
+	pub min_length : usize,
+}
+
+impl TrimOptions {
+	pub fn is_enabled(&self) -> bool {
+		self.adapter.is_some() || self.detect_adapter || self.poly_a || self.quality_cutoff.is_some() || self.min_length > 0
+	}
+}
+
+//What trimming did to the reads of a run. For paired-end runs, reads and reads too short count pairs,
+//while the trimming steps count the reads of both files they trimmed.
+#[derive(Clone, Debug, Default)]
+pub struct TrimStats {
+	pub adapter : Option<String>,
+	pub n_reads : usize,
+	pub n_adapter_trimmed : usize,
+	pub n_poly_a_trimmed : usize,
+	pub n_quality_trimmed : usize,
+	pub n_too_short : usize,
+	pub n_bases_removed : usize,
+}
+
+/**
+    trim_records:
+    Trim the 3' adapter, poly-A tail and low-quality tail of every record, in that order,
+    and drop records left shorter than the minimum length. The second reads of pairs, when
+    given, are trimmed alike, with an adapter detected from them alone, and a pair is
+    dropped if either read is too short.
+*/
+pub fn trim_records(records : &mut Vec<FqRecord>, mates : Option<&mut Vec<FqRecord>>, trim_options : &TrimOptions) -> TrimStats {
+	trim_pairs(records, mates, true, trim_options)
+}
+
+/**
+    trim_cdna_reads:
+    Trim the cDNA reads (R2) of a single-cell run as trim_records does, and drop the R1 of
+    every pair whose cDNA read is dropped. R1s are not trimmed, as they hold the cell
+    barcode and UMI.
+*/
+pub fn trim_cdna_reads(records : &mut Vec<FqRecord>, barcode_reads : &mut Vec<FqRecord>, trim_options : &TrimOptions) -> TrimStats {
+	trim_pairs(records, Some(barcode_reads), false, trim_options)
+}
+
+//Trim the records, and the mates too if asked, keeping the mates in step with the records left
+fn trim_pairs(records : &mut Vec<FqRecord>, mates : Option<&mut Vec<FqRecord>>, trim_mates : bool, trim_options : &TrimOptions) -> TrimStats {
+	let adapter : Option<String> = trim_options.adapter.clone().or_else(|| {
+		if trim_options.detect_adapter { detect_adapter(records) } else { None }
+	});
+	let mut trim_stats = TrimStats { adapter : adapter.clone(), n_reads : records.len(), ..TrimStats::default() };
+	for record in records.iter_mut() {
+		trim_record(record, adapter.as_deref(), trim_options, &mut trim_stats);
+	}
+	let mut keep : Vec<bool> = records.iter().map(|record| record.seq.len() >= trim_options.min_length.max(1)).collect();
+
+	if let Some(mates) = mates {
+		if mates.len() != records.len() {
+			panic!("The reads have {} records but their mates {}", records.len(), mates.len());
+		}
+		if trim_mates {
+			let mate_adapter : Option<String> = trim_options.adapter.clone().or_else(|| {
+				if trim_options.detect_adapter { detect_adapter(mates) } else { None }
+			});
+			for (mate, keep) in mates.iter_mut().zip(keep.iter_mut()) {
+				trim_record(mate, mate_adapter.as_deref(), trim_options, &mut trim_stats);
+				*keep = *keep && mate.seq.len() >= trim_options.min_length.max(1);
+			}
+		}
+		let mut keep_mates = keep.iter();
+		mates.retain(|_| *keep_mates.next().unwrap());
+	}
+	let mut keep_records = keep.iter();
+	records.retain(|_| *keep_records.next().unwrap());
+	trim_stats.n_too_short = trim_stats.n_reads - records.len();
+
+	info!("Trimmed {} reads: {} adapters, {} poly-A tails, {} low-quality tails, {} bases removed, {} reads too short",
+		trim_stats.n_reads, trim_stats.n_adapter_trimmed, trim_stats.n_poly_a_trimmed, trim_stats.n_quality_trimmed,
+		trim_stats.n_bases_removed, trim_stats.n_too_short);
+	trim_stats
+}
+
+fn trim_record(record : &mut FqRecord, adapter : Option<&str>, trim_options : &TrimOptions, trim_stats : &mut TrimStats) {
+	let original_length : usize = record.seq.len();
+	if let Some(adapter) = adapter {
+		if let Some(position) = find_adapter(&record.seq, adapter) {
+			truncate_record(record, position);
+			trim_stats.n_adapter_trimmed += 1;
+		}
+	}
+	if trim_options.poly_a {
+		let tail : usize = poly_a_length(&record.seq);
+		if tail >= POLY_A_MIN_LENGTH {
+			let length : usize = record.seq.len() - tail;
+			truncate_record(record, length);
+			trim_stats.n_poly_a_trimmed += 1;
+		}
+	}
+	if let (Some(cutoff), Some(qual)) = (trim_options.quality_cutoff, record.qual.as_ref()) {
+		let length : usize = quality_trim_length(qual, cutoff);
+		if length < record.seq.len() {
+			truncate_record(record, length);
+			trim_stats.n_quality_trimmed += 1;
+		}
+	}
+	trim_stats.n_bases_removed += original_length - record.seq.len();
+}
+
+fn truncate_record(record : &mut FqRecord, length : usize) {
+	record.seq.truncate(length);
+	if let Some(ref mut qual) = record.qual {
+		qual.truncate(length);
+	}
+}
+
+/**
+    detect_adapter:
+    Look for a known adapter in a sample of the reads, and failing that extend the most
+    overrepresented k-mer found mostly in the 3' half of the reads base by base in either
+    direction, for as long as most of its occurrences agree
+*/
+fn detect_adapter(records : &[FqRecord]) -> Option<String> {
+	let sample : Vec<&str> = records.iter().take(ADAPTER_SAMPLE).map(|record| record.seq.as_str()).collect();
+	let min_count : usize = ((sample.len() as f64 * ADAPTER_MIN_FRACTION).ceil() as usize).max(2);
+
+	for adapter in KNOWN_ADAPTERS.iter() {
+		if sample.iter().filter(|seq| seq.contains(adapter)).count() >= min_count {
+			info!("Detected known adapter {}", adapter);
+			return Some(adapter.to_string());
+		}
+	}
+
+	//Reads holding each k-mer, and how many of them first hold it in their 3' half
+	let mut seed_counts : HashMap<&str, (usize, usize)> = HashMap::new();
+	for seq in &sample {
+		let mut seen : Vec<(&str, bool)> = Vec::new();
+		for i in 0..seq.len().saturating_sub(ADAPTER_SEED_LENGTH - 1) {
+			let seed : &str = &seq[i..(i + ADAPTER_SEED_LENGTH)];
+			if !seen.iter().any(|&(seen_seed, _)| seen_seed == seed) {
+				seen.push((seed, 2 * i >= seq.len() - ADAPTER_SEED_LENGTH));
+			}
+		}
+		for (seed, is_3p) in seen {
+			let counts = seed_counts.entry(seed).or_insert((0, 0));
+			counts.0 += 1;
+			counts.1 += is_3p as usize;
+		}
+	}
+	//Runs of a single base are poly-A tails or low-complexity sequence rather than adapters
+	let (seed, _) = seed_counts.into_iter()
+		.filter(|&(seed, (count, count_3p))| {
+			count >= min_count && count_3p as f64 >= count as f64 * ADAPTER_MIN_3P_FRACTION && seed.chars().any(|c| !seed.starts_with(c))
+		})
+		.max_by(|a, b| (a.1).0.cmp(&(b.1).0).then_with(|| b.0.cmp(a.0)))?;
+
+	//A seed from the middle of the adapter is extended towards its start as well as its end
+	let mut adapter : String = seed.to_string();
+	while adapter.len() < ADAPTER_MAX_LENGTH {
+		if let Some(next) = agreed_neighbour(&sample, &adapter, false) {
+			adapter.push(next);
+		} else if let Some(previous) = agreed_neighbour(&sample, &adapter, true) {
+			adapter.insert(0, previous);
 		} else {
-			let mapped : String = line_val.chars().filter(|x| is_char_rna(*x)).collect();
-			current.push_str(mapped.as_str());
+			break;
 		}
 	}
+	info!("Detected overrepresented sequence {} as the adapter", adapter);
+	Some(adapter)
+}
 
-	if current != "" {
-		fq_strings.push(current);
+//The base most occurrences of a candidate adapter agree on just before or after it, if most of them do
+fn agreed_neighbour(sample : &[&str], adapter : &str, before : bool) -> Option<char> {
+	let mut neighbour_counts : HashMap<char, usize> = HashMap::new();
+	let mut n_occurrences : usize = 0;
+	for seq in sample {
+		if let Some(position) = seq.find(adapter) {
+			n_occurrences += 1;
+			let neighbour : Option<char> = if before {
+				seq[..position].chars().next_back()
+			} else {
+				seq[(position + adapter.len())..].chars().next()
+			};
+			if let Some(neighbour) = neighbour {
+				*neighbour_counts.entry(neighbour).or_insert(0) += 1;
+			}
+		}
+	}
+	match neighbour_counts.into_iter().max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0))) {
+		Some((neighbour, count)) if count * 2 > n_occurrences => Some(neighbour),
+		_ => None,
+	}
+}
+
+//The start of the first match of the adapter, or of a prefix of it running off the 3' end of the read
+fn find_adapter(seq : &str, adapter : &str) -> Option<usize> {
+	let seq_bytes : &[u8] = seq.as_bytes();
+	let adapter_bytes : &[u8] = adapter.as_bytes();
+	(0..(seq_bytes.len().saturating_sub(ADAPTER_MIN_OVERLAP) + 1)).find(|&start| {
+		let overlap : usize = adapter_bytes.len().min(seq_bytes.len() - start);
+		if overlap < ADAPTER_MIN_OVERLAP {
+			return false;
+		}
+		let mismatches : usize = seq_bytes[start..(start + overlap)].iter().zip(adapter_bytes.iter())
+			.filter(|&(a, b)| a != b)
+			.count();
+		mismatches as f64 <= (overlap as f64 * ADAPTER_MISMATCH_RATE).floor()
+	})
+}
+
+//Length of the run of A (or T, for reads from the other strand) at the 3' end of the read
+fn poly_a_length(seq : &str) -> usize {
+	let last : Option<char> = seq.chars().last();
+	match last {
+		Some('A') | Some('T') => seq.chars().rev().take_while(|&c| Some(c) == last).count(),
+		_ => 0,
+	}
+}
+
+//Length to keep so that the read ends before the first window of bases whose mean quality is below
+//the cutoff
+fn quality_trim_length(qual : &str, cutoff : u8) -> usize {
+	let scores : Vec<u32> = qual.bytes().map(|q| q.saturating_sub(PHRED_OFFSET) as u32).collect();
+	if scores.len() < QUALITY_WINDOW {
+		return scores.iter().take_while(|&&score| score >= cutoff as u32).count();
 	}
-	return fq_strings
+	for start in 0..(scores.len() - QUALITY_WINDOW + 1) {
+		let total : u32 = scores[start..(start + QUALITY_WINDOW)].iter().sum();
+		if total < cutoff as u32 * QUALITY_WINDOW as u32 {
+			//Keep the good bases at the start of the failing window
+			return start + scores[start..].iter().take_while(|&&score| score >= cutoff as u32).count();
+		}
+	}
+	scores.len()
 }
 
 /**
@@ -125,14 +584,22 @@ pub fn read_fq_fasta_file(filename : &str) -> Vec<String> {
 // 	return fq_strings
 // }
 
-pub fn read_fa_file_to_cols(filename : &str) -> FaColDB { 
+/**
+    read_fa_file_to_cols:
+    Given the name of a fasta file, read its sequences with their IDs and header metadata.
+    Every letter of a sequence is kept, upper-cased, with anything but A, C, G, T and U
+    read as N, so that positions in a genome match those of its annotation.
+*/
+pub fn read_fa_file_to_cols(filename : &str) -> FaColDB {
 	let f = File::open(filename).expect("file not found");
 	
 	let mut ids : Vec<String> = Vec::new();
 	let mut segs : Vec<String> = Vec::new();
+	let mut metadata : Vec<SegMetadata> = Vec::new();
 
 	let mut current : String = String::new();
 	let mut current_seg_id : String = String::new();
+	let mut current_seg_desc : String = String::new();
 	for line in BufReader::new(f).lines() {
 		let line_val = line.expect("line not available");
 		let first_char = line_val.chars().next().unwrap();
@@ -142,8 +609,9 @@ pub fn read_fa_file_to_cols(filename : &str) -> FaColDB {
 		if first_char == '!' || first_char == '@' || first_char == '>' {
 			// process new string
 			if current != "" {
-				ids.push(current_seg_id);
 				segs.push(current);
+				metadata.push(SegMetadata::parse(&current_seg_id, &current_seg_desc));
+				ids.push(current_seg_id);
 				current = String::new();
 			}
 			// split the header by whitespace, find the first word
@@ -154,19 +622,25 @@ pub fn read_fa_file_to_cols(filename : &str) -> FaColDB {
 			first_word = first_word.chars().filter(|x| !is_char_header(*x)).collect();
 			debug!("{}", first_word);
 			current_seg_id = first_word;
+			current_seg_desc = line_val.split_once(char::is_whitespace).map_or("", |(_, description)| description).trim().to_string();
 			continue; // skip
 		} else {
-			let mapped : String = line_val.chars().filter(|x| is_char_rna(*x)).collect();
+			let mapped : String = line_val.chars()
+				.filter(|x| !x.is_whitespace())
+				.map(|x| x.to_ascii_uppercase())
+				.map(|x| if is_char_rna(x) { x } else { 'N' })
+				.collect();
 			current.push_str(mapped.as_str());
 		}
 	}
 
 	if current != "" {
+		metadata.push(SegMetadata::parse(&current_seg_id, &current_seg_desc));
 		ids.push(current_seg_id);
 		segs.push(current);
 	}
-	return FaColDB { seg_ids : ids, seg_strings : segs };
-
+	let n_targets : usize = ids.len();
+	FaColDB { seg_ids : ids, seg_strings : segs, seg_metadata : metadata, n_targets }
 }
 
 // pub fn write_output(filename : &str, counts : HashMap<String, i32>) -> bool {
@@ -203,15 +677,1249 @@ pub fn read_fa_file_to_cols(filename : &str) -> FaColDB {
 // 	return true;
 // }
 
-pub fn write_output4(filename : &str, counts : &mut HashMap<i32, i32>, segment_id_strings : Vec<String>) -> bool {
+/**
+    write_output5:
+    Write every target with its length, effective length, TPM, read count and any extra
+    header columns in the chosen output format
+*/
+pub fn write_output5(filename : &str, format : OutputFormat, segment_id_strings : &[String], seg_strings : &[String],
+		abundance : &Abundance, seg_metadata : &[SegMetadata], extra_columns : &[String]) -> bool {
+	let mut count_writer = new_count_writer(filename, format, extra_columns);
+
+	for (i, (s, metadata)) in segment_id_strings.iter().zip(seg_metadata.iter()).enumerate() {
+		count_writer.write_row(&SegmentRow {
+			name : s,
+			length : seg_strings[i].len(),
+			effective_length : abundance.effective_lengths[i],
+			tpm : abundance.tpm[i],
+			num_reads : abundance.counts[i],
+			extra_values : extra_columns.iter().map(|column| metadata.get(column).unwrap_or("NA")).collect(),
+		});
+	}
+	true
+}
+
+/**
+    write_unitigs:
+    Write every unitig of the graph as a line of its index, length, the IDs of the segments
+    it occurs in and its sequence
+*/
+pub fn write_unitigs(filename : &str, unitig_graph : &DNAUnitigGraph, segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	for (i, unitig) in unitig_graph.unitigs.iter().enumerate() {
+		let colors : Vec<&str> = unitig.colors.iter()
+			.map(|segment_index| segment_id_strings[*segment_index].as_str())
+			.collect();
+		lw.write_fmt(format_args!("{}\t{}\t{}\t{}\n", i, unitig.sequence.len(), colors.join(","), unitig.sequence))
+			.expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_gene_counts:
+    Write the reads counted towards every gene, followed by the reads that could not be
+    counted towards exactly one gene
+*/
+pub fn write_gene_counts(filename : &str, annotation : &GeneAnnotation, feature_counts : &FeatureCounts) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	for (gene_id, count) in annotation.gene_ids.iter().zip(feature_counts.gene_counts.iter()) {
+		lw.write_fmt(format_args!("{}\t{}\n", gene_id, count)).expect("Write failed");
+	}
+	lw.write_fmt(format_args!("__no_feature\t{}\n", feature_counts.no_feature)).expect("Write failed");
+	lw.write_fmt(format_args!("__ambiguous\t{}\n", feature_counts.ambiguous)).expect("Write failed");
+	lw.write_fmt(format_args!("__not_aligned\t{}\n", feature_counts.not_aligned)).expect("Write failed");
+	true
+}
+
+/**
+    write_junction_counts:
+    Write every annotated junction as its chromosome, 1-based first and last intron positions,
+    strand and genes, followed by the number of reads spliced across it
+*/
+pub fn write_junction_counts(filename : &str, annotation : &GeneAnnotation, feature_counts : &FeatureCounts) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	for (junction, count) in annotation.junctions.iter().zip(feature_counts.junction_counts.iter()) {
+		lw.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\n",
+			junction.chrom, junction.donor + 1, junction.acceptor, junction.strand, junction.gene_ids.join(","), count))
+			.expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_gene_output:
+    Write the counts and TPM of every gene
+*/
+pub fn write_gene_output(filename : &str, gene_map : &GeneMap, gene_counts : &[f64], gene_tpm : &[f64]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	lw.write_fmt(format_args!("gene_id\tcount\ttpm\n")).expect("Write failed");
+	for ((gene_id, count), tpm) in gene_map.gene_ids.iter().zip(gene_counts.iter()).zip(gene_tpm.iter()) {
+		lw.write_fmt(format_args!("{}\t{}\t{:.6}\n", gene_id, count, tpm)).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_duplicates:
+    Write every segment collapsed into another as the retained ID, the removed ID and
+    whether it was an identical or a contained sequence
+*/
+pub fn write_duplicates(filename : &str, segment_collapse : &SegmentCollapse, segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	lw.write_fmt(format_args!("retained_id\tduplicate_id\ttype\n")).expect("Write failed");
+	for duplicate in &segment_collapse.duplicates {
+		lw.write_fmt(format_args!("{}\t{}\t{}\n",
+			segment_id_strings[duplicate.retained_index],
+			segment_id_strings[duplicate.removed_index],
+			if duplicate.contained { "contained" } else { "identical" }))
+			.expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_bootstrap_summary:
+    Write the mean and variance of every segment's count across the bootstrap
+    replicates or Gibbs samples
+*/
+pub fn write_bootstrap_summary(filename : &str, summary : &[(f64, f64)], segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	lw.write_fmt(format_args!("segment_id\tmean\tvariance\n")).expect("Write failed");
+	for (seg_id, &(mean, variance)) in segment_id_strings.iter().zip(summary.iter()) {
+		lw.write_fmt(format_args!("{}\t{:.6}\t{:.6}\n", seg_id, mean, variance)).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_bootstrap_matrix:
+    Write the count of every segment in each replicate, one row per segment and one
+    column per replicate
+*/
+pub fn write_bootstrap_matrix(filename : &str, replicates : &[Vec<f64>], segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	let header : Vec<String> = (0..replicates.len()).map(|replicate| format!("replicate_{}", replicate)).collect();
+	lw.write_fmt(format_args!("segment_id\t{}\n", header.join("\t"))).expect("Write failed");
+	for (target, seg_id) in segment_id_strings.iter().enumerate() {
+		let counts : Vec<String> = replicates.iter().map(|replicate| replicate[target].to_string()).collect();
+		lw.write_fmt(format_args!("{}\t{}\n", seg_id, counts.join("\t"))).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_lib_format_counts:
+    Write the detected library type, the number of sampled reads and the number seen in
+    each orientation as a JSON object
+*/
+pub fn write_lib_format_counts(filename : &str, library_format_counts : &LibraryFormatCounts) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	lw.write_fmt(format_args!("{{\n")).expect("Write failed");
+	lw.write_fmt(format_args!("    \"expected_format\": \"{}\",\n", library_format_counts.library_type.code())).expect("Write failed");
+	lw.write_fmt(format_args!("    \"num_sampled_reads\": {},\n", library_format_counts.n_sampled)).expect("Write failed");
+	lw.write_fmt(format_args!("    \"num_ambiguous_reads\": {},\n", library_format_counts.n_ambiguous)).expect("Write failed");
+	lw.write_fmt(format_args!("    \"strand_mapping_bias\": {:.6},\n", library_format_counts.strand_bias())).expect("Write failed");
+	let format_counts : Vec<String> = library_format_counts.format_counts.iter()
+		.map(|(code, count)| format!("\"{}\": {}", code, count))
+		.collect();
+	lw.write_fmt(format_args!("    \"format_counts\": {{{}}}\n", format_counts.join(", "))).expect("Write failed");
+	lw.write_fmt(format_args!("}}\n")).expect("Write failed");
+	true
+}
+
+/**
+    write_meta_info:
+    Write the summary of a run as a JSON object, including the library format counts
+    when the library type was detected
+*/
+pub fn write_meta_info(filename : &str, meta_info : &MetaInfo) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	let partition_seconds : Vec<String> = meta_info.partition_seconds.iter().map(|seconds| format!("{:.6}", seconds)).collect();
+	let mut fields : Vec<String> = vec![
+		format!("\"tuna_version\": {}", json_string(&meta_info.version)),
+		format!("\"command_line\": {}", json_string(&meta_info.command_line)),
+		format!("\"k\": {}", meta_info.k),
+		format!("\"index_checksum\": {}", json_string(&meta_info.index_checksum)),
+		format!("\"num_processed\": {}", meta_info.n_reads),
+		format!("\"num_assigned\": {}", meta_info.n_assigned),
+		format!("\"num_no_hits\": {}", meta_info.n_no_hits),
+		format!("\"num_decoy\": {}", meta_info.n_decoy),
+		format!("\"percent_assigned\": {:.6}", if meta_info.n_reads > 0 { 100.0 * meta_info.n_assigned as f64 / meta_info.n_reads as f64 } else { 0.0 }),
+		format!("\"multimapping_rate\": {:.6}", meta_info.multimapping_rate()),
+		format!("\"partition_seconds\": [{}]", partition_seconds.join(", ")),
+		format!("\"wall_seconds\": {:.6}", meta_info.wall_seconds),
+		format!("\"cpu_seconds\": {}", meta_info.cpu_seconds.map_or("null".to_string(), |seconds| format!("{:.6}", seconds))),
+	];
+	if let Some(ref library_format_counts) = meta_info.library_format_counts {
+		fields.push(format!("\"library_types\": [{}]", json_string(&library_format_counts.library_type.code())));
+		let format_counts : Vec<String> = library_format_counts.format_counts.iter()
+			.map(|(code, count)| format!("\"{}\": {}", code, count))
+			.collect();
+		fields.push(format!("\"lib_format_counts\": {{{}}}", format_counts.join(", ")));
+	}
+	if let Some(ref trim_stats) = meta_info.trim_stats {
+		let adapter : String = trim_stats.adapter.as_ref().map_or("null".to_string(), |adapter| json_string(adapter));
+		fields.push(format!("\"trimming\": {{\"adapter\": {}, \"num_reads\": {}, \"num_adapter_trimmed\": {}, \"num_poly_a_trimmed\": {}, \"num_quality_trimmed\": {}, \"num_too_short\": {}, \"num_bases_removed\": {}}}",
+			adapter, trim_stats.n_reads, trim_stats.n_adapter_trimmed, trim_stats.n_poly_a_trimmed, trim_stats.n_quality_trimmed,
+			trim_stats.n_too_short, trim_stats.n_bases_removed));
+	}
+
+	lw.write_fmt(format_args!("{{\n    {}\n}}\n", fields.join(",\n    "))).expect("Write failed");
+	true
+}
+
+
+/**
+    write_count_matrix:
+    Write the counts of every row (a segment or gene) in each sample as a matrix with one
+    column per sample
+*/
+pub fn write_count_matrix(filename : &str, samples : &[Sample], row_ids : &[String], sample_counts : &[Vec<f64>]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	let sample_names : Vec<&str> = samples.iter().map(|sample| sample.name.as_str()).collect();
+	lw.write_fmt(format_args!("id\t{}\n", sample_names.join("\t"))).expect("Write failed");
+	for (i, row_id) in row_ids.iter().enumerate() {
+		let counts : Vec<String> = sample_counts.iter().map(|counts| counts[i].to_string()).collect();
+		lw.write_fmt(format_args!("{}\t{}\n", row_id, counts.join("\t"))).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_sample_summary:
+    Write the reads processed, assigned, without hits and discarded as decoys for every
+    sample, with the library type it was quantified as
+*/
+pub fn write_sample_summary(filename : &str, sample_summaries : &[SampleSummary]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	lw.write_fmt(format_args!("sample\tnum_processed\tnum_assigned\tnum_no_hits\tnum_decoy\tpercent_assigned\tlibrary_type\n"))
+		.expect("Write failed");
+	for summary in sample_summaries {
+		let mapping_stats = &summary.mapping_stats;
+		let percent_assigned : f64 = if mapping_stats.n_reads > 0 { 100.0 * mapping_stats.n_assigned as f64 / mapping_stats.n_reads as f64 } else { 0.0 };
+		lw.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{:.2}\t{}\n", summary.name, mapping_stats.n_reads, mapping_stats.n_assigned,
+			mapping_stats.n_no_hits, mapping_stats.n_decoy, percent_assigned, summary.library_type)).expect("Write failed");
+	}
+	true
+}
+
+/**
+    read_whitelist:
+    Given the name of a file of cell barcodes, one per line, read the barcodes into a
+    vector. Anything after the barcode on a line is ignored.
+*/
+pub fn read_whitelist(filename : &str) -> Vec<String> {
+	let f = File::open(filename).expect("file not found");
+	BufReader::new(f).lines()
+		.map(|line| line.expect("line not available"))
+		.filter_map(|line_val| line_val.split_whitespace().next().map(|barcode| barcode.to_string()))
+		.collect()
+}
+
+/**
+    write_cell_summary:
+    Write what became of the read pairs of a single-cell run to summary.tsv in the
+    directory of its matrix
+*/
+pub fn write_cell_summary(dirname : &str, cell_stats : &CellStats, n_cells : usize) -> bool {
+	let dir = std::path::Path::new(dirname);
+	std::fs::create_dir_all(dir).expect("Unable to create directory");
+	let mut lw = LineWriter::new(File::create(dir.join("summary.tsv")).expect("Unable to create file"));
+
+	lw.write_fmt(format_args!("num_pairs\tnum_assigned\tnum_barcode_corrected\tnum_barcode_rejected\tnum_unassigned\tnum_multigene\tnum_cells\n"))
+		.expect("Write failed");
+	lw.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n", cell_stats.n_pairs, cell_stats.n_assigned, cell_stats.n_barcode_corrected,
+		cell_stats.n_barcode_rejected, cell_stats.n_unassigned, cell_stats.n_multigene, n_cells)).expect("Write failed");
+	true
+}
+
+/**
+    write_cell_matrix:
+    Write the molecules counted per cell and gene to a directory as a cells x genes
+    Matrix Market file, with the cell barcodes in barcodes.tsv and the genes in features.tsv
+*/
+pub fn write_cell_matrix(dirname : &str, cell_matrix : &CellMatrix, gene_map : &GeneMap, seg_metadata : &[SegMetadata]) -> bool {
+	let dir = std::path::Path::new(dirname);
+	std::fs::create_dir_all(dir).expect("Unable to create directory");
+
+	let mut lw = LineWriter::new(File::create(dir.join("matrix.mtx")).expect("Unable to create file"));
+	lw.write_fmt(format_args!("%%MatrixMarket matrix coordinate integer general\n")).expect("Write failed");
+	lw.write_fmt(format_args!("{} {} {}\n", cell_matrix.barcodes.len(), gene_map.gene_ids.len(), cell_matrix.entries.len())).expect("Write failed");
+	for &(cell_index, gene_index, count) in &cell_matrix.entries {
+		lw.write_fmt(format_args!("{} {} {}\n", cell_index + 1, gene_index + 1, count)).expect("Write failed");
+	}
+
+	let mut lw = LineWriter::new(File::create(dir.join("barcodes.tsv")).expect("Unable to create file"));
+	for barcode in &cell_matrix.barcodes {
+		lw.write_fmt(format_args!("{}\n", barcode)).expect("Write failed");
+	}
+
+	//Genes are named after the first of their segments to carry a gene name
+	let mut gene_names : Vec<Option<&str>> = vec![None; gene_map.gene_ids.len()];
+	for (&gene_index, metadata) in gene_map.segment_genes.iter().zip(seg_metadata.iter()) {
+		if gene_names[gene_index].is_none() {
+			gene_names[gene_index] = metadata.get("gene_name");
+		}
+	}
+	let mut lw = LineWriter::new(File::create(dir.join("features.tsv")).expect("Unable to create file"));
+	for (gene_id, gene_name) in gene_map.gene_ids.iter().zip(gene_names.iter()) {
+		lw.write_fmt(format_args!("{}\t{}\tGene Expression\n", gene_id, gene_name.unwrap_or(gene_id))).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_whitelist:
+    Write the barcodes called as cells, one per line
+*/
+pub fn write_whitelist(filename : &str, barcode_ranks : &BarcodeRanks) -> bool {
 	let file = File::create(filename).expect("Unable to create file");
 	let mut lw = LineWriter::new(file);
 
-	let mut i : i32 = 0;
-	for s in segment_id_strings {
-		let count = counts.entry(i).or_insert(0);
-		lw.write_fmt(format_args!("{}\t{}\n", s, count)).expect("Write failed");
-		i = i + 1;
+	for (barcode, _) in barcode_ranks.ranked.iter().take(barcode_ranks.n_cells) {
+		lw.write_fmt(format_args!("{}\n", barcode)).expect("Write failed");
 	}
-	return true;
-}
\ No newline at end of file
+	true
+}
+
+/**
+    write_barcode_ranks:
+    Write every barcode with its rank, read count and whether it was called as a cell,
+    for plotting the barcode rank curve
+*/
+pub fn write_barcode_ranks(filename : &str, barcode_ranks : &BarcodeRanks) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	lw.write_fmt(format_args!("rank\tbarcode\tcount\tis_cell\n")).expect("Write failed");
+	for (i, &(ref barcode, count)) in barcode_ranks.ranked.iter().enumerate() {
+		lw.write_fmt(format_args!("{}\t{}\t{}\t{}\n", i + 1, barcode, count, i < barcode_ranks.n_cells)).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_qc_json:
+    Write the read statistics of a fq or fasta file as a JSON object, with per-position
+    arrays indexed from the first base of the reads
+*/
+pub fn write_qc_json(filename : &str, read_input_filename : &str, read_qc : &ReadQc) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	let length_counts : Vec<String> = read_qc.length_counts.iter().map(|(length, count)| format!("\"{}\": {}", length, count)).collect();
+	let base_percents : Vec<[f64; 5]> = read_qc.base_percents();
+	let base_composition : Vec<String> = BASES.iter().enumerate()
+		.map(|(i, base)| {
+			let percents : Vec<String> = base_percents.iter().map(|percents| format!("{:.3}", percents[i])).collect();
+			format!("\"{}\": [{}]", base, percents.join(", "))
+		})
+		.collect();
+	let mean_qualities : Vec<String> = read_qc.mean_qualities().iter().map(|quality| format!("{:.3}", quality)).collect();
+	let gc_counts : Vec<String> = read_qc.gc_counts.iter().map(|count| count.to_string()).collect();
+	let overrepresented : Vec<String> = read_qc.overrepresented().iter()
+		.map(|&(sequence, count)| format!("{{\"sequence\": {}, \"count\": {}, \"percent\": {:.4}}}", json_string(sequence), count,
+			100.0 * count as f64 / read_qc.n_reads as f64))
+		.collect();
+
+	let fields : Vec<String> = vec![
+		format!("\"filename\": {}", json_string(read_input_filename)),
+		format!("\"num_reads\": {}", read_qc.n_reads),
+		format!("\"num_bases\": {}", read_qc.n_bases),
+		format!("\"mean_length\": {:.3}", read_qc.mean_length()),
+		format!("\"gc_percent\": {:.3}", read_qc.gc_percent()),
+		format!("\"n_percent\": {:.4}", read_qc.n_percent()),
+		format!("\"duplication_rate\": {:.6}", read_qc.duplication_rate()),
+		format!("\"length_counts\": {{{}}}", length_counts.join(", ")),
+		format!("\"base_composition\": {{{}}}", base_composition.join(", ")),
+		format!("\"mean_quality\": {}", if read_qc.n_with_quality > 0 { format!("[{}]", mean_qualities.join(", ")) } else { "null".to_string() }),
+		format!("\"gc_counts\": [{}]", gc_counts.join(", ")),
+		format!("\"overrepresented_sequences\": [{}]", overrepresented.join(", ")),
+	];
+
+	lw.write_fmt(format_args!("{{\n    {}\n}}\n", fields.join(",\n    "))).expect("Write failed");
+	true
+}
+
+/**
+    write_qc_html:
+    Write the read statistics of a fq or fasta file as a single HTML page with its charts
+    drawn inline, so that it can be opened or shared without any other files
+*/
+pub fn write_qc_html(filename : &str, read_input_filename : &str, read_qc : &ReadQc) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+	let title : String = html_escape(read_input_filename);
+
+	let summary : Vec<(&str, String)> = vec![
+		("Reads", read_qc.n_reads.to_string()),
+		("Bases", read_qc.n_bases.to_string()),
+		("Mean length", format!("{:.1}", read_qc.mean_length())),
+		("GC", format!("{:.2}%", read_qc.gc_percent())),
+		("N", format!("{:.4}%", read_qc.n_percent())),
+		("Duplication rate", format!("{:.2}%", 100.0 * read_qc.duplication_rate())),
+	];
+	let lengths : Vec<(f64, f64)> = read_qc.length_counts.iter().map(|(&length, &count)| (length as f64, count as f64)).collect();
+	let base_percents : Vec<[f64; 5]> = read_qc.base_percents();
+	let composition : Vec<(&str, Vec<(f64, f64)>)> = BASES.iter().enumerate()
+		.map(|(i, base)| (*base, base_percents.iter().enumerate().map(|(position, percents)| ((position + 1) as f64, percents[i])).collect()))
+		.collect();
+	let qualities : Vec<(f64, f64)> = read_qc.mean_qualities().iter().enumerate().map(|(position, &quality)| ((position + 1) as f64, quality)).collect();
+	let gc : Vec<(f64, f64)> = read_qc.gc_counts.iter().enumerate().map(|(percent, &count)| (percent as f64, count as f64)).collect();
+
+	lw.write_fmt(format_args!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>tuna qc: {}</title>\n", title)).expect("Write failed");
+	lw.write_fmt(format_args!("<style>body {{ font-family: sans-serif; margin: 2em; }} table {{ border-collapse: collapse; }} td, th {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }} .sequence {{ font-family: monospace; }}</style>\n</head>\n<body>\n"))
+		.expect("Write failed");
+	lw.write_fmt(format_args!("<h1>tuna qc: {}</h1>\n<h2>Summary</h2>\n<table>\n", title)).expect("Write failed");
+	for (name, value) in summary {
+		lw.write_fmt(format_args!("<tr><th>{}</th><td>{}</td></tr>\n", name, value)).expect("Write failed");
+	}
+	lw.write_fmt(format_args!("</table>\n")).expect("Write failed");
+
+	lw.write_fmt(format_args!("<h2>Read length distribution</h2>\n{}\n", svg_chart(&[("reads", lengths)], "Read length", "Reads", true))).expect("Write failed");
+	lw.write_fmt(format_args!("<h2>Per-position base composition</h2>\n{}\n", svg_chart(&composition, "Position", "Percent", false))).expect("Write failed");
+	if read_qc.n_with_quality > 0 {
+		lw.write_fmt(format_args!("<h2>Per-position mean quality</h2>\n{}\n", svg_chart(&[("quality", qualities)], "Position", "Phred quality", false)))
+			.expect("Write failed");
+	}
+	lw.write_fmt(format_args!("<h2>GC content</h2>\n{}\n", svg_chart(&[("reads", gc)], "GC percent", "Reads", true))).expect("Write failed");
+
+	lw.write_fmt(format_args!("<h2>Overrepresented sequences</h2>\n")).expect("Write failed");
+	let overrepresented : Vec<(&str, usize)> = read_qc.overrepresented();
+	if overrepresented.is_empty() {
+		lw.write_fmt(format_args!("<p>None</p>\n")).expect("Write failed");
+	} else {
+		lw.write_fmt(format_args!("<table>\n<tr><th>Sequence</th><th>Count</th><th>Percent</th></tr>\n")).expect("Write failed");
+		for (sequence, count) in overrepresented {
+			lw.write_fmt(format_args!("<tr><td class=\"sequence\">{}</td><td>{}</td><td>{:.4}</td></tr>\n", html_escape(sequence), count,
+				100.0 * count as f64 / read_qc.n_reads as f64)).expect("Write failed");
+		}
+		lw.write_fmt(format_args!("</table>\n")).expect("Write failed");
+	}
+	lw.write_fmt(format_args!("</body>\n</html>\n")).expect("Write failed");
+	true
+}
+
+fn html_escape(text : &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/**
+    write_kmer_counts:
+    Write the k-mers seen at least min_count times with their counts, either as text lines
+    of k-mer and count, or in binary: the bytes TUNAKMER, k as a little-endian u32, a byte
+    set to 1 for canonical counts, the number of k-mers as a u64, then each k-mer packed
+    two bits a base (A, C, G, T as 0 to 3, first base highest) into (k + 3) / 4 bytes
+    followed by its count as a u32
+*/
+pub fn write_kmer_counts(filename : &str, format : KmerFormat, k : usize, canonical : bool, counts : &[(String, usize)], min_count : usize) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+	let kept : Vec<&(String, usize)> = counts.iter().filter(|&&(_, count)| count >= min_count).collect();
+
+	match format {
+		KmerFormat::Text => {
+			for &&(ref kmer, count) in &kept {
+				writer.write_fmt(format_args!("{}\t{}\n", kmer, count)).expect("Write failed");
+			}
+		},
+		KmerFormat::Binary => {
+			writer.write_all(b"TUNAKMER").expect("Write failed");
+			writer.write_all(&(k as u32).to_le_bytes()).expect("Write failed");
+			writer.write_all(&[canonical as u8]).expect("Write failed");
+			writer.write_all(&(kept.len() as u64).to_le_bytes()).expect("Write failed");
+			let mut packed : Vec<u8> = vec![0; k.div_ceil(4)];
+			for &&(ref kmer, count) in &kept {
+				for byte in packed.iter_mut() {
+					*byte = 0;
+				}
+				for (i, base) in kmer.bytes().enumerate() {
+					let code : u8 = match base {
+						b'C' => 1,
+						b'G' => 2,
+						b'T' | b'U' => 3,
+						_ => 0,
+					};
+					packed[i / 4] |= code << (6 - 2 * (i % 4));
+				}
+				writer.write_all(&packed).expect("Write failed");
+				writer.write_all(&(count.min(u32::MAX as usize) as u32).to_le_bytes()).expect("Write failed");
+			}
+		},
+	}
+	info!("Wrote {} k-mers seen at least {} times", kept.len(), min_count);
+	true
+}
+
+/**
+    write_kmer_histogram:
+    Write the number of distinct k-mers seen each number of times, one multiplicity per
+    line
+*/
+pub fn write_kmer_histogram(filename : &str, histogram : &BTreeMap<usize, usize>) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	for (multiplicity, count) in histogram {
+		lw.write_fmt(format_args!("{}\t{}\n", multiplicity, count)).expect("Write failed");
+	}
+	true
+}
+
+/**
+    read_abundance_table:
+    Given the name of a file of target IDs and abundances, one tab-separated pair per line,
+    read the abundance of every target. Lines whose abundance is not a number, such as a
+    header, are skipped.
+*/
+pub fn read_abundance_table(filename : &str) -> HashMap<String, f64> {
+	let f = File::open(filename).expect("file not found");
+	let mut abundances : HashMap<String, f64> = HashMap::new();
+
+	for line in BufReader::new(f).lines() {
+		let line_val = line.expect("line not available");
+		let fields : Vec<&str> = line_val.split('\t').collect();
+		if fields.len() < 2 {
+			continue;
+		}
+		match fields[1].trim().parse::<f64>() {
+			Ok(abundance) => { abundances.insert(fields[0].trim().to_string(), abundance); },
+			Err(_) => debug!("Skipping abundance line {}", line_val),
+		}
+	}
+	info!("Read the abundances of {} targets", abundances.len());
+	abundances
+}
+
+/**
+    write_fq_records:
+    Write records as fq when they have qualities, and as fasta otherwise
+*/
+pub fn write_fq_records<'r, I : IntoIterator<Item = &'r FqRecord>>(filename : &str, records : I) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+
+	for record in records {
+		match record.qual {
+			Some(ref qual) => writer.write_fmt(format_args!("@{}\n{}\n+\n{}\n", record.name, record.seq, qual)).expect("Write failed"),
+			None => writer.write_fmt(format_args!(">{}\n{}\n", record.name, record.seq)).expect("Write failed"),
+		}
+	}
+	true
+}
+
+/**
+    write_truth_table:
+    Write where every simulated read came from: its target, the strand of its first read,
+    the start and length of its fragment and the errors added to it
+*/
+pub fn write_truth_table(filename : &str, simulated_reads : &[SimulatedRead], segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+
+	writer.write_fmt(format_args!("read_name\ttarget_id\tstrand\tfragment_start\tfragment_length\tnum_errors\n")).expect("Write failed");
+	for simulated_read in simulated_reads {
+		writer.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\n", simulated_read.record.name, segment_id_strings[simulated_read.segment_index],
+			if simulated_read.forward { '+' } else { '-' }, simulated_read.fragment_start, simulated_read.fragment_length,
+			simulated_read.n_errors)).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_truth_counts:
+    Write the abundance every target was simulated at, as TPM, with the number of reads
+    drawn from it
+*/
+pub fn write_truth_counts(filename : &str, simulated_reads : &[SimulatedRead], abundances : &[f64], segment_id_strings : &[String],
+		seg_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	let mut n_reads : Vec<usize> = vec![0; segment_id_strings.len()];
+	for simulated_read in simulated_reads {
+		n_reads[simulated_read.segment_index] += 1;
+	}
+	let total : f64 = abundances.iter().sum();
+	lw.write_fmt(format_args!("Name\tLength\tTPM\tNumReads\n")).expect("Write failed");
+	for (i, segment_id) in segment_id_strings.iter().enumerate() {
+		let tpm : f64 = if total > 0.0 { 1e6 * abundances[i] / total } else { 0.0 };
+		lw.write_fmt(format_args!("{}\t{}\t{:.6}\t{}\n", segment_id, seg_strings[i].len(), tpm, n_reads[i])).expect("Write failed");
+	}
+	true
+}
+
+/**
+    read_quant_column:
+    Given the name of a quantification output in any of the output formats, or of a truth
+    table, read the named column for every target. Headerless files, such as the counts
+    format, hold only read counts, which are read from their second column as NumReads.
+*/
+pub fn read_quant_column(filename : &str, column : &str) -> HashMap<String, f64> {
+	let f = File::open(filename).expect("file not found");
+	let mut abundances : HashMap<String, f64> = HashMap::new();
+	let mut column_index : Option<usize> = None;
+
+	for (i, line) in BufReader::new(f).lines().enumerate() {
+		let line_val = line.expect("line not available");
+		if line_val.trim().is_empty() {
+			continue;
+		}
+		if line_val.starts_with('{') {
+			if let (Some(name), Some(value)) = (json_value(&line_val, "Name"), json_value(&line_val, column)) {
+				abundances.insert(name, value.parse::<f64>().expect("abundance is not a number"));
+			}
+			continue;
+		}
+
+		let separator : char = if line_val.contains('\t') { '\t' } else { ',' };
+		let fields : Vec<&str> = line_val.split(separator).map(|field| field.trim().trim_matches('"')).collect();
+		if i == 0 && fields.iter().skip(1).all(|field| field.parse::<f64>().is_err()) {
+			//A header of only an ID and a value names the value however it likes
+			column_index = Some(fields.iter().position(|field| *field == column)
+				.or(if fields.len() == 2 { Some(1) } else { None })
+				.unwrap_or_else(|| panic!("{} has no column {}", filename, column)));
+			continue;
+		}
+		if i == 0 && column != "NumReads" {
+			panic!("{} has no header, so only its read counts can be compared, not {}", filename, column);
+		}
+		if let Some(Ok(value)) = fields.get(column_index.unwrap_or(1)).map(|field| field.parse::<f64>()) {
+			abundances.insert(fields[0].to_string(), value);
+		}
+	}
+	info!("Read {} of {} targets from {}", column, abundances.len(), filename);
+	abundances
+}
+
+//The value of a key of a flat JSON object on one line, with strings unquoted and unescaped
+fn json_value(line : &str, key : &str) -> Option<String> {
+	let pattern : String = format!("\"{}\":", key);
+	let rest : &str = line[(line.find(pattern.as_str())? + pattern.len())..].trim_start();
+	if let Some(string) = rest.strip_prefix('"') {
+		let mut value : String = String::new();
+		let mut chars = string.chars();
+		loop {
+			match chars.next()? {
+				'"' => return Some(value),
+				'\\' => value.push(match chars.next()? {
+					'n' => '\n',
+					't' => '\t',
+					'r' => '\r',
+					'b' => '\u{8}',
+					'f' => '\u{c}',
+					'u' => {
+						let code : String = chars.by_ref().take(4).collect();
+						u32::from_str_radix(&code, 16).ok().and_then(std::char::from_u32)?
+					},
+					escaped => escaped,
+				}),
+				c => value.push(c),
+			}
+		}
+	} else {
+		Some(rest[..rest.find([',', '}'])?].trim().to_string())
+	}
+}
+
+/**
+    read_truth_table:
+    Given the name of a truth table as written by tuna simulate, read the target every
+    read truly came from
+*/
+pub fn read_truth_table(filename : &str) -> HashMap<String, String> {
+	let f = File::open(filename).expect("file not found");
+	let mut truth : HashMap<String, String> = HashMap::new();
+
+	for line in BufReader::new(f).lines() {
+		let line_val = line.expect("line not available");
+		let fields : Vec<&str> = line_val.split('\t').collect();
+		if fields.len() < 2 || fields[0] == "read_name" {
+			continue;
+		}
+		truth.insert(fields[0].to_string(), fields[1].to_string());
+	}
+	info!("Read the true targets of {} reads", truth.len());
+	truth
+}
+
+/**
+    read_sam_assignments:
+    Given the name of a SAM file, read the targets every read was aligned to, leaving out
+    unmapped records
+*/
+pub fn read_sam_assignments(filename : &str) -> HashMap<String, Vec<String>> {
+	let f = File::open(filename).expect("file not found");
+	let mut assignments : HashMap<String, Vec<String>> = HashMap::new();
+
+	for line in BufReader::new(f).lines() {
+		let line_val = line.expect("line not available");
+		if line_val.starts_with('@') {
+			continue;
+		}
+		let fields : Vec<&str> = line_val.split('\t').collect();
+		if fields.len() < 3 {
+			continue;
+		}
+		let flag : u32 = fields[1].parse::<u32>().expect("SAM flag is not a number");
+		if flag & 4 != 0 || fields[2] == "*" {
+			continue;
+		}
+		let targets : &mut Vec<String> = assignments.entry(fields[0].to_string()).or_default();
+		if !targets.iter().any(|target| target == fields[2]) {
+			targets.push(fields[2].to_string());
+		}
+	}
+	info!("Read the alignments of {} reads", assignments.len());
+	assignments
+}
+
+/**
+    write_eval_report:
+    Write how a quantification compares to the truth as a JSON object, with the accuracy
+    of the read assignments when read-level truth was given
+*/
+pub fn write_eval_report(filename : &str, comparison : &AbundanceComparison, accuracy : Option<&AssignmentAccuracy>) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+
+	let mut fields : Vec<String> = vec![
+		format!("\"num_targets\": {}", comparison.n_targets),
+		format!("\"pearson\": {:.6}", comparison.pearson),
+		format!("\"spearman\": {:.6}", comparison.spearman),
+		format!("\"mard\": {:.6}", comparison.mard),
+	];
+	if let Some(accuracy) = accuracy {
+		fields.push(format!("\"read_assignment\": {{\"num_reads\": {}, \"num_assigned\": {}, \"num_correct\": {:.3}, \"precision\": {:.6}, \"recall\": {:.6}}}",
+			accuracy.n_reads, accuracy.n_assigned, accuracy.n_correct, accuracy.precision(), accuracy.recall()));
+	}
+
+	lw.write_fmt(format_args!("{{\n    {}\n}}\n", fields.join(",\n    "))).expect("Write failed");
+	true
+}
+
+/**
+    write_contaminant_summary:
+    Write the reads removed for every contaminant set, with * as its sequence, followed by
+    the reads removed for each of its sequences
+*/
+pub fn write_contaminant_summary(filename : &str, contaminant_db : &ContaminantDB, contaminant_stats : &ContaminantStats) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+	let percent = |n_reads : usize| if contaminant_stats.n_reads > 0 { 100.0 * n_reads as f64 / contaminant_stats.n_reads as f64 } else { 0.0 };
+
+	lw.write_fmt(format_args!("contaminant_set\tsequence_id\tnum_reads\tpercent_reads\n")).expect("Write failed");
+	for (set_index, set_name) in contaminant_db.set_names.iter().enumerate() {
+		let sequences : Vec<usize> = (0..contaminant_db.seg_ids.len()).filter(|&i| contaminant_db.seg_sets[i] == set_index).collect();
+		let n_reads : usize = sequences.iter().map(|&i| contaminant_stats.sequence_hits[i]).sum();
+		lw.write_fmt(format_args!("{}\t*\t{}\t{:.4}\n", set_name, n_reads, percent(n_reads))).expect("Write failed");
+		for i in sequences {
+			lw.write_fmt(format_args!("{}\t{}\t{}\t{:.4}\n", set_name, contaminant_db.seg_ids[i], contaminant_stats.sequence_hits[i],
+				percent(contaminant_stats.sequence_hits[i]))).expect("Write failed");
+		}
+	}
+	true
+}
+
+/**
+    read_taxonomy:
+    Given the name of a taxonomy file, read the taxon ID, parent taxon ID and optional
+    name of every taxon, one per line. Fields are separated by tabs, or by the tab, pipe
+    and tab of NCBI dmp files. The third field of nodes.dmp is the rank, so names are only
+    taken from tab-separated files.
+*/
+pub fn read_taxonomy(filename : &str) -> Vec<(String, String, Option<String>)> {
+	let f = File::open(filename).expect("file not found");
+	let mut nodes : Vec<(String, String, Option<String>)> = Vec::new();
+
+	for line in BufReader::new(f).lines() {
+		let line_val = line.expect("line not available");
+		let is_dmp : bool = line_val.contains('|');
+		let fields : Vec<&str> = if is_dmp { line_val.split('|').map(|field| field.trim()).collect() } else { line_val.split('\t').collect() };
+		if fields.len() < 2 || fields[0].is_empty() || line_val.starts_with('#') {
+			continue;
+		}
+		let name : Option<String> = fields.get(2).filter(|name| !is_dmp && !name.is_empty()).map(|name| name.to_string());
+		nodes.push((fields[0].to_string(), fields[1].to_string(), name));
+	}
+	info!("Read {} taxa", nodes.len());
+	nodes
+}
+
+/**
+    read_taxon_names:
+    Given the name of an NCBI names.dmp, read the scientific name of every taxon
+*/
+pub fn read_taxon_names(filename : &str) -> HashMap<String, String> {
+	let f = File::open(filename).expect("file not found");
+	let names : HashMap<String, String> = BufReader::new(f).lines()
+		.map(|line| line.expect("line not available"))
+		.filter_map(|line_val| {
+			let fields : Vec<&str> = line_val.split('|').map(|field| field.trim()).collect();
+			if fields.len() < 4 || fields[3] != "scientific name" { None } else { Some((fields[0].to_string(), fields[1].to_string())) }
+		})
+		.collect();
+	info!("Read the names of {} taxa", names.len());
+	names
+}
+
+/**
+    read_seqid2taxid:
+    Given the name of a tab-separated file of sequence IDs and taxon IDs, read the taxon
+    of every sequence
+*/
+pub fn read_seqid2taxid(filename : &str) -> HashMap<String, String> {
+	let f = File::open(filename).expect("file not found");
+	BufReader::new(f).lines()
+		.map(|line| line.expect("line not available"))
+		.filter_map(|line_val| {
+			let fields : Vec<&str> = line_val.split('\t').collect();
+			if fields.len() < 2 { None } else { Some((fields[0].trim().to_string(), fields[1].trim().to_string())) }
+		})
+		.collect()
+}
+
+/**
+    write_taxon_report:
+    Write the reads classified to every taxon and to its clade, with the unclassified
+    reads first and the taxa in decreasing order of clade reads
+*/
+pub fn write_taxon_report(filename : &str, taxon_counts : &TaxonCounts, clade_counts : &HashMap<String, usize>, taxonomy : &Taxonomy) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut lw = LineWriter::new(file);
+	let percent = |n_reads : usize| if taxon_counts.n_reads > 0 { 100.0 * n_reads as f64 / taxon_counts.n_reads as f64 } else { 0.0 };
+
+	lw.write_fmt(format_args!("taxon_id\tname\tnum_reads\tclade_reads\tpercent_clade\n")).expect("Write failed");
+	lw.write_fmt(format_args!("unclassified\tunclassified\t{}\t{}\t{:.4}\n", taxon_counts.n_unclassified, taxon_counts.n_unclassified,
+		percent(taxon_counts.n_unclassified))).expect("Write failed");
+	let mut clades : Vec<(&String, &usize)> = clade_counts.iter().collect();
+	clades.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+	for (taxon_id, &clade_reads) in clades {
+		lw.write_fmt(format_args!("{}\t{}\t{}\t{}\t{:.4}\n", taxon_id, taxonomy.name(taxon_id).unwrap_or(""),
+			taxon_counts.counts.get(taxon_id).cloned().unwrap_or(0), clade_reads, percent(clade_reads))).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_read_classifications:
+    Write the taxon every read was classified to
+*/
+pub fn write_read_classifications(filename : &str, records : &[FqRecord], classifications : &[Option<&str>]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+
+	for (record, classification) in records.iter().zip(classifications.iter()) {
+		writer.write_fmt(format_args!("{}\t{}\n", record.name, classification.unwrap_or("unclassified"))).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_multimapping_reads:
+    Write every read assigned to more than one target, with the IDs of those targets and
+    the number of the read's k-mers found in each
+*/
+pub fn write_multimapping_reads(filename : &str, records : &[FqRecord], outcomes : &[ReadOutcome], segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+
+	writer.write_fmt(format_args!("read_name\tnum_targets\ttarget_ids\tkmer_hits\n")).expect("Write failed");
+	for (record, outcome) in records.iter().zip(outcomes.iter()) {
+		if let ReadOutcome::Assigned(ref read_mapping, ref targets) = *outcome {
+			if targets.len() < 2 {
+				continue;
+			}
+			let target_ids : Vec<&str> = targets.iter().map(|&target| segment_id_strings[target].as_str()).collect();
+			let kmer_hits : Vec<String> = targets.iter()
+				.map(|&target| read_mapping.hits(target).to_string())
+				.collect();
+			writer.write_fmt(format_args!("{}\t{}\t{}\t{}\n", record.name, targets.len(), target_ids.join(","), kmer_hits.join(",")))
+				.expect("Write failed");
+		}
+	}
+	true
+}
+
+/**
+    write_equivalence_classes:
+    Write every equivalence class with its ID, its targets and the number of reads (or
+    molecules) in it
+*/
+pub fn write_equivalence_classes(filename : &str, classes : &[(&Vec<usize>, usize)], segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+
+	writer.write_fmt(format_args!("eq_class\tnum_reads\ttarget_ids\n")).expect("Write failed");
+	for (class_id, &(targets, count)) in classes.iter().enumerate() {
+		let target_ids : Vec<&str> = targets.iter().map(|&target| segment_id_strings[target].as_str()).collect();
+		writer.write_fmt(format_args!("{}\t{}\t{}\n", class_id, count, target_ids.join(","))).expect("Write failed");
+	}
+	true
+}
+
+/**
+    write_read_assignments:
+    Write what became of every read: whether it was assigned, the ID of its equivalence
+    class among the given classes (as written by write_equivalence_classes), the targets
+    it was assigned to and the number of its k-mers found in each
+*/
+pub fn write_read_assignments(filename : &str, records : &[FqRecord], outcomes : &[ReadOutcome], classes : &[(&Vec<usize>, usize)], segment_id_strings : &[String]) -> bool {
+	let file = File::create(filename).expect("Unable to create file");
+	let mut writer = BufWriter::new(file);
+	let class_ids : HashMap<&Vec<usize>, usize> = classes.iter().enumerate().map(|(class_id, &(targets, _))| (targets, class_id)).collect();
+
+	writer.write_fmt(format_args!("read_name\tstatus\teq_class\ttarget_ids\tkmer_hits\n")).expect("Write failed");
+	for (record, outcome) in records.iter().zip(outcomes.iter()) {
+		match *outcome {
+			ReadOutcome::Assigned(ref read_mapping, ref targets) => {
+				let target_ids : Vec<&str> = targets.iter().map(|&target| segment_id_strings[target].as_str()).collect();
+				let kmer_hits : Vec<String> = targets.iter()
+					.map(|&target| read_mapping.hits(target).to_string())
+					.collect();
+				writer.write_fmt(format_args!("{}\tassigned\t{}\t{}\t{}\n", record.name, class_ids[targets], target_ids.join(","), kmer_hits.join(",")))
+					.expect("Write failed");
+			},
+			ReadOutcome::Decoy(_) => writer.write_fmt(format_args!("{}\tdecoy\t*\t*\t*\n", record.name)).expect("Write failed"),
+			ReadOutcome::NoHits => writer.write_fmt(format_args!("{}\tno_hits\t*\t*\t*\n", record.name)).expect("Write failed"),
+		}
+	}
+	true
+}
+
+/**
+    read_assignment_table:
+    Given the name of a read assignments file, read the targets every assigned read was
+    assigned to
+*/
+pub fn read_assignment_table(filename : &str) -> HashMap<String, Vec<String>> {
+	let f = File::open(filename).expect("file not found");
+	let mut assignments : HashMap<String, Vec<String>> = HashMap::new();
+
+	for line in BufReader::new(f).lines() {
+		let line_val = line.expect("line not available");
+		let fields : Vec<&str> = line_val.split('\t').collect();
+		if fields.len() < 4 || fields[1] != "assigned" {
+			continue;
+		}
+		assignments.insert(fields[0].to_string(), fields[3].split(',').map(|target_id| target_id.to_string()).collect());
+	}
+	info!("Read the assignments of {} reads", assignments.len());
+	assignments
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Cursor;
+
+	fn parse(text : &str) -> Vec<FqRecord> {
+		FqRecords::new(Cursor::new(text.as_bytes().to_vec())).collect()
+	}
+
+	#[test]
+	fn keeps_ambiguous_bases_in_line_with_the_qualities() {
+		let records = parse("@r1 extra\nACGNTacgu\n+\n@IIIIIII#\n@r2\nAC.G\n+\nIIII\n");
+		assert_eq!(records.len(), 2);
+		assert_eq!((records[0].name.as_str(), records[0].seq.as_str()), ("r1", "ACGNTACGU"));
+		assert_eq!(records[0].qual.as_deref(), Some("@IIIIIII#"));
+		assert_eq!(records[1].seq, "ACNG");
+		assert_eq!(records[1].seq.len(), records[1].qual.as_ref().map_or(0, |qual| qual.len()));
+	}
+
+	#[test]
+	fn joins_multi_line_fasta_and_names_records_without_a_header() {
+		let records = parse("ACGT\n>r2 description\nAAAA\nCCCC\n>\nGG\n");
+		let names : Vec<&str> = records.iter().map(|record| record.name.as_str()).collect();
+		assert_eq!(names, vec!["read1", "r2", "read3"]);
+		assert_eq!(records[1].seq, "AAAACCCC");
+		assert!(records.iter().all(|record| record.qual.is_none()));
+	}
+
+	#[test]
+	fn decoys_follow_the_targets() {
+		let path = std::env::temp_dir().join("tuna_test_targets.fa");
+		std::fs::write(&path, ">t1 gene:g1\nACGT\nACGT\n>t2\nGGGG\n").unwrap();
+		let mut fa_col_db = read_fa_file_to_cols(path.to_str().unwrap());
+		std::fs::write(&path, ">d1\nTTTT\n").unwrap();
+		fa_col_db.append_decoys(read_fa_file_to_cols(path.to_str().unwrap()));
+
+		assert_eq!(fa_col_db.n_targets, 2);
+		assert_eq!(fa_col_db.target_ids(), &["t1".to_string(), "t2".to_string()]);
+		assert_eq!(fa_col_db.seg_strings, vec!["ACGTACGT", "GGGG", "TTTT"]);
+		assert_eq!(fa_col_db.seg_metadata[0].gene_id(), Some("g1"));
+
+		fa_col_db.retain_segments(&[1, 2]);
+		assert_eq!(fa_col_db.n_targets, 1);
+		assert_eq!(fa_col_db.seg_ids, vec!["t2", "d1"]);
+	}
+
+	#[test]
+	fn reads_single_and_paired_end_samples() {
+		let dir = std::env::temp_dir().join("tuna_test_sample_sheet");
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("samples.tsv");
+		std::fs::write(&path, "#name\treads\nA\ta.fq\n\nB,b_1.fq,b_2.fq\nC\n").unwrap();
+		let samples = read_sample_sheet(path.to_str().unwrap());
+
+		assert_eq!(samples.len(), 2);
+		assert_eq!(samples[0].read_filename, dir.join("a.fq").to_string_lossy());
+		assert_eq!(samples[0].mate_filename, None);
+		assert_eq!(samples[1].name, "B");
+		assert_eq!(samples[1].mate_filename, Some(dir.join("b_2.fq").to_string_lossy().into_owned()));
+	}
+
+	#[test]
+	fn writes_space_separated_matrix_market() {
+		let dir = std::env::temp_dir().join("tuna_test_cell_matrix");
+		let cell_matrix = CellMatrix { barcodes : vec!["AAAA".to_string(), "CCCC".to_string()], entries : vec![(0, 1, 2), (1, 0, 1)] };
+		let gene_map = GeneMap { gene_ids : vec!["g1".to_string(), "g2".to_string()], segment_genes : vec![0, 1] };
+		let seg_metadata : Vec<SegMetadata> = vec![SegMetadata::parse("t1", "gene_symbol:ABC"), SegMetadata::default()];
+		write_cell_matrix(dir.to_str().unwrap(), &cell_matrix, &gene_map, &seg_metadata);
+
+		assert_eq!(std::fs::read_to_string(dir.join("matrix.mtx")).unwrap(),
+			"%%MatrixMarket matrix coordinate integer general\n2 2 2\n1 2 2\n2 1 1\n");
+		assert_eq!(std::fs::read_to_string(dir.join("barcodes.tsv")).unwrap(), "AAAA\nCCCC\n");
+		assert_eq!(std::fs::read_to_string(dir.join("features.tsv")).unwrap(), "g1\tABC\tGene Expression\ng2\tg2\tGene Expression\n");
+
+		let cell_stats = CellStats { n_pairs : 10, n_barcode_corrected : 1, n_barcode_rejected : 2, n_unassigned : 3, n_multigene : 1, n_assigned : 4 };
+		write_cell_summary(dir.to_str().unwrap(), &cell_stats, cell_matrix.barcodes.len());
+		assert_eq!(std::fs::read_to_string(dir.join("summary.tsv")).unwrap().lines().nth(1), Some("10\t4\t1\t2\t3\t1\t2"));
+	}
+
+	fn trim_options() -> TrimOptions {
+		TrimOptions { adapter : Some("AGATCGGAAGAGC".to_string()), poly_a : true, quality_cutoff : Some(20), min_length : 5, ..TrimOptions::default() }
+	}
+
+	#[test]
+	fn finds_adapters_and_partial_adapters_at_the_3p_end() {
+		assert_eq!(find_adapter("ACGTACGTAGATCGGAAGAGCTT", "AGATCGGAAGAGC"), Some(8));
+		//One mismatch in thirteen bases is within the allowed rate
+		assert_eq!(find_adapter("ACGTACGTAGATCGCAAGAGC", "AGATCGGAAGAGC"), Some(8));
+		assert_eq!(find_adapter("ACGTACGTACGTAGAT", "AGATCGGAAGAGC"), Some(12));
+		assert_eq!(find_adapter("ACGTACGTACGTACAG", "AGATCGGAAGAGC"), None);
+	}
+
+	#[test]
+	fn trims_poly_a_and_low_quality_tails() {
+		assert_eq!(poly_a_length("ACGTAAAAAAAA"), 8);
+		assert_eq!(poly_a_length("ACGATTTT"), 4);
+		assert_eq!(poly_a_length("ACGTC"), 0);
+		assert_eq!(quality_trim_length("IIIIIIII####", 20), 8);
+		assert_eq!(quality_trim_length("IIIIII###I", 20), 6);
+		assert_eq!(quality_trim_length("II#", 20), 2);
+		assert_eq!(quality_trim_length("IIIIIIII", 20), 8);
+	}
+
+	#[test]
+	fn trims_mates_in_lockstep() {
+		let mut records = parse("@r1\nACGTACGTAGATCGGAAGAGC\n+\nIIIIIIIIIIIIIIIIIIIII\n@r2\nACGTACGTCCAAAAAAAAAA\n+\nIIIIIIIIIIIIIIIIIIII\n@r3\nACGTACGTCC\n+\nIIIIIIIIII\n");
+		let mut mates = parse("@r1\nGGCCGGCCGG\n+\nIIIIIIIIII\n@r2\nGGCCGGCCGG\n+\nIIIIIIIIII\n@r3\nGGAGATCGGAAGAGC\n+\nIIIIIIIIIIIIIII\n");
+		let trim_stats = trim_records(&mut records, Some(&mut mates), &trim_options());
+
+		assert_eq!(records.iter().map(|record| record.seq.as_str()).collect::<Vec<&str>>(), vec!["ACGTACGT", "ACGTACGTCC"]);
+		assert_eq!(records[1].qual.as_deref(), Some("IIIIIIIIII"));
+		assert_eq!(mates.iter().map(|mate| mate.name.as_str()).collect::<Vec<&str>>(), vec!["r1", "r2"]);
+		assert_eq!((trim_stats.n_reads, trim_stats.n_adapter_trimmed, trim_stats.n_poly_a_trimmed, trim_stats.n_too_short), (3, 2, 1, 1));
+	}
+
+	#[test]
+	fn trims_cdna_reads_and_drops_their_barcode_reads_alike() {
+		let mut records = parse("@r1\nACGTACGTAGATCGGAAGAGC\n+\nIIIIIIIIIIIIIIIIIIIII\n@r2\nAGATCGGAAGAGCTT\n+\nIIIIIIIIIIIIIII\n");
+		//A barcode and UMI that would lose its end to poly-A trimming
+		let mut barcode_reads = parse("@r1\nCCCCGGGGAAAAAAAA\n+\nIIIIIIIIIIIIIIII\n@r2\nTTTTGGGGAAAAAAAA\n+\nIIIIIIIIIIIIIIII\n");
+		let trim_stats = trim_cdna_reads(&mut records, &mut barcode_reads, &trim_options());
+
+		assert_eq!(records.iter().map(|record| record.seq.as_str()).collect::<Vec<&str>>(), vec!["ACGTACGT"]);
+		assert_eq!(barcode_reads.iter().map(|record| record.seq.as_str()).collect::<Vec<&str>>(), vec!["CCCCGGGGAAAAAAAA"]);
+		assert_eq!((trim_stats.n_reads, trim_stats.n_adapter_trimmed, trim_stats.n_poly_a_trimmed, trim_stats.n_too_short), (2, 2, 0, 1));
+	}
+
+	#[test]
+	fn detects_adapters_enriched_at_3p_ends() {
+		let adapter : &str = "TTAGGCATCGGATCCAGTAC";
+		//Pseudorandom inserts, so that no k-mer of theirs is overrepresented
+		let inserts : Vec<String> = (0..40u64).map(|i| {
+			let mut x : u64 = i + 1;
+			(0..30).map(|_| {
+				x = (x * 1103515245 + 12345) % (1 << 31);
+				['A', 'C', 'G', 'T'][((x >> 16) % 4) as usize]
+			}).collect()
+		}).collect();
+		let with_adapter : Vec<FqRecord> = inserts.iter()
+			.map(|insert| FqRecord { name : String::new(), seq : format!("{}{}", insert, adapter), qual : None, umi : None })
+			.collect();
+		assert_eq!(detect_adapter(&with_adapter).as_deref(), Some(adapter));
+
+		//The same sequence at the 5' end of the reads is not an adapter
+		let at_5p : Vec<FqRecord> = inserts.iter()
+			.map(|insert| FqRecord { name : String::new(), seq : format!("{}{}", adapter, insert), qual : None, umi : None })
+			.collect();
+		assert_eq!(detect_adapter(&at_5p), None);
+	}
+
+	#[test]
+	fn packs_kmers_two_bits_a_base() {
+		let path = std::env::temp_dir().join("tuna_test_kmers.bin");
+		let counts : Vec<(String, usize)> = vec![("ACGTT".to_string(), 7), ("TTTTT".to_string(), 1)];
+		write_kmer_counts(path.to_str().unwrap(), KmerFormat::Binary, 5, true, &counts, 2);
+		let bytes : Vec<u8> = std::fs::read(&path).unwrap();
+
+		assert_eq!(&bytes[..8], b"TUNAKMER");
+		assert_eq!(&bytes[8..12], &5u32.to_le_bytes());
+		assert_eq!(bytes[12], 1);
+		assert_eq!(&bytes[13..21], &1u64.to_le_bytes());
+		//ACGT fills the first byte and the last T the top bits of the second
+		assert_eq!(&bytes[21..23], &[0b0001_1011, 0b1100_0000]);
+		assert_eq!(&bytes[23..], &7u32.to_le_bytes());
+	}
+
+	#[test]
+	fn reads_quant_columns_by_header_and_unescapes_json() {
+		let path = std::env::temp_dir().join("tuna_test_quant.tsv");
+		std::fs::write(&path, "Name\tLength\tTPM\tNumReads\nt1\t100\t25.5\t3\nt2\t50\t74.5\t4\n").unwrap();
+		let tpm = read_quant_column(path.to_str().unwrap(), "TPM");
+		assert_eq!(tpm.get("t2"), Some(&74.5));
+
+		std::fs::write(&path, "t1\t3\nt2\t4\n").unwrap();
+		assert_eq!(read_quant_column(path.to_str().unwrap(), "NumReads").get("t1"), Some(&3.0));
+
+		std::fs::write(&path, "{\"Name\": \"t1,\\\"a\\\"\\u00e9\", \"TPM\": 2.5, \"NumReads\": 3.000}\n").unwrap();
+		let json = read_quant_column(path.to_str().unwrap(), "NumReads");
+		assert_eq!(json.get("t1,\"a\"\u{e9}"), Some(&3.0));
+	}
+
+	#[test]
+	#[should_panic(expected = "has no header")]
+	fn rejects_named_columns_of_headerless_counts() {
+		let path = std::env::temp_dir().join("tuna_test_headerless.tsv");
+		std::fs::write(&path, "t1\t3\nt2\t4\n").unwrap();
+		read_quant_column(path.to_str().unwrap(), "TPM");
+	}
+
+	#[test]
+	fn reads_assigned_targets_from_assignment_tables() {
+		let path = std::env::temp_dir().join("tuna_test_assignment_table.tsv");
+		std::fs::write(&path, "read_name\tstatus\teq_class\ttarget_ids\tkmer_hits\nr1\tassigned\t0\tt1,t2\t5,5\nr2\tno_hits\t*\t*\t*\n").unwrap();
+		let assignments = read_assignment_table(path.to_str().unwrap());
+		assert_eq!(assignments.len(), 1);
+		assert_eq!(assignments["r1"], vec!["t1", "t2"]);
+	}
+
+	#[test]
+	fn numbers_read_classes_as_in_the_class_table() {
+		use dna_hash_table::ReadMapping;
+		use dna_hash_table::SegmentCounts;
+
+		let mapping = |hits : &[(usize, usize)]| ReadMapping::from_hit_counts(hits.iter().cloned().collect()).unwrap();
+		let mut segment_counts = SegmentCounts::default();
+		segment_counts.equivalence_classes.insert(vec![1], 1);
+		segment_counts.equivalence_classes.insert(vec![0, 1], 1);
+		let outcomes : Vec<ReadOutcome> = vec![
+			ReadOutcome::Assigned(mapping(&[(0, 5), (1, 5)]), vec![0, 1]),
+			ReadOutcome::NoHits,
+			ReadOutcome::Decoy(mapping(&[(2, 4)])),
+			ReadOutcome::Assigned(mapping(&[(0, 1), (1, 3)]), vec![1]),
+		];
+		let records : Vec<FqRecord> = parse("@r1\nA\n+\nI\n@r2\nA\n+\nI\n@r3\nA\n+\nI\n@r4\nA\n+\nI\n");
+		let segment_ids : Vec<String> = vec!["t1".to_string(), "t2".to_string(), "decoy".to_string()];
+		let classes = segment_counts.numbered_classes();
+
+		let classes_path = std::env::temp_dir().join("tuna_test_eq_classes.tsv");
+		write_equivalence_classes(classes_path.to_str().unwrap(), &classes, &segment_ids);
+		assert_eq!(std::fs::read_to_string(&classes_path).unwrap(), "eq_class\tnum_reads\ttarget_ids\n0\t1\tt1,t2\n1\t1\tt2\n");
+
+		let assignments_path = std::env::temp_dir().join("tuna_test_read_assignments.tsv");
+		write_read_assignments(assignments_path.to_str().unwrap(), &records, &outcomes, &classes, &segment_ids);
+		assert_eq!(std::fs::read_to_string(&assignments_path).unwrap(), "read_name\tstatus\teq_class\ttarget_ids\tkmer_hits\n\
+			r1\tassigned\t0\tt1,t2\t5,5\nr2\tno_hits\t*\t*\t*\nr3\tdecoy\t*\t*\t*\nr4\tassigned\t1\tt2\t3\n");
+		let assigned = read_assignment_table(assignments_path.to_str().unwrap());
+		assert_eq!(assigned.len(), 2);
+		assert_eq!(assigned["r4"], vec!["t2"]);
+
+		let multimapping_path = std::env::temp_dir().join("tuna_test_multimapping.tsv");
+		write_multimapping_reads(multimapping_path.to_str().unwrap(), &records, &outcomes, &segment_ids);
+		assert_eq!(std::fs::read_to_string(&multimapping_path).unwrap(), "read_name\tnum_targets\ttarget_ids\tkmer_hits\nr1\t2\tt1,t2\t5,5\n");
+	}
+
+	#[test]
+	fn keeps_masked_bases_and_gaps_of_genomes_in_place() {
+		use dna_spliced_aligner::JunctionSet;
+		use gene_annotation::GeneAnnotation;
+
+		let path = std::env::temp_dir().join("tuna_test_masked_genome.fa");
+		std::fs::write(&path, ">chr1 soft-masked\nACGTACGTNNNNNNNNacgtacgtGGCCAATT\nnnnnCCGGTTAA\n").unwrap();
+		let genome = read_fa_file_to_cols(path.to_str().unwrap());
+		assert_eq!(genome.seg_strings[0].len(), 44);
+		assert_eq!(&genome.seg_strings[0][8..24], "NNNNNNNNACGTACGT");
+
+		//The second exon lies after the masked run and the gap, so its bases are only found if none were dropped
+		let gtf_path = std::env::temp_dir().join("tuna_test_masked_genome.gtf");
+		std::fs::write(&gtf_path, "chr1\ttest\texon\t25\t32\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+			chr1\ttest\texon\t37\t44\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n").unwrap();
+		let annotation = GeneAnnotation::read_annotation(gtf_path.to_str().unwrap());
+		let junction_set = JunctionSet::new(&annotation, &genome.seg_ids, &genome.seg_strings, 4, 5);
+		assert_eq!(junction_set.sequences, vec!["AATTCCGG".to_string()]);
+	}
+
+	#[test]
+	fn keeps_reads_spanning_the_gaps_of_decoys() {
+		use dna_hash_table::DNAHashTable;
+		use dna_hash_table::get_segments;
+
+		let path = std::env::temp_dir().join("tuna_test_gap_target.fa");
+		std::fs::write(&path, ">t1\nTAGCAGGTTTTT\n").unwrap();
+		let mut fa_col_db = read_fa_file_to_cols(path.to_str().unwrap());
+		//Joined across its gap and masked run, the decoy would hold every k-mer of the read
+		std::fs::write(&path, ">chr1\nCGTTAGCA\nNNNNNN\nggtacCATG\n").unwrap();
+		fa_col_db.append_decoys(read_fa_file_to_cols(path.to_str().unwrap()));
+		assert_eq!(fa_col_db.seg_strings[1], "CGTTAGCANNNNNNGGTACCATG");
+
+		let kmer_hash_table = DNAHashTable::new(&fa_col_db.seg_strings, 7);
+		let segment_counts = get_segments(&kmer_hash_table, &["TAGCAGGTAC".to_string()], fa_col_db.n_targets, false);
+		assert_eq!(segment_counts.mapping_stats.n_decoy, 0);
+		assert_eq!(segment_counts.counts.get(&0), Some(&1));
+	}
+
+	#[test]
+	fn reads_names_from_names_dmp_rather_than_ranks_of_nodes_dmp() {
+		let nodes_path = std::env::temp_dir().join("tuna_test_nodes.dmp");
+		std::fs::write(&nodes_path, "1\t|\t1\t|\tno rank\t|\n562\t|\t2\t|\tspecies\t|\n").unwrap();
+		let nodes = read_taxonomy(nodes_path.to_str().unwrap());
+		assert_eq!(nodes, vec![("1".to_string(), "1".to_string(), None), ("562".to_string(), "2".to_string(), None)]);
+
+		let tsv_path = std::env::temp_dir().join("tuna_test_taxonomy.tsv");
+		std::fs::write(&tsv_path, "#taxon\tparent\tname\n562\t2\tEscherichia coli\n").unwrap();
+		assert_eq!(read_taxonomy(tsv_path.to_str().unwrap()), vec![("562".to_string(), "2".to_string(), Some("Escherichia coli".to_string()))]);
+
+		let names_path = std::env::temp_dir().join("tuna_test_names.dmp");
+		std::fs::write(&names_path, "562\t|\tBacillus coli\t|\t\t|\tsynonym\t|\n562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n").unwrap();
+		let names = read_taxon_names(names_path.to_str().unwrap());
+		assert_eq!(names.len(), 1);
+		assert_eq!(names["562"], "Escherichia coli");
+	}
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use log::*;
+
+use read_inputs::FqRecord;
+use read_inputs::FqRecords;
+
+//Bases counted at each position, with every other code counted as N
+pub const BASES : [&str; 5] = ["A", "C", "G", "T", "N"];
+const PHRED_OFFSET : u8 = 33;
+//Distinct sequences tracked for the duplication rate and overrepresented sequences. Once this many have
+//been seen, only further copies of those already tracked are counted, as FastQC does.
+const DUPLICATION_SAMPLE : usize = 100_000;
+//Sequences are tracked by their first bases only, so that reads differing in a low-quality tail still match
+const DUPLICATION_PREFIX : usize = 50;
+//Fraction of all reads a sequence must make up to be reported as overrepresented
+const OVERREPRESENTED_FRACTION : f64 = 0.001;
+const MAX_OVERREPRESENTED : usize = 20;
+
+//Read statistics of a fq or fasta file, gathered one record at a time
+pub struct ReadQc {
+	pub n_reads : usize,
+	pub n_bases : usize,
+	pub n_with_quality : usize,
+	pub length_counts : BTreeMap<usize, usize>,
+	//Counts of A, C, G, T and N at each position of the reads
+	pub base_counts : Vec<[usize; 5]>,
+	quality_sums : Vec<u64>,
+	quality_counts : Vec<usize>,
+	//Reads by the percentage of their called bases that are G or C
+	pub gc_counts : Vec<usize>,
+	sequence_counts : HashMap<String, usize>,
+	n_duplication_sampled : usize,
+}
+
+impl Default for ReadQc {
+	fn default() -> ReadQc {
+		ReadQc {
+			n_reads : 0,
+			n_bases : 0,
+			n_with_quality : 0,
+			length_counts : BTreeMap::new(),
+			base_counts : Vec::new(),
+			quality_sums : Vec::new(),
+			quality_counts : Vec::new(),
+			gc_counts : vec![0; 101],
+			sequence_counts : HashMap::new(),
+			n_duplication_sampled : 0,
+		}
+	}
+}
+
+impl ReadQc {
+	pub fn add(&mut self, record : &FqRecord) {
+		let seq : &[u8] = record.seq.as_bytes();
+		self.n_reads += 1;
+		self.n_bases += seq.len();
+		*self.length_counts.entry(seq.len()).or_insert(0) += 1;
+
+		if self.base_counts.len() < seq.len() {
+			self.base_counts.resize(seq.len(), [0; 5]);
+		}
+		let mut n_gc : usize = 0;
+		let mut n_called : usize = 0;
+		for (position, &base) in seq.iter().enumerate() {
+			let base_index : usize = match base.to_ascii_uppercase() {
+				b'A' => 0,
+				b'C' => 1,
+				b'G' => 2,
+				b'T' | b'U' => 3,
+				_ => 4,
+			};
+			self.base_counts[position][base_index] += 1;
+			if base_index < 4 {
+				n_called += 1;
+			}
+			if base_index == 1 || base_index == 2 {
+				n_gc += 1;
+			}
+		}
+		if n_called > 0 {
+			self.gc_counts[(100.0 * n_gc as f64 / n_called as f64).round() as usize] += 1;
+		}
+
+		if let Some(ref qual) = record.qual {
+			self.n_with_quality += 1;
+			let qual : &[u8] = &qual.as_bytes()[..qual.len().min(seq.len())];
+			if self.quality_sums.len() < qual.len() {
+				self.quality_sums.resize(qual.len(), 0);
+				self.quality_counts.resize(qual.len(), 0);
+			}
+			for (position, &q) in qual.iter().enumerate() {
+				self.quality_sums[position] += q.saturating_sub(PHRED_OFFSET) as u64;
+				self.quality_counts[position] += 1;
+			}
+		}
+
+		let prefix : &str = &record.seq[..record.seq.len().min(DUPLICATION_PREFIX)];
+		if let Some(count) = self.sequence_counts.get_mut(prefix) {
+			*count += 1;
+			self.n_duplication_sampled += 1;
+		} else if self.sequence_counts.len() < DUPLICATION_SAMPLE {
+			self.sequence_counts.insert(prefix.to_string(), 1);
+			self.n_duplication_sampled += 1;
+		}
+	}
+
+	pub fn mean_length(&self) -> f64 {
+		if self.n_reads > 0 { self.n_bases as f64 / self.n_reads as f64 } else { 0.0 }
+	}
+
+	//Percentage of all called bases that are G or C
+	pub fn gc_percent(&self) -> f64 {
+		let (n_gc, n_called) = self.base_counts.iter()
+			.fold((0, 0), |(n_gc, n_called), counts| (n_gc + counts[1] + counts[2], n_called + counts[..4].iter().sum::<usize>()));
+		if n_called > 0 { 100.0 * n_gc as f64 / n_called as f64 } else { 0.0 }
+	}
+
+	pub fn n_percent(&self) -> f64 {
+		let n_n : usize = self.base_counts.iter().map(|counts| counts[4]).sum();
+		if self.n_bases > 0 { 100.0 * n_n as f64 / self.n_bases as f64 } else { 0.0 }
+	}
+
+	//Percentage of the reads at each position carrying each of A, C, G, T and N
+	pub fn base_percents(&self) -> Vec<[f64; 5]> {
+		self.base_counts.iter()
+			.map(|counts| {
+				let total : usize = counts.iter().sum();
+				let mut percents : [f64; 5] = [0.0; 5];
+				for (percent, &count) in percents.iter_mut().zip(counts.iter()) {
+					*percent = 100.0 * count as f64 / total as f64;
+				}
+				percents
+			})
+			.collect()
+	}
+
+	//Mean Phred quality at each position, for fq files
+	pub fn mean_qualities(&self) -> Vec<f64> {
+		self.quality_sums.iter().zip(self.quality_counts.iter())
+			.map(|(&sum, &count)| sum as f64 / count as f64)
+			.collect()
+	}
+
+	//Fraction of the sampled reads that repeat a sequence seen before
+	pub fn duplication_rate(&self) -> f64 {
+		if self.n_duplication_sampled > 0 {
+			1.0 - self.sequence_counts.len() as f64 / self.n_duplication_sampled as f64
+		} else {
+			0.0
+		}
+	}
+
+	//Sequences making up at least a thousandth of the reads, most frequent first, with their counts
+	pub fn overrepresented(&self) -> Vec<(&str, usize)> {
+		let min_count : f64 = self.n_reads as f64 * OVERREPRESENTED_FRACTION;
+		let mut overrepresented : Vec<(&str, usize)> = self.sequence_counts.iter()
+			.filter(|&(_, &count)| count > 1 && count as f64 >= min_count)
+			.map(|(sequence, &count)| (sequence.as_str(), count))
+			.collect();
+		overrepresented.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+		overrepresented.truncate(MAX_OVERREPRESENTED);
+		overrepresented
+	}
+}
+
+/**
+    qc_reads:
+    Stream the records of a fq or fasta file and gather their read statistics
+*/
+pub fn qc_reads(filename : &str) -> ReadQc {
+	let mut read_qc = ReadQc::default();
+	for record in FqRecords::open(filename) {
+		read_qc.add(&record);
+	}
+	info!("Read {} reads of mean length {:.1}, {:.2}% GC, {:.2}% duplicated", read_qc.n_reads, read_qc.mean_length(),
+		read_qc.gc_percent(), 100.0 * read_qc.duplication_rate());
+	read_qc
+}
+
+/**
+    svg_chart:
+    Draw one or more series of (x, y) points as an inline SVG chart, as bars when asked
+    for and as lines otherwise, with a legend when there is more than one series
+*/
+pub fn svg_chart(series : &[(&str, Vec<(f64, f64)>)], x_label : &str, y_label : &str, bars : bool) -> String {
+	const WIDTH : f64 = 640.0;
+	const HEIGHT : f64 = 240.0;
+	const MARGIN : f64 = 40.0;
+	const COLOURS : [&str; 5] = ["#2b8a3e", "#1971c2", "#f08c00", "#c92a2a", "#868e96"];
+
+	let points = series.iter().flat_map(|(_, points)| points.iter());
+	let (x_max, y_max) = points.fold((1.0f64, 1.0f64), |(x_max, y_max), &(x, y)| (x_max.max(x), y_max.max(y)));
+	let to_x = |x : f64| MARGIN + x / x_max * (WIDTH - 2.0 * MARGIN);
+	let to_y = |y : f64| HEIGHT - MARGIN - y / y_max * (HEIGHT - 2.0 * MARGIN);
+
+	let mut svg : String = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">", WIDTH, HEIGHT);
+	svg.push_str(&format!("<line x1=\"{m}\" y1=\"{b}\" x2=\"{r}\" y2=\"{b}\" stroke=\"black\"/><line x1=\"{m}\" y1=\"{m}\" x2=\"{m}\" y2=\"{b}\" stroke=\"black\"/>",
+		m = MARGIN, b = HEIGHT - MARGIN, r = WIDTH - MARGIN));
+	svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" font-size=\"12\">{}</text>", WIDTH / 2.0, HEIGHT - 8.0, x_label));
+	svg.push_str(&format!("<text x=\"12\" y=\"{}\" font-size=\"12\" transform=\"rotate(-90 12 {})\" text-anchor=\"middle\">{}</text>",
+		HEIGHT / 2.0, HEIGHT / 2.0, y_label));
+	svg.push_str(&format!("<text x=\"{}\" y=\"{}\" font-size=\"10\" text-anchor=\"end\">{:.4}</text>", MARGIN - 4.0, MARGIN + 4.0, y_max));
+	svg.push_str(&format!("<text x=\"{}\" y=\"{}\" font-size=\"10\" text-anchor=\"end\">{}</text>", WIDTH - MARGIN, HEIGHT - MARGIN + 14.0, x_max));
+
+	for (i, &(name, ref points)) in series.iter().enumerate() {
+		let colour : &str = COLOURS[i % COLOURS.len()];
+		if bars {
+			let bar_width : f64 = ((WIDTH - 2.0 * MARGIN) / (x_max + 1.0)).max(1.0);
+			for &(x, y) in points {
+				svg.push_str(&format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"/>",
+					to_x(x) - bar_width / 2.0, to_y(y), bar_width, HEIGHT - MARGIN - to_y(y), colour));
+			}
+		} else {
+			let path : Vec<String> = points.iter().map(|&(x, y)| format!("{:.1},{:.1}", to_x(x), to_y(y))).collect();
+			svg.push_str(&format!("<polyline fill=\"none\" stroke=\"{}\" points=\"{}\"/>", colour, path.join(" ")));
+		}
+		if series.len() > 1 {
+			svg.push_str(&format!("<text x=\"{}\" y=\"{}\" font-size=\"12\" fill=\"{}\">{}</text>", WIDTH - MARGIN + 4.0, MARGIN + 14.0 * i as f64, colour, name));
+		}
+	}
+	svg.push_str("</svg>");
+	svg
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	fn qc(fq : &str) -> ReadQc {
+		let mut read_qc = ReadQc::default();
+		for record in FqRecords::new(Cursor::new(fq.to_string())) {
+			read_qc.add(&record);
+		}
+		read_qc
+	}
+
+	#[test]
+	fn counts_lengths_bases_and_qualities() {
+		let read_qc = qc("@r1\nACGT\n+\nII#I\n@r2\nGGNU\n+\nIIII\n@r3\nAC\n+\n##\n");
+		assert_eq!((read_qc.n_reads, read_qc.n_bases, read_qc.n_with_quality), (3, 10, 3));
+		assert_eq!(read_qc.length_counts.iter().map(|(&length, &count)| (length, count)).collect::<Vec<(usize, usize)>>(), vec![(2, 1), (4, 2)]);
+		assert_eq!(read_qc.base_counts[2], [0, 0, 1, 0, 1]);
+		//U is counted as T, and N is left out of the GC percentage
+		assert_eq!(read_qc.base_counts[3], [0, 0, 0, 2, 0]);
+		assert!((read_qc.gc_percent() - 100.0 * 5.0 / 9.0).abs() < 1e-9);
+		assert!((read_qc.n_percent() - 10.0).abs() < 1e-9);
+		assert_eq!(read_qc.mean_qualities(), vec![82.0 / 3.0, 82.0 / 3.0, 21.0, 40.0]);
+		assert_eq!(read_qc.base_percents()[3], [0.0, 0.0, 0.0, 100.0, 0.0]);
+		assert_eq!(read_qc.gc_counts[50], 2);
+		assert_eq!(read_qc.gc_counts[67], 1);
+	}
+
+	#[test]
+	fn reports_duplicated_and_overrepresented_sequences() {
+		let read_qc = qc(">r1\nACGT\n>r2\nACGT\n>r3\nACGT\n>r4\nTTTT\n>r5\nGGGG\n>r6\nGGGG\n");
+		assert_eq!(read_qc.n_with_quality, 0);
+		assert!(read_qc.mean_qualities().is_empty());
+		assert!((read_qc.duplication_rate() - 0.5).abs() < 1e-9);
+		assert_eq!(read_qc.overrepresented(), vec![("ACGT", 3), ("GGGG", 2)]);
+	}
+
+	#[test]
+	fn draws_bars_lines_and_legends() {
+		let bars : String = svg_chart(&[("reads", vec![(0.0, 1.0), (1.0, 2.0)])], "length", "count", true);
+		assert_eq!(bars.matches("<rect").count(), 2);
+		assert!(!bars.contains("<polyline") && !bars.contains(">reads<"));
+
+		let lines : String = svg_chart(&[("A", vec![(0.0, 1.0)]), ("C", vec![(0.0, 2.0)])], "position", "%", false);
+		assert_eq!(lines.matches("<polyline").count(), 2);
+		assert!(lines.contains(">A<") && lines.contains(">C<") && lines.ends_with("</svg>"));
+	}
+}
@@ -0,0 +1,345 @@
+use std::fs::File;
+use std::io::Write;
+use std::io::BufWriter;
+use std::env;
+
+use flate2::Compression;
+use flate2::Crc;
+use flate2::write::DeflateEncoder;
+
+use dna_aligner::Alignment;
+use dna_aligner::SeedExtendAligner;
+use library_type::reverse_complement;
+use read_inputs::FqRecord;
+
+const FLAG_UNMAPPED : u16 = 0x4;
+const FLAG_REVERSE : u16 = 0x10;
+const FLAG_SECONDARY : u16 = 0x100;
+const PHRED_OFFSET : u8 = 33;
+//Uncompressed bytes gathered into each BGZF block, as htslib does, so that even incompressible data fits
+//the 64 KiB limit on a block
+const BGZF_BLOCK_SIZE : usize = 0xff00;
+//The empty block every BGZF file ends with
+const BGZF_EOF : [u8; 28] = [0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, 0x42, 0x43, 0x02, 0, 0x1b, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+//BAM codes of the bases, in the order of their 4-bit values
+const BAM_BASES : &[u8] = b"=ACMGRSVTWYHKDBN";
+const BAM_CIGAR_OPS : &[u8] = b"MIDNSHP=X";
+
+//The header of a SAM or BAM file, with one @SQ line per segment
+fn header_text(seg_ids : &[String], seg_strings : &[String]) -> String {
+	let mut header : String = "@HD\tVN:1.6\tSO:unsorted\n".to_string();
+	for (seg_id, seg_string) in seg_ids.iter().zip(seg_strings.iter()) {
+		header.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", seg_id, seg_string.len()));
+	}
+	let command_line : Vec<String> = env::args().collect();
+	header.push_str(&format!("@PG\tID:tuna\tPN:tuna\tVN:{}\tCL:{}\n", env!("CARGO_PKG_VERSION"), command_line.join(" ")));
+	header
+}
+
+//One record of a read, ready to be written as SAM text or encoded as BAM. Secondary records carry no
+//sequence or quality.
+struct SamRecord<'r> {
+	name : &'r str,
+	flag : u16,
+	alignment : Option<&'r Alignment>,
+	mapq : u8,
+	seq : Option<String>,
+	qual : Option<String>,
+}
+
+//The best alignment of the read as the primary record and the others as secondary records, or a single
+//unmapped record if the read did not align. Reverse alignments carry the reverse complement of the read.
+fn sam_records<'r>(record : &'r FqRecord, alignments : &'r [Alignment]) -> Vec<SamRecord<'r>> {
+	if alignments.is_empty() {
+		return vec![SamRecord { name : &record.name, flag : FLAG_UNMAPPED, alignment : None, mapq : 0,
+			seq : Some(record.seq.clone()), qual : record.qual.clone() }];
+	}
+
+	let mapq : u8 = SeedExtendAligner::mapping_quality(alignments);
+	alignments.iter().enumerate()
+		.map(|(i, alignment)| {
+			let strand_flag : u16 = if alignment.reverse { FLAG_REVERSE } else { 0 };
+			let (seq, qual) = match (i, alignment.reverse) {
+				(0, false) => (Some(record.seq.clone()), record.qual.clone()),
+				(0, true) => (Some(reverse_complement(&record.seq)), record.qual.as_ref().map(|qual| qual.chars().rev().collect())),
+				_ => (None, None),
+			};
+			SamRecord {
+				name : &record.name,
+				flag : strand_flag | if i == 0 { 0 } else { FLAG_SECONDARY },
+				alignment : Some(alignment),
+				mapq,
+				seq,
+				qual,
+			}
+		})
+		.collect()
+}
+
+//Writes reads and their alignments as SAM records
+pub struct SamWriter {
+	writer : BufWriter<File>,
+	seg_ids : Vec<String>,
+}
+
+impl SamWriter {
+	pub fn new(filename : &str, seg_ids : &[String], seg_strings : &[String]) -> SamWriter {
+		let file = File::create(filename).expect("Unable to create file");
+		let mut sam_writer = SamWriter {
+			writer : BufWriter::new(file),
+			seg_ids : seg_ids.to_vec(),
+		};
+		sam_writer.writer.write_all(header_text(seg_ids, seg_strings).as_bytes()).expect("Write failed");
+		sam_writer
+	}
+
+	pub fn write_read(&mut self, record : &FqRecord, alignments : &[Alignment]) {
+		for sam_record in sam_records(record, alignments) {
+			let seq : &str = sam_record.seq.as_ref().map_or("*", |seq| seq.as_str());
+			let qual : &str = sam_record.qual.as_ref().map_or("*", |qual| qual.as_str());
+			let line : String = match sam_record.alignment {
+				None => format!("{}\t{}\t*\t0\t0\t*\t*\t0\t0\t{}\t{}", sam_record.name, sam_record.flag, seq, qual),
+				Some(alignment) => format!("{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t{}\tNM:i:{}\tAS:i:{}",
+					sam_record.name,
+					sam_record.flag,
+					self.seg_ids[alignment.segment_index],
+					alignment.position + 1,
+					sam_record.mapq,
+					alignment.cigar_string(),
+					seq,
+					qual,
+					alignment.edit_distance,
+					alignment.score),
+			};
+			self.writer.write_fmt(format_args!("{}\n", line)).expect("Write failed");
+		}
+	}
+}
+
+//Writes reads and their alignments as BAM records, compressed in BGZF blocks
+pub struct BamWriter {
+	writer : BufWriter<File>,
+	block : Vec<u8>,
+}
+
+impl BamWriter {
+	pub fn new(filename : &str, seg_ids : &[String], seg_strings : &[String]) -> BamWriter {
+		let file = File::create(filename).expect("Unable to create file");
+		let mut bam_writer = BamWriter {
+			writer : BufWriter::new(file),
+			block : Vec::with_capacity(BGZF_BLOCK_SIZE),
+		};
+
+		let header : String = header_text(seg_ids, seg_strings);
+		let mut bytes : Vec<u8> = b"BAM\x01".to_vec();
+		bytes.extend_from_slice(&(header.len() as i32).to_le_bytes());
+		bytes.extend_from_slice(header.as_bytes());
+		bytes.extend_from_slice(&(seg_ids.len() as i32).to_le_bytes());
+		for (seg_id, seg_string) in seg_ids.iter().zip(seg_strings.iter()) {
+			bytes.extend_from_slice(&(seg_id.len() as i32 + 1).to_le_bytes());
+			bytes.extend_from_slice(seg_id.as_bytes());
+			bytes.push(0);
+			bytes.extend_from_slice(&(seg_string.len() as i32).to_le_bytes());
+		}
+		bam_writer.write_bytes(&bytes);
+		bam_writer
+	}
+
+	pub fn write_read(&mut self, record : &FqRecord, alignments : &[Alignment]) {
+		for sam_record in sam_records(record, alignments) {
+			let bytes : Vec<u8> = encode_bam_record(&sam_record);
+			self.write_bytes(&bytes);
+		}
+	}
+
+	fn write_bytes(&mut self, mut bytes : &[u8]) {
+		while !bytes.is_empty() {
+			let n : usize = bytes.len().min(BGZF_BLOCK_SIZE - self.block.len());
+			self.block.extend_from_slice(&bytes[..n]);
+			bytes = &bytes[n..];
+			if self.block.len() == BGZF_BLOCK_SIZE {
+				self.flush_block();
+			}
+		}
+	}
+
+	//Compress the gathered bytes into one BGZF block: a gzip member whose extra field holds its size
+	fn flush_block(&mut self) {
+		if self.block.is_empty() {
+			return;
+		}
+		let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(&self.block).expect("Compression failed");
+		let compressed : Vec<u8> = encoder.finish().expect("Compression failed");
+		let mut crc = Crc::new();
+		crc.update(&self.block);
+
+		let block_size : usize = 18 + compressed.len() + 8;
+		let mut header : [u8; 18] = [0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, 0x42, 0x43, 0x02, 0, 0, 0];
+		header[16..18].copy_from_slice(&((block_size - 1) as u16).to_le_bytes());
+		self.writer.write_all(&header).expect("Write failed");
+		self.writer.write_all(&compressed).expect("Write failed");
+		self.writer.write_all(&crc.sum().to_le_bytes()).expect("Write failed");
+		self.writer.write_all(&(self.block.len() as u32).to_le_bytes()).expect("Write failed");
+		self.block.clear();
+	}
+}
+
+impl Drop for BamWriter {
+	fn drop(&mut self) {
+		self.flush_block();
+		self.writer.write_all(&BGZF_EOF).expect("Write failed");
+	}
+}
+
+//The BAM encoding of a record, led by its size
+fn encode_bam_record(sam_record : &SamRecord) -> Vec<u8> {
+	let (ref_id, position, cigar, bin) : (i32, i32, Vec<u32>, u16) = match sam_record.alignment {
+		None => (-1, -1, Vec::new(), reg2bin(-1, 0)),
+		Some(alignment) => {
+			let cigar : Vec<u32> = alignment.cigar.iter()
+				.map(|&(length, op)| {
+					let code : usize = BAM_CIGAR_OPS.iter().position(|&bam_op| bam_op as char == op).expect("unknown CIGAR operation");
+					(length as u32) << 4 | code as u32
+				})
+				.collect();
+			let reference_length : usize = alignment.cigar.iter()
+				.filter(|&&(_, op)| op == 'M' || op == 'D' || op == 'N' || op == '=' || op == 'X')
+				.map(|&(length, _)| length)
+				.sum();
+			let start : i64 = alignment.position as i64;
+			(alignment.segment_index as i32, alignment.position as i32, cigar, reg2bin(start, start + reference_length.max(1) as i64))
+		}
+	};
+	let seq : &[u8] = sam_record.seq.as_ref().map_or(&[], |seq| seq.as_bytes());
+
+	let mut bytes : Vec<u8> = Vec::new();
+	bytes.extend_from_slice(&ref_id.to_le_bytes());
+	bytes.extend_from_slice(&position.to_le_bytes());
+	bytes.push((sam_record.name.len() + 1) as u8);
+	bytes.push(sam_record.mapq);
+	bytes.extend_from_slice(&bin.to_le_bytes());
+	bytes.extend_from_slice(&(cigar.len() as u16).to_le_bytes());
+	bytes.extend_from_slice(&sam_record.flag.to_le_bytes());
+	bytes.extend_from_slice(&(seq.len() as i32).to_le_bytes());
+	//Mate reference, mate position and template length
+	bytes.extend_from_slice(&(-1i32).to_le_bytes());
+	bytes.extend_from_slice(&(-1i32).to_le_bytes());
+	bytes.extend_from_slice(&0i32.to_le_bytes());
+	bytes.extend_from_slice(sam_record.name.as_bytes());
+	bytes.push(0);
+	for op in cigar {
+		bytes.extend_from_slice(&op.to_le_bytes());
+	}
+	bytes.extend(pack_bases(seq));
+	match sam_record.qual {
+		Some(ref qual) => bytes.extend(qual.bytes().map(|q| q.saturating_sub(PHRED_OFFSET))),
+		None => bytes.extend(std::iter::repeat_n(0xff, seq.len())),
+	}
+	if let Some(alignment) = sam_record.alignment {
+		bytes.extend_from_slice(b"NMi");
+		bytes.extend_from_slice(&(alignment.edit_distance as i32).to_le_bytes());
+		bytes.extend_from_slice(b"ASi");
+		bytes.extend_from_slice(&alignment.score.to_le_bytes());
+	}
+
+	let mut record : Vec<u8> = (bytes.len() as i32).to_le_bytes().to_vec();
+	record.extend(bytes);
+	record
+}
+
+//Two bases to a byte, the first in the high nibble, with letters outside the BAM alphabet as N
+fn pack_bases(seq : &[u8]) -> Vec<u8> {
+	let code = |base : u8| -> u8 {
+		BAM_BASES.iter().position(|&bam_base| bam_base == base.to_ascii_uppercase()).unwrap_or(BAM_BASES.len() - 1) as u8
+	};
+	seq.chunks(2)
+		.map(|pair| code(pair[0]) << 4 | pair.get(1).map_or(0, |&base| code(base)))
+		.collect()
+}
+
+//The smallest bin of the BAM index holding the 0-based half-open region [start, end)
+fn reg2bin(start : i64, end : i64) -> u16 {
+	let end : i64 = end - 1;
+	for &(shift, offset) in &[(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+		if start >> shift == end >> shift {
+			return (offset + (start >> shift)) as u16;
+		}
+	}
+	0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Read;
+
+	use flate2::read::MultiGzDecoder;
+
+	fn read_i32(bytes : &[u8], offset : usize) -> i32 {
+		i32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+	}
+
+	#[test]
+	fn bins_regions_as_the_bam_index_does() {
+		assert_eq!(reg2bin(-1, 0), 4680);
+		assert_eq!(reg2bin(0, 1), 4681);
+		assert_eq!(reg2bin(16383, 16385), 585);
+		assert_eq!(reg2bin(1 << 26, (1 << 26) + 100), 4681 + (1 << 12));
+	}
+
+	#[test]
+	fn packs_two_bases_to_a_byte() {
+		assert_eq!(pack_bases(b"ACGTN"), vec![0x12, 0x48, 0xf0]);
+		assert_eq!(pack_bases(b"acx"), vec![0x12, 0xf0]);
+	}
+
+	#[test]
+	fn writes_reverse_alignments_with_the_reverse_complement_of_the_read() {
+		let record = FqRecord { name : "r1".to_string(), seq : "AACG".to_string(), qual : Some("ABCD".to_string()), umi : None };
+		let alignments = vec![
+			Alignment { segment_index : 0, position : 5, reverse : true, cigar : vec![(4, 'M')], edit_distance : 0, score : 8 },
+			Alignment { segment_index : 0, position : 9, reverse : false, cigar : vec![(4, 'M')], edit_distance : 1, score : 2 },
+		];
+		let sam_records = sam_records(&record, &alignments);
+		assert_eq!(sam_records.len(), 2);
+		assert_eq!(sam_records[0].flag, FLAG_REVERSE);
+		assert_eq!(sam_records[0].seq.as_deref(), Some("CGTT"));
+		assert_eq!(sam_records[0].qual.as_deref(), Some("DCBA"));
+		assert_eq!(sam_records[1].flag, FLAG_SECONDARY);
+		assert!(sam_records[1].seq.is_none());
+	}
+
+	#[test]
+	fn writes_bam_that_decompresses_to_the_records() {
+		let filename : String = std::env::temp_dir().join(format!("tuna_test_{}.bam", std::process::id())).to_string_lossy().into_owned();
+		let record = FqRecord { name : "r1".to_string(), seq : "ACGTA".to_string(), qual : Some("IIIII".to_string()), umi : None };
+		let alignment = Alignment { segment_index : 0, position : 3, reverse : false, cigar : vec![(5, 'M')], edit_distance : 0, score : 10 };
+		{
+			let mut bam_writer = BamWriter::new(&filename, &["seg1".to_string()], &["ACGACGTACGT".to_string()]);
+			bam_writer.write_read(&record, &[alignment]);
+		}
+
+		let mut bytes : Vec<u8> = Vec::new();
+		MultiGzDecoder::new(File::open(&filename).expect("BAM not written")).read_to_end(&mut bytes).expect("BAM is not BGZF");
+		std::fs::remove_file(&filename).expect("could not remove BAM");
+		assert_eq!(&bytes[..4], b"BAM\x01");
+		let header_length : usize = read_i32(&bytes, 4) as usize;
+		let references : usize = 8 + header_length;
+		assert_eq!(read_i32(&bytes, references), 1);
+		assert_eq!(&bytes[(references + 8)..(references + 13)], b"seg1\0");
+		assert_eq!(read_i32(&bytes, references + 13), 11);
+
+		let record_start : usize = references + 17;
+		assert_eq!(read_i32(&bytes, record_start) as usize, bytes.len() - record_start - 4);
+		//Reference, position, read name length and mapping quality follow the record size
+		assert_eq!(read_i32(&bytes, record_start + 4), 0);
+		assert_eq!(read_i32(&bytes, record_start + 8), 3);
+		assert_eq!(&bytes[(record_start + 12)..(record_start + 14)], &[3, 60]);
+		assert_eq!(&bytes[(record_start + 36)..(record_start + 39)], b"r1\0");
+		assert_eq!(read_i32(&bytes, record_start + 39), 5 << 4);
+		assert_eq!(&bytes[(record_start + 43)..(record_start + 46)], &[0x12, 0x48, 0x10]);
+		assert_eq!(&bytes[(record_start + 46)..(record_start + 51)], &[40; 5]);
+	}
+}
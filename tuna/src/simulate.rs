@@ -0,0 +1,194 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Normal};
+
+use log::*;
+
+use library_type::LibraryType;
+use library_type::MateOrientation;
+use library_type::Strandedness;
+use library_type::reverse_complement;
+use read_inputs::FqRecord;
+
+const BASES : [char; 4] = ['A', 'C', 'G', 'T'];
+//Phred quality written for every simulated base
+const SIMULATED_QUALITY : char = 'I';
+
+//How fragments are drawn from the targets and how much of them is read
+#[derive(Clone, Copy, Debug)]
+pub struct FragmentModel {
+	pub mean_length : f64,
+	pub sd_length : f64,
+	pub read_length : usize,
+}
+
+//Per-base rates of sequencing errors
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorModel {
+	pub substitution_rate : f64,
+	pub insertion_rate : f64,
+	pub deletion_rate : f64,
+}
+
+//A simulated read, with its mate for paired library types, and where it truly came from
+#[derive(Clone, Debug)]
+pub struct SimulatedRead {
+	pub record : FqRecord,
+	pub mate : Option<FqRecord>,
+	pub segment_index : usize,
+	//Whether the first read lies on the forward strand of its target
+	pub forward : bool,
+	pub fragment_start : usize,
+	pub fragment_length : usize,
+	pub n_errors : usize,
+}
+
+/**
+    simulate_reads:
+    Draw fragments from the targets in proportion to their abundance times their
+    effective length, read them on the strands the library type calls for, and add
+    sequencing errors to the reads
+*/
+pub fn simulate_reads(seg_strings : &[String], abundances : &[f64], n_reads : usize, fragment_model : FragmentModel,
+		error_model : ErrorModel, library_type : LibraryType, seed : u64) -> Vec<SimulatedRead> {
+	let weights : Vec<f64> = seg_strings.iter().zip(abundances.iter())
+		.map(|(seg_string, &abundance)| abundance * (seg_string.len() as f64 - fragment_model.mean_length + 1.0).max(1.0))
+		.collect();
+	let targets = WeightedIndex::new(&weights).expect("no target has a positive abundance");
+	let fragment_lengths = Normal::new(fragment_model.mean_length, fragment_model.sd_length).expect("invalid fragment length distribution");
+	let mut rng = StdRng::seed_from_u64(seed);
+
+	let mut simulated_reads : Vec<SimulatedRead> = Vec::with_capacity(n_reads);
+	for i in 0..n_reads {
+		let segment_index : usize = targets.sample(&mut rng);
+		let seg_string : &str = &seg_strings[segment_index];
+		let fragment_length : usize = (fragment_lengths.sample(&mut rng).round().max(1.0) as usize).min(seg_string.len());
+		let fragment_start : usize = rng.gen_range(0..(seg_string.len() - fragment_length + 1));
+		let fragment : &str = &seg_string[fragment_start..(fragment_start + fragment_length)];
+
+		let forward : bool = match library_type.strandedness {
+			Strandedness::Forward => true,
+			Strandedness::Reverse => false,
+			Strandedness::Unstranded => rng.gen::<bool>(),
+		};
+		let fragment_rc : String = reverse_complement(fragment);
+		let (first_strand, other_strand) : (&str, &str) = if forward { (fragment, &fragment_rc) } else { (&fragment_rc, fragment) };
+
+		let name : String = format!("read{}", i);
+		let (seq, mut n_errors) = sequence_read(first_strand, fragment_model.read_length, error_model, &mut rng);
+		let record : FqRecord = simulated_record(&name, seq);
+		let mate : Option<FqRecord> = library_type.orientation.map(|orientation| {
+			//Inward mates start from the other end of the fragment on the other strand, matching mates
+			//end at the other end of the fragment on the same strand
+			let mate_source : &str = match orientation {
+				MateOrientation::Inward => other_strand,
+				MateOrientation::Matching => &first_strand[first_strand.len().saturating_sub(fragment_model.read_length)..],
+			};
+			let (mate_seq, mate_errors) = sequence_read(mate_source, fragment_model.read_length, error_model, &mut rng);
+			n_errors += mate_errors;
+			simulated_record(&name, mate_seq)
+		});
+
+		simulated_reads.push(SimulatedRead { record, mate, segment_index, forward, fragment_start, fragment_length, n_errors });
+	}
+	info!("Simulated {} {} reads", simulated_reads.len(), library_type.code());
+	simulated_reads
+}
+
+//Read up to read_length bases from the start of the source, substituting, inserting and deleting bases
+//at the rates of the error model
+fn sequence_read(source : &str, read_length : usize, error_model : ErrorModel, rng : &mut StdRng) -> (String, usize) {
+	let mut read : String = String::with_capacity(read_length);
+	let mut n_errors : usize = 0;
+	let mut bases = source.chars();
+	while read.len() < read_length {
+		if rng.gen::<f64>() < error_model.insertion_rate {
+			read.push(BASES[rng.gen_range(0..BASES.len())]);
+			n_errors += 1;
+			continue;
+		}
+		let base : char = match bases.next() {
+			Some(base) => base,
+			None => break,
+		};
+		let draw : f64 = rng.gen::<f64>();
+		if draw < error_model.deletion_rate {
+			n_errors += 1;
+		} else if draw < error_model.deletion_rate + error_model.substitution_rate {
+			let substitutes : Vec<char> = BASES.iter().cloned().filter(|&other| other != base).collect();
+			read.push(substitutes[rng.gen_range(0..substitutes.len())]);
+			n_errors += 1;
+		} else {
+			read.push(base);
+		}
+	}
+	(read, n_errors)
+}
+
+fn simulated_record(name : &str, seq : String) -> FqRecord {
+	let qual : String = std::iter::repeat_n(SIMULATED_QUALITY, seq.len()).collect();
+	FqRecord { name : name.to_string(), seq, qual : Some(qual), umi : None }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const NO_ERRORS : ErrorModel = ErrorModel { substitution_rate : 0.0, insertion_rate : 0.0, deletion_rate : 0.0 };
+
+	fn targets() -> Vec<String> {
+		vec!["CCGTAATGCCTTTCCCTAACAGAGTTTTTCGAACTCGTGT".to_string(), "GGGGCCCCAAAATTTTGGGGCCCCAAAATTTT".to_string()]
+	}
+
+	#[test]
+	fn draws_error_free_reads_from_abundant_targets_on_the_library_strand() {
+		let seg_strings = targets();
+		let fragment_model = FragmentModel { mean_length : 20.0, sd_length : 2.0, read_length : 10 };
+		let simulated_reads = simulate_reads(&seg_strings, &[1.0, 0.0], 50, fragment_model, NO_ERRORS, "SR".parse().unwrap(), 7);
+
+		assert_eq!(simulated_reads.len(), 50);
+		for simulated_read in &simulated_reads {
+			assert_eq!(simulated_read.segment_index, 0);
+			assert!(!simulated_read.forward);
+			assert_eq!(simulated_read.n_errors, 0);
+			assert!(simulated_read.mate.is_none());
+			let fragment : &str = &seg_strings[0][simulated_read.fragment_start..(simulated_read.fragment_start + simulated_read.fragment_length)];
+			assert!(reverse_complement(fragment).starts_with(&simulated_read.record.seq));
+			assert_eq!(simulated_read.record.qual.as_deref().map(|qual| qual.len()), Some(simulated_read.record.seq.len()));
+		}
+
+		//The same seed draws the same reads
+		let again = simulate_reads(&seg_strings, &[1.0, 0.0], 50, fragment_model, NO_ERRORS, "SR".parse().unwrap(), 7);
+		assert!(simulated_reads.iter().zip(again.iter()).all(|(a, b)| a.record.seq == b.record.seq));
+	}
+
+	#[test]
+	fn reads_inward_mates_from_the_other_end_of_the_fragment() {
+		let seg_strings = targets();
+		let fragment_model = FragmentModel { mean_length : 30.0, sd_length : 0.0, read_length : 8 };
+		let simulated_reads = simulate_reads(&seg_strings, &[1.0, 1.0], 20, fragment_model, NO_ERRORS, "ISF".parse().unwrap(), 3);
+
+		for simulated_read in &simulated_reads {
+			let seg_string : &str = &seg_strings[simulated_read.segment_index];
+			let fragment : &str = &seg_string[simulated_read.fragment_start..(simulated_read.fragment_start + simulated_read.fragment_length)];
+			assert!(simulated_read.forward);
+			assert_eq!(simulated_read.record.seq, &fragment[..8]);
+			assert_eq!(simulated_read.mate.as_ref().map(|mate| mate.seq.clone()), Some(reverse_complement(&fragment[(fragment.len() - 8)..])));
+		}
+	}
+
+	#[test]
+	fn substitutes_every_base_at_full_rate() {
+		let mut rng = StdRng::seed_from_u64(1);
+		let error_model = ErrorModel { substitution_rate : 1.0, insertion_rate : 0.0, deletion_rate : 0.0 };
+		let (read, n_errors) = sequence_read("ACGTACGT", 6, error_model, &mut rng);
+		assert_eq!(n_errors, 6);
+		assert!(read.chars().zip("ACGTAC".chars()).all(|(base, source)| base != source));
+
+		//Deleting every base runs out of source before the read is full
+		let error_model = ErrorModel { substitution_rate : 0.0, insertion_rate : 0.0, deletion_rate : 1.0 };
+		assert_eq!(sequence_read("ACGT", 4, error_model, &mut rng), (String::new(), 4));
+	}
+}
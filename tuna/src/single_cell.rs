@@ -0,0 +1,315 @@
+use rayon::prelude::*;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use log::*;
+
+use dna_hash_table::Pseudoaligner;
+use gene_map::GeneMap;
+use umi;
+
+const BASES : [char; 4] = ['A', 'C', 'G', 'T'];
+
+//Cell barcodes known to the chemistry, used to correct barcodes with a single sequencing error
+pub struct Whitelist {
+	barcodes : HashSet<String>,
+}
+
+impl Whitelist {
+	pub fn new(barcodes : Vec<String>) -> Whitelist {
+		Whitelist { barcodes : barcodes.into_iter().collect() }
+	}
+
+	//The whitelisted barcode a read barcode stands for: the barcode itself, or the only whitelisted
+	//barcode one substitution away from it
+	pub fn correct(&self, barcode : &str) -> Option<String> {
+		if self.barcodes.contains(barcode) {
+			return Some(barcode.to_string());
+		}
+		let mut corrected : Option<String> = None;
+		for neighbour in hamming_neighbours(barcode) {
+			if self.barcodes.contains(&neighbour) {
+				if corrected.is_some() {
+					return None;
+				}
+				corrected = Some(neighbour);
+			}
+		}
+		corrected
+	}
+}
+
+//Every sequence differing from the given one at exactly one base
+pub fn hamming_neighbours(sequence : &str) -> Vec<String> {
+	let bases : Vec<char> = sequence.chars().collect();
+	let mut neighbours : Vec<String> = Vec::with_capacity(bases.len() * 3);
+	for i in 0..bases.len() {
+		for &base in BASES.iter().filter(|&&base| base != bases[i]) {
+			let mut neighbour : Vec<char> = bases.clone();
+			neighbour[i] = base;
+			neighbours.push(neighbour.into_iter().collect());
+		}
+	}
+	neighbours
+}
+
+//Where the cell barcode and UMI sit at the start of R1
+#[derive(Clone, Copy, Debug)]
+pub struct BarcodeLayout {
+	pub barcode_length : usize,
+	pub umi_length : usize,
+}
+
+impl BarcodeLayout {
+	pub fn split<'r>(&self, r1 : &'r str) -> Option<(&'r str, &'r str)> {
+		if r1.len() < self.barcode_length + self.umi_length {
+			return None;
+		}
+		Some((&r1[..self.barcode_length], &r1[self.barcode_length..(self.barcode_length + self.umi_length)]))
+	}
+}
+
+//Barcodes of a run ranked by the number of reads carrying them, and the rank of the knee in that
+//distribution, above which barcodes are taken to be cells rather than empty droplets
+pub struct BarcodeRanks {
+	pub ranked : Vec<(String, usize)>,
+	pub n_cells : usize,
+}
+
+impl BarcodeRanks {
+	pub fn new(r1s : &[String], layout : BarcodeLayout) -> BarcodeRanks {
+		let mut barcode_counts : HashMap<&str, usize> = HashMap::new();
+		for (barcode, _) in r1s.iter().filter_map(|r1| layout.split(r1)) {
+			*barcode_counts.entry(barcode).or_insert(0) += 1;
+		}
+		let mut ranked : Vec<(String, usize)> = barcode_counts.into_iter()
+			.map(|(barcode, count)| (barcode.to_string(), count))
+			.collect();
+		ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+		let counts : Vec<usize> = ranked.iter().map(|&(_, count)| count).collect();
+		let n_cells : usize = knee_point(&counts);
+		info!("Inferred {} cells from {} barcodes", n_cells, ranked.len());
+		BarcodeRanks { ranked, n_cells }
+	}
+
+	pub fn whitelist(&self) -> Whitelist {
+		Whitelist::new(self.ranked.iter().take(self.n_cells).map(|(barcode, _)| barcode.clone()).collect())
+	}
+}
+
+/**
+    knee_point:
+    Given read counts sorted in decreasing order, find the number of barcodes before the
+    knee of the log-log rank plot: the point furthest below the line joining its ends
+*/
+pub fn knee_point(counts : &[usize]) -> usize {
+	if counts.len() < 3 {
+		return counts.len();
+	}
+	let points : Vec<(f64, f64)> = counts.iter().enumerate()
+		.map(|(i, &count)| (((i + 1) as f64).log10(), (count.max(1) as f64).log10()))
+		.collect();
+	let (x0, y0) = points[0];
+	let (x1, y1) = points[points.len() - 1];
+	let (dx, dy) = (x1 - x0, y1 - y0);
+	let norm : f64 = (dx * dx + dy * dy).sqrt();
+	if norm == 0.0 {
+		return counts.len();
+	}
+
+	//Signed distance from the line, positive below it
+	let (knee, distance) = points.iter().enumerate()
+		.map(|(i, &(x, y))| (i, (dy * (x - x0) - dx * (y - y0)) / norm))
+		.fold((0, f64::MIN), |best, point| if point.1 > best.1 { point } else { best });
+	if distance <= 0.0 { counts.len() } else { knee.max(1) }
+}
+
+//A cDNA read assigned to a single gene, with the cell and molecule it came from
+#[derive(Clone, Debug)]
+pub struct CellRead {
+	pub barcode : String,
+	pub umi : String,
+	pub gene_index : usize,
+}
+
+//What became of the read pairs of a single-cell run
+#[derive(Clone, Debug, Default)]
+pub struct CellStats {
+	pub n_pairs : usize,
+	pub n_barcode_corrected : usize,
+	pub n_barcode_rejected : usize,
+	pub n_unassigned : usize,
+	pub n_multigene : usize,
+	pub n_assigned : usize,
+}
+
+enum PairOutcome {
+	Assigned(CellRead, bool),
+	BarcodeRejected,
+	Unassigned,
+	Multigene,
+}
+
+/**
+    assign_cell_reads:
+    Split the barcode and UMI off every R1, correct the barcode against the whitelist and
+    pseudoalign the R2 to the gene its best targets all belong to
+*/
+pub fn assign_cell_reads<P : Pseudoaligner + Sync>(r1s : &[String], r2s : &[String], layout : BarcodeLayout, whitelist : &Whitelist,
+		pseudoaligner : &P, n_targets : usize, gene_map : &GeneMap) -> (Vec<CellRead>, CellStats) {
+	if r1s.len() != r2s.len() {
+		warn!("R1 has {} reads but R2 has {}; only the first {} pairs are used", r1s.len(), r2s.len(), r1s.len().min(r2s.len()));
+	}
+
+	let outcomes : Vec<PairOutcome> = r1s.par_iter().zip(r2s.par_iter())
+		.map(|(r1, r2)| {
+			let (barcode, umi) = match layout.split(r1) {
+				Some(split) => split,
+				None => return PairOutcome::BarcodeRejected,
+			};
+			let corrected : String = match whitelist.correct(barcode) {
+				Some(corrected) => corrected,
+				None => return PairOutcome::BarcodeRejected,
+			};
+
+			let targets : Vec<usize> = match pseudoaligner.pseudoalign(r2).and_then(|read_mapping| read_mapping.best_targets(n_targets)) {
+				Some(targets) => targets,
+				None => return PairOutcome::Unassigned,
+			};
+			let mut genes : Vec<usize> = targets.iter().map(|&target| gene_map.segment_genes[target]).collect();
+			genes.sort();
+			genes.dedup();
+			if genes.len() != 1 {
+				return PairOutcome::Multigene;
+			}
+
+			let was_corrected : bool = corrected != barcode;
+			PairOutcome::Assigned(CellRead { barcode : corrected, umi : umi.to_string(), gene_index : genes[0] }, was_corrected)
+		})
+		.collect();
+
+	let mut cell_stats = CellStats { n_pairs : outcomes.len(), ..CellStats::default() };
+	let mut cell_reads : Vec<CellRead> = Vec::with_capacity(outcomes.len());
+	for outcome in outcomes {
+		match outcome {
+			PairOutcome::Assigned(cell_read, was_corrected) => {
+				cell_stats.n_assigned += 1;
+				if was_corrected {
+					cell_stats.n_barcode_corrected += 1;
+				}
+				cell_reads.push(cell_read);
+			},
+			PairOutcome::BarcodeRejected => cell_stats.n_barcode_rejected += 1,
+			PairOutcome::Unassigned => cell_stats.n_unassigned += 1,
+			PairOutcome::Multigene => cell_stats.n_multigene += 1,
+		}
+	}
+
+	info!("Processed {} read pairs: {} assigned to a gene ({} with corrected barcodes), {} with rejected barcodes, {} unassigned, {} spanning several genes",
+		cell_stats.n_pairs, cell_stats.n_assigned, cell_stats.n_barcode_corrected, cell_stats.n_barcode_rejected,
+		cell_stats.n_unassigned, cell_stats.n_multigene);
+	(cell_reads, cell_stats)
+}
+
+//Molecules counted per cell and gene, with cells in order of their barcodes
+pub struct CellMatrix {
+	pub barcodes : Vec<String>,
+	pub entries : Vec<(usize, usize, usize)>,
+}
+
+impl CellMatrix {
+	//Count the molecules of every cell and gene by collapsing their UMIs
+	pub fn new(cell_reads : &[CellRead]) -> CellMatrix {
+		let mut umis : HashMap<(&str, usize), HashMap<&str, usize>> = HashMap::new();
+		for cell_read in cell_reads {
+			*umis.entry((cell_read.barcode.as_str(), cell_read.gene_index)).or_default()
+				.entry(cell_read.umi.as_str()).or_insert(0) += 1;
+		}
+
+		let mut barcodes : Vec<String> = cell_reads.iter().map(|cell_read| cell_read.barcode.clone()).collect();
+		barcodes.sort();
+		barcodes.dedup();
+		let cell_indexes : HashMap<&str, usize> = barcodes.iter().enumerate().map(|(i, barcode)| (barcode.as_str(), i)).collect();
+
+		let mut entries : Vec<(usize, usize, usize)> = umis.iter()
+			.map(|(&(barcode, gene_index), umi_counts)| (cell_indexes[barcode], gene_index, umi::directional_clusters(umi_counts)))
+			.collect();
+		entries.sort();
+
+		info!("Counted {} molecules in {} cells", entries.iter().map(|&(_, _, count)| count).sum::<usize>(), barcodes.len());
+		CellMatrix { barcodes, entries }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Cursor;
+
+	use dna_hash_table::DNAHashTable;
+	use read_inputs::FqRecords;
+
+	#[test]
+	fn corrects_barcodes_one_substitution_away() {
+		let whitelist = Whitelist::new(vec!["AAAA".to_string(), "CCCC".to_string(), "ACCC".to_string()]);
+		assert_eq!(whitelist.correct("AAAA"), Some("AAAA".to_string()));
+		assert_eq!(whitelist.correct("AANA"), Some("AAAA".to_string()));
+		//Both CCCC and ACCC are one substitution from GCCC
+		assert_eq!(whitelist.correct("GCCC"), None);
+		assert_eq!(whitelist.correct("GGGG"), None);
+		assert_eq!(hamming_neighbours("AC").len(), 6);
+	}
+
+	#[test]
+	fn knee_separates_cells_from_empty_droplets() {
+		let mut counts : Vec<usize> = vec![1000, 980, 950, 940, 900];
+		counts.extend(vec![5, 4, 3, 3, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1]);
+		assert_eq!(knee_point(&counts), 5);
+		//Without a knee, or with too few barcodes to find one, every barcode is a cell
+		assert_eq!(knee_point(&[10, 10, 10, 10]), 4);
+		assert_eq!(knee_point(&[10, 1]), 2);
+		assert_eq!(knee_point(&[]), 0);
+	}
+
+	#[test]
+	fn ranks_barcodes_and_whitelists_those_above_the_knee() {
+		let layout = BarcodeLayout { barcode_length : 2, umi_length : 1 };
+		let mut r1s : Vec<String> = Vec::new();
+		for &(barcode, count) in &[("AA", 200), ("CC", 150), ("GG", 2), ("GT", 1), ("TT", 1), ("TA", 1)] {
+			r1s.extend(std::iter::repeat_n(format!("{}C", barcode), count));
+		}
+		r1s.push("A".to_string());
+		let barcode_ranks = BarcodeRanks::new(&r1s, layout);
+		assert_eq!(barcode_ranks.ranked[0], ("AA".to_string(), 200));
+		assert_eq!(barcode_ranks.ranked.len(), 6);
+		assert_eq!(barcode_ranks.n_cells, 2);
+		assert_eq!(barcode_ranks.whitelist().correct("CC"), Some("CC".to_string()));
+		assert_eq!(barcode_ranks.whitelist().correct("GG"), None);
+	}
+
+	#[test]
+	fn splits_barcode_and_umi_from_r1() {
+		let layout = BarcodeLayout { barcode_length : 4, umi_length : 3 };
+		assert_eq!(layout.split("AAAACCCGGG"), Some(("AAAA", "CCC")));
+		assert_eq!(layout.split("AAAACC"), None);
+	}
+
+	#[test]
+	fn ambiguous_barcode_bases_keep_the_umi_in_place() {
+		let r1s : Vec<String> = FqRecords::new(Cursor::new("@r1\nAANACCCGGG\n+\nIIIIIIIIII\n"))
+			.map(|record| record.seq).collect();
+		let r2s : Vec<String> = vec!["CCGTAATGCCTTTCCC".to_string()];
+		let segments : Vec<String> = vec!["CCGTAATGCCTTTCCCTAACAGAG".to_string()];
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		let gene_map = GeneMap { gene_ids : vec!["g1".to_string()], segment_genes : vec![0] };
+		let whitelist = Whitelist::new(vec!["AAAA".to_string()]);
+		let layout = BarcodeLayout { barcode_length : 4, umi_length : 3 };
+
+		let (cell_reads, cell_stats) = assign_cell_reads(&r1s, &r2s, layout, &whitelist, &kmer_hash_table, 1, &gene_map);
+		assert_eq!(cell_stats.n_barcode_corrected, 1);
+		assert_eq!((cell_reads[0].barcode.as_str(), cell_reads[0].umi.as_str(), cell_reads[0].gene_index), ("AAAA", "CCC", 0));
+	}
+}
@@ -0,0 +1,244 @@
+use rayon::prelude::*;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use log::*;
+
+use dna_hash_table::DNAHashTable;
+use library_type::reverse_complement;
+
+//Deepest lineage followed before a taxonomy is taken to have a cycle
+const MAX_DEPTH : usize = 1000;
+
+//A taxonomy tree given by the parent of every taxon. Roots are their own parent or have none.
+pub struct Taxonomy {
+	parents : HashMap<String, String>,
+	names : HashMap<String, String>,
+}
+
+impl Taxonomy {
+	pub fn new(nodes : Vec<(String, String, Option<String>)>) -> Taxonomy {
+		let mut taxonomy = Taxonomy { parents : HashMap::new(), names : HashMap::new() };
+		for (taxon_id, parent_id, name) in nodes {
+			if let Some(name) = name {
+				taxonomy.names.insert(taxon_id.clone(), name);
+			}
+			taxonomy.parents.insert(taxon_id, parent_id);
+		}
+		taxonomy
+	}
+
+	//Name the taxa, replacing any names given with the tree
+	pub fn set_names(&mut self, names : HashMap<String, String>) {
+		self.names.extend(names);
+	}
+
+	pub fn contains(&self, taxon_id : &str) -> bool {
+		self.parents.contains_key(taxon_id)
+	}
+
+	pub fn name(&self, taxon_id : &str) -> Option<&str> {
+		self.names.get(taxon_id).map(|name| name.as_str())
+	}
+
+	//The taxon followed by its ancestors up to its root
+	pub fn lineage<'t>(&'t self, taxon_id : &'t str) -> Vec<&'t str> {
+		let mut lineage : Vec<&str> = vec![taxon_id];
+		let mut current : &str = taxon_id;
+		while let Some(parent_id) = self.parents.get(current) {
+			if parent_id == current || lineage.len() >= MAX_DEPTH {
+				break;
+			}
+			current = parent_id.as_str();
+			lineage.push(current);
+		}
+		lineage
+	}
+
+	//The lowest common ancestor of two taxa, if they share a root
+	pub fn lca<'t>(&'t self, a : &'t str, b : &'t str) -> Option<&'t str> {
+		let ancestors : HashSet<&str> = self.lineage(a).into_iter().collect();
+		self.lineage(b).into_iter().find(|taxon_id| ancestors.contains(taxon_id))
+	}
+}
+
+//Reads classified to each taxon, and those left unclassified
+#[derive(Clone, Debug, Default)]
+pub struct TaxonCounts {
+	pub n_reads : usize,
+	pub n_unclassified : usize,
+	pub counts : HashMap<String, usize>,
+}
+
+/**
+    classify_reads:
+    Classify every read by k-mer voting: each k-mer votes for the lowest common ancestor
+    of the taxa of the segments it occurs in, and the read goes to the taxon whose
+    lineage gathers the most votes, or to the common ancestor of the taxa tied for most.
+    Reads without a labelled k-mer are left unclassified.
+*/
+pub fn classify_reads<'t>(reads : &[String], kmer_hash_table : &DNAHashTable<'t>, seg_taxa : &'t [Option<&'t str>],
+		taxonomy : &'t Taxonomy) -> (Vec<Option<&'t str>>, TaxonCounts) {
+	let kmer_taxa : HashMap<&str, &str> = kmer_taxa(kmer_hash_table, seg_taxa, taxonomy);
+	let k : usize = kmer_hash_table.k();
+	let classifications : Vec<Option<&str>> = reads.par_iter()
+		.map(|read| {
+			//Reads may come from either strand of their source, so vote with the strand more k-mers agree on
+			let forward_votes : HashMap<&str, usize> = kmer_votes(read, k, &kmer_taxa);
+			let reverse_votes : HashMap<&str, usize> = kmer_votes(&reverse_complement(read), k, &kmer_taxa);
+			let votes = if reverse_votes.values().sum::<usize>() > forward_votes.values().sum::<usize>() { reverse_votes } else { forward_votes };
+			best_taxon(&votes, taxonomy)
+		})
+		.collect();
+
+	let mut taxon_counts = TaxonCounts { n_reads : reads.len(), ..TaxonCounts::default() };
+	for classification in &classifications {
+		match *classification {
+			Some(taxon_id) => *taxon_counts.counts.entry(taxon_id.to_string()).or_insert(0) += 1,
+			None => taxon_counts.n_unclassified += 1,
+		}
+	}
+	info!("Classified {} of {} reads to {} taxa", taxon_counts.n_reads - taxon_counts.n_unclassified, taxon_counts.n_reads,
+		taxon_counts.counts.len());
+	(classifications, taxon_counts)
+}
+
+//The lowest common ancestor of the taxa of the segments each distinct k-mer of the index occurs in, for
+//k-mers occurring in at least one labelled segment
+fn kmer_taxa<'t>(kmer_hash_table : &DNAHashTable<'t>, seg_taxa : &'t [Option<&'t str>], taxonomy : &'t Taxonomy) -> HashMap<&'t str, &'t str> {
+	let k : usize = kmer_hash_table.k();
+	let mut kmer_taxa : HashMap<&str, &str> = HashMap::new();
+	let mut seen : HashSet<&str> = HashSet::new();
+	for segment in kmer_hash_table.segments() {
+		for i in 0..(segment.len() + 1).saturating_sub(k) {
+			let kmer : &str = &segment[i..(i + k)];
+			if !seen.insert(kmer) {
+				continue;
+			}
+			let (kmers, kmer_indexes) = match kmer_hash_table.get_kmer(kmer) {
+				Some(hit) => hit,
+				None => continue,
+			};
+			let mut taxa = kmer_indexes.iter().filter_map(|&index| seg_taxa[kmers[index].segment_index]);
+			let kmer_taxon : Option<&str> = taxa.next()
+				.and_then(|first| taxa.try_fold(first, |lca, taxon_id| taxonomy.lca(lca, taxon_id)));
+			if let Some(taxon_id) = kmer_taxon {
+				kmer_taxa.insert(kmer, taxon_id);
+			}
+		}
+	}
+	debug!("Found the taxa of {} of {} distinct k-mers", kmer_taxa.len(), seen.len());
+	kmer_taxa
+}
+
+//Each k-mer of the read with a taxon votes for it
+fn kmer_votes<'t>(read : &str, k : usize, kmer_taxa : &HashMap<&str, &'t str>) -> HashMap<&'t str, usize> {
+	let mut votes : HashMap<&str, usize> = HashMap::new();
+	for i in 0..(read.len() + 1).saturating_sub(k) {
+		if let Some(&taxon_id) = kmer_taxa.get(&read[i..(i + k)]) {
+			*votes.entry(taxon_id).or_insert(0) += 1;
+		}
+	}
+	votes
+}
+
+//The voted taxon with the highest root-to-leaf score, the votes summed along its lineage
+fn best_taxon<'t>(votes : &HashMap<&'t str, usize>, taxonomy : &'t Taxonomy) -> Option<&'t str> {
+	let scores : Vec<(&str, usize)> = votes.keys()
+		.map(|&taxon_id| (taxon_id, taxonomy.lineage(taxon_id).iter().map(|ancestor| votes.get(ancestor).cloned().unwrap_or(0)).sum()))
+		.collect();
+	let best_score : usize = scores.iter().map(|&(_, score)| score).max()?;
+	let mut tied = scores.iter().filter(|&&(_, score)| score == best_score).map(|&(taxon_id, _)| taxon_id);
+	let first : &str = tied.next()?;
+	tied.try_fold(first, |lca, taxon_id| taxonomy.lca(lca, taxon_id))
+}
+
+//Reads classified to each taxon or any of its descendants
+pub fn clade_counts(taxon_counts : &TaxonCounts, taxonomy : &Taxonomy) -> HashMap<String, usize> {
+	let mut clade_counts : HashMap<String, usize> = HashMap::new();
+	for (taxon_id, &count) in &taxon_counts.counts {
+		for ancestor in taxonomy.lineage(taxon_id) {
+			*clade_counts.entry(ancestor.to_string()).or_insert(0) += count;
+		}
+	}
+	clade_counts
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	//A root with bacteria, E. coli and S. aureus under it, and human directly below the root
+	fn taxonomy() -> Taxonomy {
+		Taxonomy::new(vec![
+			("1".to_string(), "1".to_string(), Some("root".to_string())),
+			("2".to_string(), "1".to_string(), None),
+			("562".to_string(), "2".to_string(), None),
+			("1280".to_string(), "2".to_string(), None),
+			("9606".to_string(), "1".to_string(), None),
+		])
+	}
+
+	#[test]
+	fn finds_lineages_and_lowest_common_ancestors() {
+		let taxonomy = taxonomy();
+		assert_eq!(taxonomy.lineage("562"), vec!["562", "2", "1"]);
+		assert_eq!(taxonomy.lca("562", "1280"), Some("2"));
+		assert_eq!(taxonomy.lca("562", "9606"), Some("1"));
+		assert_eq!(taxonomy.lca("562", "2"), Some("2"));
+		assert_eq!(taxonomy.lca("562", "562"), Some("562"));
+		//Taxa outside the tree only share an ancestor with themselves
+		assert_eq!(taxonomy.lca("562", "7"), None);
+		assert_eq!(taxonomy.lineage("7"), vec!["7"]);
+	}
+
+	#[test]
+	fn stops_following_cyclic_lineages() {
+		let taxonomy = Taxonomy::new(vec![("a".to_string(), "b".to_string(), None), ("b".to_string(), "a".to_string(), None)]);
+		assert_eq!(taxonomy.lineage("a").len(), MAX_DEPTH);
+	}
+
+	#[test]
+	fn takes_names_from_the_tree_and_then_names_files() {
+		let mut taxonomy = taxonomy();
+		assert_eq!(taxonomy.name("1"), Some("root"));
+		assert_eq!(taxonomy.name("562"), None);
+		taxonomy.set_names(vec![("562".to_string(), "Escherichia coli".to_string())].into_iter().collect());
+		assert_eq!(taxonomy.name("562"), Some("Escherichia coli"));
+	}
+
+	#[test]
+	fn shared_kmers_vote_for_the_common_ancestor() {
+		let taxonomy = taxonomy();
+		let segments : Vec<String> = vec!["ACGTTGCAAGGC".to_string(), "ACGTCCCCTTTT".to_string(), "GGCAGGCA".to_string()];
+		let seg_taxa : Vec<Option<&str>> = vec![Some("562"), Some("1280"), None];
+		let kmer_hash_table = DNAHashTable::new(&segments, 4);
+
+		let kmer_taxa : HashMap<&str, &str> = kmer_taxa(&kmer_hash_table, &seg_taxa, &taxonomy);
+		assert_eq!(kmer_taxa.get("ACGT"), Some(&"2"));
+		assert_eq!(kmer_taxa.get("TGCA"), Some(&"562"));
+		//k-mers of unlabelled segments only have no taxon, those shared with a labelled one keep its taxon
+		assert_eq!(kmer_taxa.get("GCAG"), None);
+		assert_eq!(kmer_taxa.get("AGGC"), Some(&"562"));
+
+		let reads : Vec<String> = vec!["GTTGCAAG".to_string(), "ACGT".to_string(), "GAGAGAGA".to_string()];
+		let (classifications, taxon_counts) = classify_reads(&reads, &kmer_hash_table, &seg_taxa, &taxonomy);
+		assert_eq!(classifications, vec![Some("562"), Some("2"), None]);
+		assert_eq!((taxon_counts.n_reads, taxon_counts.n_unclassified), (3, 1));
+
+		let clade_counts = clade_counts(&taxon_counts, &taxonomy);
+		assert_eq!((clade_counts["1"], clade_counts["2"], clade_counts["562"]), (2, 2, 1));
+	}
+
+	#[test]
+	fn ties_go_to_the_common_ancestor_and_lineages_add_up() {
+		let taxonomy = taxonomy();
+		let tied : HashMap<&str, usize> = vec![("562", 2), ("1280", 2)].into_iter().collect();
+		assert_eq!(best_taxon(&tied, &taxonomy), Some("2"));
+		//Votes for an ancestor support its descendants
+		let nested : HashMap<&str, usize> = vec![("2", 2), ("562", 1), ("9606", 2)].into_iter().collect();
+		assert_eq!(best_taxon(&nested, &taxonomy), Some("562"));
+		assert_eq!(best_taxon(&HashMap::new(), &taxonomy), None);
+	}
+}
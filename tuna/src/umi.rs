@@ -0,0 +1,241 @@
+use rayon::prelude::*;
+
+use std::collections::HashMap;
+
+use log::*;
+
+use dna_hash_table::DNAHashTable;
+use dna_hash_table::MappingStats;
+use dna_hash_table::Pseudoaligner;
+use dna_hash_table::ReadOutcome;
+use dna_hash_table::SegmentCounts;
+use read_inputs::FqRecord;
+
+//The targets a read is assigned to and its start on the first of them, where that could be found
+type Placement = (Vec<usize>, Option<usize>);
+
+//Where the UMI of each read is found
+#[derive(Clone, Debug)]
+pub enum UmiSource {
+	//The last field of the read name after the given delimiter, such as _ for UMI-tools or : for bcl2fastq
+	ReadName(char),
+	//The start of the read, laid out by a pattern in which N marks a UMI base and any other character a
+	//base to discard
+	Pattern(String),
+}
+
+/**
+    extract_umis:
+    Take the UMI of every record from its name or the start of its sequence, trimming the
+    sequence and quality of inline UMIs
+*/
+pub fn extract_umis(records : &mut [FqRecord], umi_source : &UmiSource) {
+	for record in records.iter_mut() {
+		let umi : String = match *umi_source {
+			UmiSource::ReadName(delimiter) => {
+				let name : &str = record.name.split_whitespace().next().unwrap_or("");
+				match name.rsplit_once(delimiter) {
+					Some((_, umi)) if is_umi(umi) => umi.to_string(),
+					_ => panic!("Read {} has no UMI after a {} in its name", record.name, delimiter),
+				}
+			},
+			UmiSource::Pattern(ref pattern) => {
+				let length : usize = pattern.len().min(record.seq.len());
+				let umi : String = record.seq[..length].chars().zip(pattern.chars())
+					.filter(|&(_, p)| p == 'N')
+					.map(|(base, _)| base)
+					.collect();
+				record.seq = record.seq[length..].to_string();
+				if let Some(ref mut qual) = record.qual {
+					*qual = qual[length.min(qual.len())..].to_string();
+				}
+				umi
+			},
+		};
+		record.umi = Some(umi);
+	}
+}
+
+//Whether a field of a read name is made of bases, unlike the coordinates that end Illumina read names
+fn is_umi(field : &str) -> bool {
+	!field.is_empty() && field.chars().all(|c| matches!(c, 'A' | 'C' | 'G' | 'T' | 'N'))
+}
+
+/**
+    count_molecules:
+    Count the reads assigned to each target as molecules: reads sharing their best targets
+    and start position are collapsed by UMI with the directional method, and each
+    resulting molecule credits its targets once. Reads whose start cannot be placed are
+    each counted as a molecule of their own. The outcome of every read is kept if asked for.
+*/
+pub fn count_molecules<P : Pseudoaligner + Sync>(pseudoaligner : &P, kmer_hash_table : &DNAHashTable, reads : &[String], umis : &[String],
+		n_targets : usize, keep_outcomes : bool) -> SegmentCounts {
+	let (outcomes, positions) : (Vec<ReadOutcome>, Vec<Option<usize>>) = reads.par_iter()
+		.map(|read| {
+			let outcome : ReadOutcome = ReadOutcome::new(pseudoaligner, read, n_targets);
+			let position : Option<usize> = match outcome {
+				ReadOutcome::Assigned(_, ref targets) => read_position(kmer_hash_table, read, targets[0]),
+				_ => None,
+			};
+			(outcome, position)
+		})
+		.unzip();
+
+	let mut mapping_stats = MappingStats::default();
+	let mut umi_groups : HashMap<Placement, HashMap<&str, usize>> = HashMap::new();
+	for ((outcome, &position), umi) in outcomes.iter().zip(positions.iter()).zip(umis.iter()) {
+		mapping_stats.n_reads += 1;
+		match *outcome {
+			ReadOutcome::Assigned(_, ref targets) => {
+				mapping_stats.n_assigned += 1;
+				*umi_groups.entry((targets.clone(), position)).or_default().entry(umi.as_str()).or_insert(0) += 1;
+			},
+			ReadOutcome::Decoy(_) => mapping_stats.n_decoy += 1,
+			ReadOutcome::NoHits => mapping_stats.n_no_hits += 1,
+		}
+	}
+
+	let mut segment_counts = SegmentCounts::default();
+	let mut n_molecules : usize = 0;
+	for ((targets, position), umi_counts) in umi_groups {
+		let molecules : usize = match position {
+			Some(_) => directional_clusters(&umi_counts),
+			None => umi_counts.values().sum(),
+		};
+		n_molecules += molecules;
+		for &segment_index in &targets {
+			*segment_counts.counts.entry(segment_index as i32).or_insert(0) += molecules as i32;
+		}
+		*segment_counts.equivalence_classes.entry(targets).or_insert(0) += molecules;
+	}
+	segment_counts.mapping_stats = mapping_stats;
+	if keep_outcomes {
+		segment_counts.outcomes = outcomes;
+	}
+
+	info!("Collapsed {} assigned reads into {} molecules", segment_counts.mapping_stats.n_assigned, n_molecules);
+	segment_counts
+}
+
+//The start of a read on a segment, from the first of its k-mers found there
+fn read_position(kmer_hash_table : &DNAHashTable, read : &str, segment_index : usize) -> Option<usize> {
+	let k : usize = kmer_hash_table.k();
+	(0..(read.len() + 1).saturating_sub(k)).find_map(|offset| {
+		kmer_hash_table.get_kmer(&read[offset..(offset + k)]).and_then(|(kmers, kmer_indexes)| {
+			kmer_indexes.iter()
+				.map(|&index| &kmers[index])
+				.filter(|kmer| kmer.segment_index == segment_index && kmer.position >= offset)
+				.map(|kmer| kmer.position - offset)
+				.min()
+		})
+	})
+}
+
+/**
+    directional_clusters:
+    Count the molecules among the UMIs of one position with the directional adjacency
+    method: a UMI one substitution away from another with at least twice its count, less
+    one, is taken to be an error of that UMI
+*/
+pub fn directional_clusters(umi_counts : &HashMap<&str, usize>) -> usize {
+	let mut umis : Vec<(&str, usize)> = umi_counts.iter().map(|(&umi, &count)| (umi, count)).collect();
+	umis.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+	let mut clustered : Vec<bool> = vec![false; umis.len()];
+	let mut n_clusters : usize = 0;
+	for start in 0..umis.len() {
+		if clustered[start] {
+			continue;
+		}
+		n_clusters += 1;
+		clustered[start] = true;
+		let mut stack : Vec<usize> = vec![start];
+		while let Some(node) = stack.pop() {
+			let (umi, count) = umis[node];
+			for other in 0..umis.len() {
+				if !clustered[other] && count + 1 >= 2 * umis[other].1 && hamming_distance(umi, umis[other].0) == 1 {
+					clustered[other] = true;
+					stack.push(other);
+				}
+			}
+		}
+	}
+	n_clusters
+}
+
+fn hamming_distance(a : &str, b : &str) -> usize {
+	if a.len() != b.len() {
+		return usize::MAX;
+	}
+	a.bytes().zip(b.bytes()).filter(|&(x, y)| x != y).count()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn umi_counts<'a>(counts : &[(&'a str, usize)]) -> HashMap<&'a str, usize> {
+		counts.iter().cloned().collect()
+	}
+
+	fn record(name : &str, seq : &str) -> FqRecord {
+		FqRecord { name : name.to_string(), seq : seq.to_string(), qual : Some("I".repeat(seq.len())), umi : None }
+	}
+
+	#[test]
+	fn directional_collapse_absorbs_rare_neighbours() {
+		//ACGT explains both of its one-substitution neighbours with at most half its count, less one
+		assert_eq!(directional_clusters(&umi_counts(&[("ACGT", 10), ("ACGA", 5), ("ACGC", 2)])), 1);
+		//A neighbour seen too often to be an error of it is a molecule of its own
+		assert_eq!(directional_clusters(&umi_counts(&[("ACGT", 10), ("ACGA", 6)])), 2);
+		//Errors are followed through chains of neighbours
+		assert_eq!(directional_clusters(&umi_counts(&[("AAAA", 20), ("AAAT", 9), ("AATT", 4)])), 1);
+		assert_eq!(directional_clusters(&umi_counts(&[("AAAA", 3), ("TTTT", 3)])), 2);
+		assert_eq!(hamming_distance("ACG", "AC"), usize::MAX);
+	}
+
+	#[test]
+	fn takes_umis_from_names_after_the_delimiter() {
+		let mut records = [record("READ1_ACGTAC extra", "GGGG"), record("M1:RUN:FC:1:1101:AACC", "TTTT")];
+		extract_umis(&mut records[..1], &UmiSource::ReadName('_'));
+		extract_umis(&mut records[1..], &UmiSource::ReadName(':'));
+		assert_eq!(records[0].umi, Some("ACGTAC".to_string()));
+		assert_eq!(records[1].umi, Some("AACC".to_string()));
+	}
+
+	#[test]
+	#[should_panic(expected = "has no UMI")]
+	fn rejects_illumina_coordinates_as_umis() {
+		extract_umis(&mut [record("M1:RUN:FC:1:1101:1000:2000", "ACGT")], &UmiSource::ReadName(':'));
+	}
+
+	#[test]
+	fn takes_inline_umis_by_pattern() {
+		let mut records = vec![record("r1", "ACGTTGCATT")];
+		extract_umis(&mut records, &UmiSource::Pattern("NNXN".to_string()));
+		assert_eq!(records[0].umi, Some("ACT".to_string()));
+		assert_eq!(records[0].seq, "TGCATT");
+		assert_eq!(records[0].qual.as_deref(), Some("IIIIII"));
+	}
+
+	#[test]
+	fn collapses_reads_by_position_and_keeps_unplaced_reads_apart() {
+		let segments : Vec<String> = vec!["CCGTAATGCCTTTCCCTAACAGAGTTTTTCGAACTCGTGT".to_string()];
+		let kmer_hash_table = DNAHashTable::new(&segments, 7);
+		//The first read's first k-mer has an error, so it is placed by its second k-mer
+		let reads : Vec<String> = vec![
+			"ACGTAATGCCTTTCCC".to_string(),
+			"CCGTAATGCCTTTCCC".to_string(),
+			"TAACAGAGTTTTTCGA".to_string(),
+		];
+		let umis : Vec<String> = vec!["AAAA".to_string(), "AAAA".to_string(), "AAAA".to_string()];
+		assert_eq!(read_position(&kmer_hash_table, &reads[0], 0), Some(0));
+		assert_eq!(read_position(&kmer_hash_table, &reads[2], 0), Some(16));
+		assert_eq!(read_position(&kmer_hash_table, "AAAAAAA", 0), None);
+
+		let segment_counts = count_molecules(&kmer_hash_table, &kmer_hash_table, &reads, &umis, 1, true);
+		assert_eq!(segment_counts.counts.get(&0), Some(&2));
+		assert_eq!(segment_counts.mapping_stats.n_assigned, 3);
+		assert_eq!(segment_counts.outcomes.len(), 3);
+	}
+}